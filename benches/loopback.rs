@@ -0,0 +1,59 @@
+//! Two tunnels over real UDP on 127.0.0.1, sockets and all. Prints what it measured:
+//! `cargo bench --bench loopback`.
+
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+use common::*;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use wireshade::{Tunnel, WgTcpStream};
+
+const CHUNK: usize = 1 << 20;
+const BULK_CHUNKS: usize = 64;
+const MESSAGE: usize = 64;
+const ROUND_TRIPS: usize = 1000;
+const CONNECTIONS: usize = 500;
+
+async fn connect(client: &Tunnel, port: u16) -> WgTcpStream {
+    WgTcpStream::connect(client, format!("10.0.0.1:{}", port).parse().unwrap()).await.unwrap()
+}
+
+#[tokio::main]
+async fn main() {
+    let (server, client) = udp_pair();
+    sink_server(&server, 9, CHUNK).await;
+    echo_server(&server, 7).await;
+    drop_server(&server, 80).await;
+
+    let mut stream = connect(&client, 9).await;
+    let chunk = payload(CHUNK);
+    let started = Instant::now();
+    for _ in 0..BULK_CHUNKS {
+        stream.write_all(&chunk).await.unwrap();
+        stream.read_exact(&mut [0]).await.unwrap();
+    }
+    let elapsed = started.elapsed();
+    println!("bulk        {} MiB in {:.2?}: {:.1} MiB/s", BULK_CHUNKS, elapsed, BULK_CHUNKS as f64 / elapsed.as_secs_f64());
+
+    let mut stream = connect(&client, 7).await;
+    let (message, mut echoed) = (payload(MESSAGE), [0u8; MESSAGE]);
+    let mut times = vec![Duration::ZERO; ROUND_TRIPS];
+    for time in &mut times {
+        let started = Instant::now();
+        stream.write_all(&message).await.unwrap();
+        stream.read_exact(&mut echoed).await.unwrap();
+        *time = started.elapsed();
+    }
+    times.sort();
+    let percentile = |p: usize| times[(times.len() - 1) * p / 100];
+    println!("round trip  {} of {} bytes: median {:.1?}, p99 {:.1?}", ROUND_TRIPS, MESSAGE, percentile(50), percentile(99));
+
+    // Reset rather than closed, or each would hold a socket in TIME-WAIT for 10 s
+    let started = Instant::now();
+    for _ in 0..CONNECTIONS {
+        client.connect("10.0.0.1".parse().unwrap(), 80).await.unwrap().abort();
+    }
+    let elapsed = started.elapsed();
+    println!("connect     {} in {:.2?}: {:.0}/s", CONNECTIONS, elapsed, CONNECTIONS as f64 / elapsed.as_secs_f64());
+}
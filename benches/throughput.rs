@@ -0,0 +1,100 @@
+//! The data path between two tunnels in one process, wired back to back without sockets:
+//! what the TCP stack, WireGuard and the copies in between cost on their own.
+
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+use common::*;
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use std::time::Instant;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::runtime::Runtime;
+use wireshade::{CallbackTransport, Tunnel, WgTcpStream};
+
+const BULK: usize = 1 << 20;
+const MESSAGE: usize = 64;
+
+/// A runtime with a server and client tunnel on it, connected over `CallbackTransport::pair`.
+fn setup() -> (Runtime, Tunnel, Tunnel) {
+    let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build().unwrap();
+    let (server, client) = {
+        let _guard = runtime.enter();
+        let (a, b) = ("192.0.2.1:51820".parse().unwrap(), "192.0.2.2:51820".parse().unwrap());
+        let (server_transport, client_transport) = CallbackTransport::pair(a, b);
+        pair_over(server_transport, client_transport, a)
+    };
+    (runtime, server, client)
+}
+
+async fn connect(client: &Tunnel, port: u16) -> WgTcpStream {
+    WgTcpStream::connect(client, format!("10.0.0.1:{}", port).parse().unwrap()).await.unwrap()
+}
+
+fn bulk(c: &mut Criterion) {
+    let (runtime, server, client) = setup();
+    let mut stream = runtime.block_on(async {
+        sink_server(&server, 9, BULK).await;
+        connect(&client, 9).await
+    });
+    let data = payload(BULK);
+
+    let mut group = c.benchmark_group("tcp");
+    group.throughput(Throughput::Bytes(BULK as u64));
+    group.bench_function("bulk", |b| {
+        b.iter_custom(|iters| {
+            runtime.block_on(async {
+                let started = Instant::now();
+                for _ in 0..iters {
+                    stream.write_all(&data).await.unwrap();
+                    stream.read_exact(&mut [0]).await.unwrap();
+                }
+                started.elapsed()
+            })
+        })
+    });
+    group.finish();
+}
+
+fn round_trip(c: &mut Criterion) {
+    let (runtime, server, client) = setup();
+    let mut stream = runtime.block_on(async {
+        echo_server(&server, 7).await;
+        connect(&client, 7).await
+    });
+    let message = payload(MESSAGE);
+
+    c.bench_function("tcp/round_trip", |b| {
+        b.iter_custom(|iters| {
+            runtime.block_on(async {
+                let mut echoed = [0u8; MESSAGE];
+                let started = Instant::now();
+                for _ in 0..iters {
+                    stream.write_all(&message).await.unwrap();
+                    stream.read_exact(&mut echoed).await.unwrap();
+                }
+                started.elapsed()
+            })
+        })
+    });
+}
+
+fn connections(c: &mut Criterion) {
+    let (runtime, server, client) = setup();
+    runtime.block_on(drop_server(&server, 80));
+
+    // Reset rather than closed, or each would hold a socket in TIME-WAIT for 10 s
+    c.bench_function("tcp/connect", |b| {
+        b.iter_custom(|iters| {
+            runtime.block_on(async {
+                let started = Instant::now();
+                for _ in 0..iters {
+                    client.connect("10.0.0.1".parse().unwrap(), 80).await.unwrap().abort();
+                }
+                started.elapsed()
+            })
+        })
+    });
+}
+
+criterion_group!(benches, bulk, round_trip, connections);
+criterion_main!(benches);
@@ -0,0 +1,443 @@
+/* Types of the native binding, as generated from src/bindings.rs by `napi build --dts`,
+ * plus the JavaScript client in lib/. Regenerate the native part when the binding changes. */
+
+import { EventEmitter } from 'events'
+import * as http from 'http'
+import * as https from 'https'
+import * as net from 'net'
+
+/** How the native side rejects: an `Error` with one of the codes listed in the README. */
+export interface WireShadeError extends Error {
+  code: string
+}
+/** A WireGuard peer as passed from JavaScript (mirrors a `[Peer]` section). */
+export interface PeerConfig {
+  publicKey: string
+  presharedKey?: string
+  /**
+   * `host:port`. Leave empty for peers that connect to us; their endpoint is learned
+   * from the first authenticated packet.
+   */
+  endpoint?: string
+  /** CIDRs routed to this peer. Plain addresses are treated as /32 (IPv4) or /128 (IPv6). */
+  allowedIps?: Array<string>
+  persistentKeepalive?: number
+}
+export interface WireShadeOptions {
+  /** Bind the outer UDP socket to this port instead of an ephemeral one (`ListenPort`). */
+  listenPort?: number
+  /** IPv6 address inside the tunnel (a second `Address`), to reach IPv6 hosts. */
+  sourceIp6?: string
+  /** `AllowedIPs` of the peer given to the constructor; all addresses if not set. */
+  allowedIps?: Array<string>
+  /** Peers in addition to the one given to the constructor. */
+  peers?: Array<PeerConfig>
+  /**
+   * Seconds between DNS lookups of peer endpoints given as hostnames. Endpoints are
+   * always looked up again after repeated handshake failures; this adds a periodic check.
+   */
+  resolveInterval?: number
+  reconnect?: ReconnectOptions
+  /** DNS servers inside the tunnel (`DNS`), used to resolve names for the proxies. */
+  dns?: Array<string>
+  /** Runs the tunnel over a simulated bad network, for testing. */
+  simulate?: SimulateOptions
+}
+/**
+ * When a peer counts as dead and how handshakes with it are retried. Defaults match
+ * the JS client's `reconnect` option.
+ */
+export interface ReconnectOptions {
+  enabled?: boolean
+  /** Seconds without any authenticated packet from the peer after sending it data (default 15). */
+  deadPeerTimeout?: number
+  /** First retry delay in ms (default 1000). */
+  delay?: number
+  /** Upper bound for the retry delay in ms (default 30000). */
+  maxDelay?: number
+  backoffMultiplier?: number
+  /** Random spread applied to each delay, 0.2 = ±20% (default 0.2). */
+  jitter?: number
+  /** Give up after this many retries; 0 retries forever (default 10). */
+  maxAttempts?: number
+}
+/** A simulated network for the `simulate` option, applied to each direction on its own. */
+export interface SimulateOptions {
+  /** Fraction of packets dropped, 0 to 1 (default 0). */
+  loss?: number
+  /** Latency added to every packet in ms (default 0). */
+  delayMs?: number
+  /** Random extra latency up to this many ms (default 0). */
+  jitterMs?: number
+  /** Fraction of packets delivered twice (default 0). */
+  duplicate?: number
+  /** Fraction of packets held back so that later ones overtake them (default 0). */
+  reorder?: number
+  /** Bytes per second (default: unlimited). */
+  bandwidth?: number
+  /** The same seed makes the same decisions for the same traffic (default 0). */
+  seed?: number
+  /** Affect the decrypted packets instead of the WireGuard datagrams (default false). */
+  inner?: boolean
+}
+/** Which decrypted packets `onPacket` reports. Empty matches everything. */
+export interface PacketFilterOptions {
+  /** IP protocol number, e.g. 1 (ICMP), 6 (TCP), 17 (UDP), 58 (ICMPv6). */
+  protocol?: number
+  /** TCP/UDP source or destination port. */
+  port?: number
+  /** Keep matching packets away from the TCP stack (default false: they are only copied). */
+  intercept?: boolean
+}
+/** Where `startSocks5` listens and whether clients must log in. */
+export interface Socks5Options {
+  /** Host address to listen on (default "127.0.0.1:1080"). */
+  bind?: string
+  auth?: ProxyAuth
+}
+/** Where `startExitNode` lets peers connect to. */
+export interface ExitNodeOptions {
+  /**
+   * Destination CIDRs peers may reach, e.g. ["0.0.0.0/0"] for anywhere. Required, so an
+   * exit node is never an open relay by accident.
+   */
+  allowedIps: Array<string>
+  /** Destination ports peers may reach (default: any). */
+  allowedPorts?: Array<number>
+  /** Connections open at once; further ones are reset (default: no limit). */
+  maxConnections?: number
+}
+/** Limits for `forwardRemote`. */
+export interface RemoteForwardOptions {
+  /** Connections open at once; further tunnel clients are reset (default: no limit). */
+  maxConnections?: number
+}
+/** Where `startHttpProxy` listens, who may use it and where it may connect to. */
+export interface HttpProxyOptions {
+  /** Host address to listen on (default "127.0.0.1:8080"). */
+  bind?: string
+  /** Require `Proxy-Authorization: Basic` with these credentials. */
+  auth?: ProxyAuth
+  /** Destination CIDRs clients may reach (default: any). */
+  allowedIps?: Array<string>
+  /** Destination ports clients may reach (default: any). */
+  allowedPorts?: Array<number>
+}
+/** Probes sent by `ping`. */
+export interface PingOptions {
+  /** Echo requests to send (default 4). */
+  count?: number
+  /** How long each probe waits for its reply, in ms (default 1000). */
+  timeoutMs?: number
+  /** Delay between probes in ms (default 1000). */
+  intervalMs?: number
+  /** Payload bytes per probe (default 56, at most 1392 to fit the tunnel MTU). */
+  size?: number
+}
+/** What `startCapture` records. */
+export interface CaptureOptions {
+  /** Decrypted IP packets (default true). */
+  inner?: boolean
+  /** Encrypted WireGuard datagrams, shown as UDP (default false). */
+  outer?: boolean
+  /** Bytes kept per packet (default 65535, 0 for whole packets). */
+  snaplen?: number
+}
+/** One native log line, as passed to the `setLogger` callback. */
+export interface LogRecord {
+  /** "error", "warn", "info", "debug" or "trace" */
+  level: string
+  /** Module the line comes from, e.g. "wireshade::peer" or "wireshade::tcp". */
+  target: string
+  message: string
+}
+/** Outcome of `ping`. */
+export interface PingResult {
+  host: string
+  transmitted: number
+  received: number
+  /** Fraction of probes without a reply, 0 to 1. */
+  loss: number
+  /** Round-trip time of each probe in ms, `null` for lost ones. */
+  rtts: Array<number | undefined | null>
+  min?: number
+  avg?: number
+  max?: number
+}
+/** Username and password clients must present. */
+export interface ProxyAuth {
+  username: string
+  password: string
+}
+/** Traffic of a proxy or forward since it started. */
+export interface TrafficStats {
+  activeConnections: number
+  totalConnections: number
+  /** Bytes sent into the tunnel. */
+  bytesSent: number
+  /** Bytes received from the tunnel. */
+  bytesReceived: number
+}
+/** Passed to `onStateChange` when a peer's tunnel goes up or down. */
+export interface StateChange {
+  publicKey: string
+  /** "connecting", "connected", "reconnecting" or "failed" */
+  state: string
+  /** Retry number while reconnecting, 0 otherwise. */
+  attempt: number
+}
+/**
+ * Passed to `onEndpointChange` whenever a peer's outer address changes, either because
+ * it roamed or because its hostname now resolves elsewhere.
+ */
+export interface EndpointChange {
+  publicKey: string
+  endpoint: string
+  previous?: string
+}
+export class NativeWireShade {
+  constructor(privateKey: string, peerPublicKey: string, presharedKey: string | undefined | null, endpoint: string, sourceIp: string, options?: WireShadeOptions | undefined | null)
+  /**
+   * Responder mode: bind to `listen_port` and wait for the given peers to connect in,
+   * like a `wg` interface with `ListenPort` and no peer endpoints. `source_ip6` adds an
+   * IPv6 address inside the tunnel.
+   */
+  static server(privateKey: string, sourceIp: string, listenPort: number, peers: Array<PeerConfig>, sourceIp6?: string | undefined | null): NativeWireShade
+  /**
+   * Route native logs at `level` ("off", "error", "warn", "info", "debug", "trace") to
+   * `callback`, or to stderr without one. Applies to all instances.
+   */
+  static setLogger(level: string, callback?: (err: Error | null, arg: LogRecord) => any | undefined | null): void
+  /**
+   * Without callbacks, resolves once the connection is established and its data is read
+   * with `read()`. With them, resolves right away and data goes to `on_data`; `on_close`
+   * receives the reason as a coded error (ECONNREFUSED, ECONNRESET, ETIMEDOUT) or nothing
+   * when the connection was closed in an orderly way.
+   */
+  connect(destIp: string, destPort: number, onData?: (err: Error | null, arg: Buffer) => any | undefined | null, onClose?: (err: Error | null, arg: WireShadeError | undefined | null) => any | undefined | null): Promise<Connection>
+  /**
+   * Listens on `port` at the tunnel address. Each connection comes out of the listener's
+   * `accept()` as a `Connection` of its own, read like one opened with `connect`.
+   */
+  createListener(port: number): Promise<Listener>
+  /**
+   * Replace the outer UDP socket and all WireGuard sessions, e.g. after a network change.
+   * The TCP/IP stack is kept, so open connections resume once the new handshake is done.
+   * Resolves to the new local UDP address.
+   */
+  rebind(listenPort?: number | undefined | null): Promise<string>
+  /**
+   * Register a callback invoked with a `StateChange` whenever a peer connects, is
+   * detected dead, is retried or is given up on
+   */
+  onStateChange(callback: (err: Error | null, arg: StateChange) => any): void
+  /** Register a callback invoked with an `EndpointChange` whenever a peer's endpoint moves */
+  onEndpointChange(callback: (err: Error | null, arg: EndpointChange) => any): void
+  /**
+   * Register a callback invoked once with the root cause if the tunnel dies (an error or a
+   * bug in the native loop). Every call after that rejects with `ERR_WIRESHADE_TUNNEL_FAILED`.
+   */
+  onFatalError(callback: (err: Error | null, arg: WireShadeError) => any): void
+  /**
+   * Send ICMP echo requests to `ip` through the tunnel. Resolves with the round-trip
+   * time of each probe and the loss once every probe is answered or timed out.
+   */
+  ping(ip: string, options?: PingOptions | undefined | null): Promise<PingResult>
+  /**
+   * Run a SOCKS5 proxy on a host port that opens its connections through the tunnel.
+   * Supports CONNECT to IPv4 addresses and to names, which are resolved with the `dns` servers.
+   */
+  startSocks5(options?: Socks5Options | undefined | null): Promise<ProxyServer>
+  /**
+   * Run an HTTP proxy on a host port that opens its connections through the tunnel:
+   * `CONNECT host:port` for HTTPS and other TCP, and plain `http://` requests.
+   */
+  startHttpProxy(options?: HttpProxyOptions | undefined | null): Promise<ProxyServer>
+  /**
+   * Listen on `local_bind` on the host (e.g. "127.0.0.1:3333") and connect every client to
+   * `remote_ip:remote_port` through the tunnel. Data is copied natively, without JS.
+   */
+  forwardLocal(localBind: string, remoteIp: string, remotePort: number): Promise<ProxyServer>
+  /**
+   * Listen on `tunnel_port` at the tunnel address and connect every client to
+   * `target_host:target_port` on the host. A `target_host` of "unix:/path" connects to a
+   * Unix socket instead (no port). Data is copied natively, without JS.
+   */
+  forwardRemote(tunnelPort: number, targetHost: string, targetPort?: number | undefined | null, options?: RemoteForwardOptions | undefined | null): Promise<ProxyServer>
+  /**
+   * Act as an exit node: TCP connections peers make through us to hosts in
+   * `options.allowedIps` are accepted and made from this machine instead, and the data
+   * relayed natively. Peers route those destinations to us in their AllowedIPs.
+   */
+  startExitNode(options: ExitNodeOptions): Promise<ProxyServer>
+  /**
+   * Inject a raw IPv4 or IPv6 packet into the tunnel. It is routed to a peer by its
+   * destination like packets from the TCP stack, and sent as is.
+   */
+  sendPacket(packet: Buffer): Promise<void>
+  /**
+   * Register a callback for decrypted packets from the tunnel that match `filter`,
+   * called with the raw IP packet. Returns an id for `offPacket`.
+   */
+  onPacket(filter: PacketFilterOptions | undefined | null, callback: (err: Error | null, arg: Buffer) => any): number
+  offPacket(id: number): void
+  /**
+   * Write packets to a pcapng file at `path` for Wireshark until `stopCapture()`.
+   * Starting again switches to the new file.
+   */
+  startCapture(path: string, options?: CaptureOptions | undefined | null): void
+  /** Stop the running capture and flush it to disk. */
+  stopCapture(): void
+}
+export class Connection {
+  /**
+   * Address of the other end: where we connected to, or where an accepted connection
+   * came from.
+   */
+  get remoteAddress(): string
+  get remotePort(): number
+  /**
+   * Queue `data` for sending. Prefer `write` to wait until the tunnel has taken it.
+   * `data` is copied, so the caller may reuse it right away.
+   */
+  send(data: Buffer): Promise<void>
+  /**
+   * Resolves once `data` is in the TCP send buffer, so a writer waiting on it never runs
+   * ahead of the tunnel. Rejects with the reason if the connection is gone.
+   */
+  write(data: Buffer): Promise<void>
+  /**
+   * Next chunk of at most `max_bytes` (default 64 KiB) received data, or `null` once the
+   * remote end has finished sending. Rejects with the reason (e.g. ECONNRESET) if the
+   * connection broke. Only for connections opened without callbacks.
+   */
+  read(maxBytes?: number | undefined | null): Promise<Buffer | null>
+  /** Send FIN after everything written so far; data can still be received. */
+  close(): Promise<void>
+  /** Reset the connection right away, dropping anything not sent yet. */
+  abort(): Promise<void>
+  /** Reads until the remote end is done, like calling `read()` until it returns `null`. */
+  [Symbol.asyncIterator](): AsyncGenerator<Buffer, void, undefined>
+}
+/** Connections arriving on a tunnel port, from `createListener`. */
+export class Listener {
+  /** Address it listens on, e.g. "10.0.0.2:80". */
+  get address(): string
+  get port(): number
+  /** Next connection, or `null` once the listener is closed. */
+  accept(): Promise<Connection | null>
+  /**
+   * Stop listening. Pending and later `accept` calls resolve to `null`; connections
+   * already accepted stay open.
+   */
+  close(): void
+  /** Accepts connections until the listener is closed. */
+  [Symbol.asyncIterator](): AsyncGenerator<Connection, void, undefined>
+}
+/** A proxy or port forward, listening on a host port or, for `forwardRemote`, a tunnel port. */
+export class ProxyServer {
+  /** Address it listens on, e.g. "127.0.0.1:1080". */
+  get address(): string
+  get port(): number
+  stats(): TrafficStats
+  /** Stop listening and drop every connection it opened. */
+  close(): void
+}
+
+/** A parsed `.conf` file, as returned by `parseConfig` and `readConfig`. */
+export interface WireGuardConfig {
+  privateKey: string
+  sourceIp: string
+  sourceIp6?: string
+  listenPort?: number
+  dns?: Array<string>
+  /** The first peer's, as in `peers[0]`. */
+  peerPublicKey: string
+  presharedKey?: string
+  endpoint?: string
+  peers: Array<PeerConfig>
+}
+
+export interface ClientConfig {
+  wireguard: WireGuardConfig
+  /** Names resolved to VPN addresses without DNS. */
+  hosts?: Record<string, string>
+  logging?: boolean
+  resolveInterval?: number
+  simulate?: SimulateOptions
+  reconnect?: ReconnectOptions & {
+    /** VPN address pinged every `healthCheckInterval` ms. */
+    healthCheckHost?: string
+    healthCheckInterval?: number
+    healthCheckTimeout?: number
+  }
+  onConnect?: () => void
+  onDisconnect?: (err?: Error) => void
+  onReconnect?: () => void
+}
+
+export declare const ConnectionState: {
+  readonly DISCONNECTED: 'disconnected'
+  readonly CONNECTING: 'connecting'
+  readonly CONNECTED: 'connected'
+  readonly RECONNECTING: 'reconnecting'
+}
+export type ConnectionStateValue = typeof ConnectionState[keyof typeof ConnectionState]
+
+/** Sockets handed out by the client and `WireShadeServer` behave like `net.Socket`s. */
+export type ConnectionSocket = net.Socket
+
+export declare class WireShadeServer extends EventEmitter {
+  readonly port: number | null
+  readonly listening: boolean
+  listen(port: number, callback?: () => void): Promise<void>
+  close(callback?: () => void): void
+  on(event: 'connection', listener: (socket: ConnectionSocket, info: { remoteAddress: string; remotePort: number }) => void): this
+  on(event: 'listening' | 'close', listener: () => void): this
+  on(event: 'error', listener: (err: WireShadeError) => void): this
+}
+
+export declare class WireShadeAgent extends http.Agent {
+  constructor(gw: NativeWireShade, options?: http.AgentOptions & { logging?: boolean; logger?: (...args: any[]) => void })
+}
+
+export declare class WireShadeClient extends EventEmitter {
+  /** A config object, or the path of a `.conf` file and the other options. */
+  constructor(configOrPath: ClientConfig | string, options?: Omit<ClientConfig, 'wireguard'>)
+  readonly state: ConnectionStateValue
+  /** The `http` module, with requests going through the tunnel. */
+  readonly http: typeof http
+  /** The `https` module, with requests going through the tunnel. */
+  readonly https: typeof https
+  start(): Promise<void>
+  reconnect(): void
+  close(): void
+  getHttpAgent(): http.Agent
+  getHttpsAgent(): https.Agent
+  addHost(hostname: string, ip: string): void
+  get(url: string, options?: http.RequestOptions): Promise<string>
+  request(url: string, options?: http.RequestOptions & { body?: string | Buffer }): Promise<string>
+  connect(options: { host: string; port: number }, connectionListener?: () => void): ConnectionSocket
+  listen(port: number, onConnection?: (socket: ConnectionSocket) => void): Promise<WireShadeServer>
+  forwardLocal(localPort: number | string, remoteHost: string, remotePort: number): Promise<ProxyServer>
+  forwardRemote(vpnPort: number, targetHost: string, targetPort?: number, options?: RemoteForwardOptions): Promise<ProxyServer>
+  startSocks5(options?: Socks5Options): Promise<ProxyServer>
+  startHttpProxy(options?: HttpProxyOptions): Promise<ProxyServer>
+  startExitNode(options: ExitNodeOptions): Promise<ProxyServer>
+  ping(host: string, options?: PingOptions): Promise<PingResult>
+  sendPacket(packet: Buffer): Promise<void>
+  onPacket(filter: PacketFilterOptions | undefined | null, callback: (packet: Buffer) => void): number
+  offPacket(id: number): void
+  startCapture(path: string, options?: CaptureOptions): void
+  stopCapture(): void
+  on(event: 'connect' | 'reconnect' | 'reconnectFailed' | 'healthCheck' | 'close', listener: () => void): this
+  on(event: 'disconnect', listener: (err?: WireShadeError) => void): this
+  on(event: 'reconnecting', listener: (attempt: number) => void): this
+  on(event: 'stateChange', listener: (state: ConnectionStateValue) => void): this
+  on(event: 'endpointChange', listener: (change: EndpointChange) => void): this
+  on(event: 'error', listener: (err: Error) => void): this
+}
+
+export { WireShadeClient as WireShade }
+export declare function parseConfig(content: string): WireGuardConfig
+export declare function readConfig(filePath: string): WireGuardConfig
@@ -1,17 +1,4 @@
-const fs = require('fs');
-const path = require('path');
-
-let binding;
-try {
-    binding = require('./wireshade.node');
-} catch (e) {
-    try {
-        binding = require('./wireshade.win32-x64-msvc.node');
-    } catch (e2) {
-        throw new Error('Could not load native binding');
-    }
-}
-
+const binding = require('./lib/native');
 const { WireShadeAgent } = require('./lib/agent');
 const { WireShadeClient, ConnectionState } = require('./lib/client');
 const { WireShadeServer } = require('./lib/server');
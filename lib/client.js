@@ -2,17 +2,7 @@ const fs = require('fs');
 const path = require('path');
 const EventEmitter = require('events');
 
-let binding;
-try {
-    binding = require('../wireshade.node');
-} catch (e) {
-    try {
-        binding = require('../wireshade.win32-x64-msvc.node');
-    } catch (e2) {
-        throw new Error('Could not load native binding: ' + e2.message);
-    }
-}
-const { WireShade } = binding;
+const { WireShade } = require('./native');
 const { WireShadeAgent } = require('./agent');
 const { WireShadeServer } = require('./server');
 const { readWireGuardConfig } = require('./config_parser');
@@ -57,23 +47,23 @@ class WireShadeClient extends EventEmitter {
         // Connection state
         this.state = ConnectionState.DISCONNECTED;
         this.reconnectAttempts = 0;
-        this.reconnectTimer = null;
         this.healthCheckTimer = null;
 
-        // Reconnection config with defaults
+        // Reconnection config with defaults. Dead-peer detection and retries run natively.
         this.reconnectConfig = {
             enabled: config.reconnect?.enabled !== false,
             maxAttempts: config.reconnect?.maxAttempts ?? 10,
             delay: config.reconnect?.delay ?? 1000,
             maxDelay: config.reconnect?.maxDelay ?? 30000,
             backoffMultiplier: config.reconnect?.backoffMultiplier ?? 1.5,
-            healthCheckInterval: config.reconnect?.healthCheckInterval ?? 30000
+            jitter: config.reconnect?.jitter ?? 0.2,
+            deadPeerTimeout: config.reconnect?.deadPeerTimeout ?? 15,
+            healthCheckInterval: config.reconnect?.healthCheckInterval ?? 30000,
+            // VPN address to ping on each health check; without one only keepalives are relied on
+            healthCheckHost: config.reconnect?.healthCheckHost ?? null,
+            healthCheckTimeout: config.reconnect?.healthCheckTimeout ?? 2000
         };
 
-
-
-
-
         // Support for property-style callbacks
         if (config.onConnect) this.on('connect', config.onConnect);
         if (config.onDisconnect) this.on('disconnect', config.onDisconnect);
@@ -151,16 +141,63 @@ class WireShadeClient extends EventEmitter {
         this.state = ConnectionState.CONNECTING;
         this.emit('stateChange', this.state);
 
+        // Keep the native instance (and every open TCP connection) across reconnects
+        if (this.gw) {
+            return this._rebindNative();
+        }
+
         try {
             // Create new native instance
+            const wg = this.config.wireguard;
+            const first = (wg.peers || [])[0];
             this.gw = new WireShade(
-                this.config.wireguard.privateKey,
-                this.config.wireguard.peerPublicKey,
-                this.config.wireguard.presharedKey || "",
-                this.config.wireguard.endpoint,
-                this.config.wireguard.sourceIp
+                wg.privateKey,
+                wg.peerPublicKey,
+                wg.presharedKey || "",
+                wg.endpoint || "",
+                wg.sourceIp,
+                {
+                    listenPort: wg.listenPort,
+                    sourceIp6: wg.sourceIp6,
+                    // A config without AllowedIPs for it leaves the first peer as the default route
+                    allowedIps: first && first.allowedIps && first.allowedIps.length ? first.allowedIps : undefined,
+                    // The first peer is passed positionally above
+                    peers: (wg.peers || []).slice(1),
+                    resolveInterval: this.config.resolveInterval,
+                    dns: wg.dns,
+                    simulate: this.config.simulate,
+                    reconnect: {
+                        enabled: this.reconnectConfig.enabled,
+                        maxAttempts: this.reconnectConfig.maxAttempts,
+                        delay: this.reconnectConfig.delay,
+                        maxDelay: this.reconnectConfig.maxDelay,
+                        backoffMultiplier: this.reconnectConfig.backoffMultiplier,
+                        jitter: this.reconnectConfig.jitter,
+                        deadPeerTimeout: this.reconnectConfig.deadPeerTimeout
+                    }
+                }
             );
 
+            // Tunnel up/down transitions of the main peer drive our connection state
+            this.gw.onStateChange((err, change) => {
+                if (err || this._closed || change.publicKey !== wg.peerPublicKey) return;
+                this._onNativeState(change);
+            });
+
+            // Peer roamed or its hostname now resolves to a different address
+            this.gw.onEndpointChange((err, change) => {
+                if (err) return;
+                this.log(`[WireShadeClient] Endpoint changed: ${change.previous || '-'} -> ${change.endpoint}`);
+                this.emit('endpointChange', change);
+            });
+
+            // The native loop died; the error carries the root cause and every later call fails with it
+            this.gw.onFatalError((err, fatal) => {
+                if (err || this._closed) return;
+                this.log('[WireShadeClient] Tunnel failed:', fatal.message);
+                this._handleConnectionError(fatal);
+            });
+
             // Initialize/Update TCP Agent
             this.agents.tcp = new WireShadeAgent(this.gw, {
                 keepAlive: true,
@@ -172,15 +209,11 @@ class WireShadeClient extends EventEmitter {
             this.agents.http = null;
             this.agents.https = null;
 
-            // Simulate async handshake completion
-            // Native currently doesn't expose a "Handshake Complete" event, 
-            // so we assume success if no error occurs quickly.
-            // Future improvement: Expose handshake state from Rust.
-            setTimeout(() => {
-                if (this.state === ConnectionState.CONNECTING) {
-                    this._onConnected();
-                }
-            }, 1000); // Reduced to 1s for snappier feel
+            // Without an endpoint we wait for the peer to connect in (server mode),
+            // there is no handshake of ours to wait for.
+            if (!wg.endpoint) {
+                setImmediate(() => this._onConnected());
+            }
 
         } catch (err) {
             this.log('[WireShadeClient] Connection failed:', err.message);
@@ -188,6 +221,47 @@ class WireShadeClient extends EventEmitter {
         }
     }
 
+    /**
+     * Internal: Re-establish the tunnel on a fresh UDP socket without dropping connections
+     */
+    _rebindNative() {
+        this.gw.rebind(this.config.wireguard.listenPort).then((localAddr) => {
+            this.log(`[WireShadeClient] Tunnel rebound to ${localAddr}`);
+            // The native side reports 'connected' once the new handshake completes
+        }).catch((err) => {
+            this.log('[WireShadeClient] Rebind failed:', err.message);
+            this._handleConnectionError(err);
+        });
+    }
+
+    /**
+     * Internal: React to a state transition reported by the native tunnel
+     */
+    _onNativeState({ state, attempt }) {
+        switch (state) {
+            case 'connected':
+                if (this.state !== ConnectionState.CONNECTED) this._onConnected();
+                break;
+            case 'reconnecting':
+                this.reconnectAttempts = attempt;
+                if (this.state !== ConnectionState.RECONNECTING) {
+                    this.state = ConnectionState.RECONNECTING;
+                    this.emit('stateChange', this.state);
+                    this._stopHealthCheck();
+                }
+                if (attempt > 0) {
+                    this.log(`[WireShadeClient] Reconnecting (attempt ${attempt}/${this.reconnectConfig.maxAttempts || '∞'})`);
+                    this.emit('reconnecting', attempt);
+                }
+                break;
+            case 'failed':
+                this.log('[WireShadeClient] Max reconnection attempts reached');
+                this.emit('reconnectFailed');
+                this._handleConnectionError(Object.assign(new Error('Peer unreachable'), { code: 'ERR_WIRESHADE_HANDSHAKE_TIMEOUT' }));
+                break;
+        }
+    }
+
     /**
      * Called when connection is established
      */
@@ -227,45 +301,7 @@ class WireShadeClient extends EventEmitter {
         this.emit('disconnect', err);
 
         if (this.config.onDisconnect) this.config.onDisconnect(err);
-
-        // Attempt reconnection if enabled
-        if (this.reconnectConfig.enabled) {
-            this._scheduleReconnect();
-        }
-    }
-
-    /**
-     * Schedule a reconnection attempt
-     */
-    _scheduleReconnect() {
-        if (this.reconnectTimer) {
-            clearTimeout(this.reconnectTimer);
-        }
-
-        // Check max attempts
-        if (this.reconnectConfig.maxAttempts > 0 &&
-            this.reconnectAttempts >= this.reconnectConfig.maxAttempts) {
-            this.log('[WireShadeClient] Max reconnection attempts reached');
-            this.emit('reconnectFailed');
-            return;
-        }
-
-        // Calculate delay with exponential backoff
-        const delay = Math.min(
-            this.reconnectConfig.delay * Math.pow(this.reconnectConfig.backoffMultiplier, this.reconnectAttempts),
-            this.reconnectConfig.maxDelay
-        );
-
-        this.reconnectAttempts++;
-        this.state = ConnectionState.RECONNECTING;
-        this.emit('stateChange', this.state);
-
-        this.log(`[WireShadeClient] Reconnecting in ${delay}ms (attempt ${this.reconnectAttempts}/${this.reconnectConfig.maxAttempts || '∞'})`);
-
-        this.reconnectTimer = setTimeout(() => {
-            this.emit('reconnecting', this.reconnectAttempts);
-            this._initNative();
-        }, delay);
+        this._stopHealthCheck();
     }
 
     /**
@@ -292,12 +328,29 @@ class WireShadeClient extends EventEmitter {
     }
 
     /**
-     * Perform a health check (attempt a simple operation)
+     * Perform a health check: ping the configured VPN host, re-establish the tunnel if it is unreachable
      */
     async _performHealthCheck() {
-        // For now, we rely on the WireGuard keepalives
-        // Future: Could ping a known VPN host
-        this.emit('healthCheck');
+        const host = this.reconnectConfig.healthCheckHost;
+        if (!host || !this.gw) {
+            // Nothing to ping: rely on the WireGuard keepalives and native dead-peer detection
+            this.emit('healthCheck');
+            return;
+        }
+
+        let result;
+        try {
+            result = await this.gw.ping(host, { count: 1, timeoutMs: this.reconnectConfig.healthCheckTimeout });
+        } catch (err) {
+            this.log('[WireShadeClient] Health check failed:', err.message);
+            return;
+        }
+        this.emit('healthCheck', result);
+
+        if (result.received === 0 && this.state === ConnectionState.CONNECTED) {
+            this.log(`[WireShadeClient] Health check: ${host} did not answer, re-establishing tunnel`);
+            this._rebindNative();
+        }
     }
 
     /**
@@ -307,7 +360,6 @@ class WireShadeClient extends EventEmitter {
         this.log('[WireShadeClient] Manual reconnect triggered');
         this.reconnectAttempts = 0;
         this._stopHealthCheck();
-        if (this.reconnectTimer) clearTimeout(this.reconnectTimer);
         this._initNative();
     }
 
@@ -348,61 +400,40 @@ class WireShadeClient extends EventEmitter {
         this.hosts[hostname] = ip;
     }
 
+    /**
+     * Forward a local port to a destination inside the VPN (Local Port Forwarding).
+     * Data is copied natively, without passing through JS.
+     * @param {number|string} localPort - Local port (all interfaces) or 'host:port' to bind
+     * @param {string} remoteHost - IP or `hosts` name inside the VPN
+     * @param {number} remotePort
+     * @returns {Promise<Object>} Server with `address`, `port`, `stats()` and `close()`
+     */
     async forwardLocal(localPort, remoteHost, remotePort) {
-        return new Promise((resolve, reject) => {
-            const server = net.createServer((clientSocket) => {
-                const tunnelSocket = this.agents.tcp.createConnection({
-                    host: remoteHost,
-                    port: remotePort
-                });
-
-                clientSocket.pipe(tunnelSocket);
-                tunnelSocket.pipe(clientSocket);
-
-                const cleanup = () => {
-                    clientSocket.destroy();
-                    tunnelSocket.destroy();
-                };
-                clientSocket.on('error', cleanup);
-                tunnelSocket.on('error', cleanup);
-                clientSocket.on('close', cleanup);
-                tunnelSocket.on('close', cleanup);
-            });
-
-            server.listen(localPort, () => {
-                this.servers.push(server);
-                resolve(server);
-            });
-
-            server.on('error', reject);
+        if (!this.gw) throw new Error("WireShade not initialized");
+        const localBind = typeof localPort === 'string' && localPort.includes(':') ? localPort : `0.0.0.0:${localPort}`;
+        const remoteIp = await new Promise((resolve, reject) => {
+            this._customLookup(remoteHost, { family: 4 }, (err, address) => err ? reject(err) : resolve(address));
         });
+        const server = await this.gw.forwardLocal(localBind, remoteIp, remotePort);
+        this.servers.push(server);
+        return server;
     }
 
     /**
      * Listen on a VPN port and forward all traffic to a local destination (Reverse Port Forwarding).
+     * Connections are accepted and copied natively.
      * @param {number} vpnPort - The port to listen on inside the VPN.
-     * @param {string} targetHost - The local host to forward to (e.g., 'localhost').
-     * @param {number} targetPort - The local port to forward to.
-     * @returns {Promise<WireShadeServer>}
+     * @param {string} targetHost - The local host to forward to (e.g., 'localhost'), or 'unix:/path/to/socket'.
+     * @param {number} [targetPort] - The local port to forward to (not used for Unix sockets).
+     * @param {Object} [options]
+     * @param {number} [options.maxConnections] - Connections open at once; further ones are reset.
+     * @returns {Promise<Object>} Server with `address`, `port`, `stats()` and `close()`
      */
-    async forwardRemote(vpnPort, targetHost, targetPort) {
-        return this.listen(vpnPort, (vpnSocket) => {
-            const localSocket = net.connect(targetPort, targetHost, () => {
-                // Pipe data between VPN socket and Local socket
-                vpnSocket.pipe(localSocket);
-                localSocket.pipe(vpnSocket);
-            });
-
-            const cleanup = () => {
-                vpnSocket.destroy();
-                localSocket.destroy();
-            };
-
-            vpnSocket.on('error', cleanup);
-            localSocket.on('error', cleanup);
-            vpnSocket.on('close', cleanup);
-            localSocket.on('close', cleanup);
-        });
+    async forwardRemote(vpnPort, targetHost, targetPort, options) {
+        if (!this.gw) throw new Error("WireShade not initialized");
+        const server = await this.gw.forwardRemote(vpnPort, targetHost, targetPort, options);
+        this.servers.push(server);
+        return server;
     }
 
     _customLookup(hostname, options, callback) {
@@ -524,12 +555,101 @@ class WireShadeClient extends EventEmitter {
         return this.agents.tcp.createConnection(options, connectionListener);
     }
 
+    /**
+     * Start a SOCKS5 proxy on a local port that connects through the VPN
+     * @param {Object} [options] - { bind = '127.0.0.1:1080', auth: { username, password } }
+     * @returns {Promise<Object>} Server with `address`, `port` and `close()`
+     */
+    async startSocks5(options) {
+        if (!this.gw) throw new Error("WireShade not initialized");
+        const server = await this.gw.startSocks5(options);
+        this.servers.push(server);
+        return server;
+    }
+
+    /**
+     * Start an HTTP proxy (CONNECT and plain http://) on a local port that connects through the VPN
+     * @param {Object} [options] - { bind = '127.0.0.1:8080', auth: { username, password }, allowedIps, allowedPorts }
+     * @returns {Promise<Object>} Server with `address`, `port` and `close()`
+     */
+    async startHttpProxy(options) {
+        if (!this.gw) throw new Error("WireShade not initialized");
+        const server = await this.gw.startHttpProxy(options);
+        this.servers.push(server);
+        return server;
+    }
+
+    /**
+     * Act as an exit node: peers' TCP connections to allowed destinations are made from this machine
+     * @param {Object} options - { allowedIps (required, e.g. ['0.0.0.0/0']), allowedPorts, maxConnections }
+     * @returns {Promise<Object>} Handle with `stats()` and `close()`
+     */
+    async startExitNode(options) {
+        if (!this.gw) throw new Error("WireShade not initialized");
+        const server = await this.gw.startExitNode(options);
+        this.servers.push(server);
+        return server;
+    }
+
+    /**
+     * Ping a host behind the VPN
+     * @param {string} host - IPv4 address or a name from `hosts`
+     * @param {Object} [options] - { count = 4, timeoutMs = 1000, intervalMs = 1000, size = 56 }
+     * @returns {Promise<Object>} { host, transmitted, received, loss, rtts, min, avg, max }
+     */
+    async ping(host, options) {
+        if (!this.gw) throw new Error("WireShade not initialized");
+        return this.gw.ping(this.hosts[host] || host, options);
+    }
+
+    /**
+     * Send a raw IPv4/IPv6 packet through the tunnel
+     * @param {Buffer} packet - Complete IP packet, including headers
+     * @returns {Promise<void>}
+     */
+    async sendPacket(packet) {
+        if (!this.gw) throw new Error("WireShade not initialized");
+        return this.gw.sendPacket(packet);
+    }
+
+    /**
+     * Receive decrypted IP packets from the tunnel
+     * @param {Object} [filter] - { protocol, port, intercept = false }; intercepted packets don't reach the TCP stack
+     * @param {Function} callback - (packet: Buffer) => void
+     * @returns {number} Id for offPacket()
+     */
+    onPacket(filter, callback) {
+        if (!this.gw) throw new Error("WireShade not initialized");
+        return this.gw.onPacket(filter, (err, packet) => callback(packet));
+    }
+
+    offPacket(id) {
+        if (this.gw) this.gw.offPacket(id);
+    }
+
+    /**
+     * Record tunnel traffic to a pcapng file (open it with Wireshark)
+     * @param {string} path
+     * @param {Object} [options] - { inner = true, outer = false, snaplen = 65535 (0 for whole packets) }
+     */
+    startCapture(path, options) {
+        if (!this.gw) throw new Error("WireShade not initialized");
+        this.gw.startCapture(path, options);
+    }
+
+    /**
+     * Stop the capture started with startCapture() and flush the file
+     */
+    stopCapture() {
+        if (this.gw) this.gw.stopCapture();
+    }
+
     close() {
         this.state = ConnectionState.DISCONNECTED;
         this.reconnectConfig.enabled = false; // Prevent reconnection
+        this._closed = true; // Ignore further native state changes
 
         this._stopHealthCheck();
-        if (this.reconnectTimer) clearTimeout(this.reconnectTimer);
 
         if (this.agents.http) this.agents.http.destroy();
         if (this.agents.https) this.agents.https.destroy();
@@ -1,4 +1,5 @@
 const fs = require('fs');
+const net = require('net');
 
 /**
  * Parses a standard WireGuard configuration file content.
@@ -10,12 +11,16 @@ function parseWireGuardConfig(content) {
     const config = {
         privateKey: '',
         sourceIp: '',
+        listenPort: undefined,
         peerPublicKey: '',
         presharedKey: '',
-        endpoint: ''
+        endpoint: '',
+        // Every [Peer] section; the top-level peer fields mirror the first one.
+        peers: []
     };
 
     let currentSection = '';
+    let currentPeer = null;
 
     for (let line of lines) {
         line = line.trim();
@@ -23,6 +28,10 @@ function parseWireGuardConfig(content) {
 
         if (line.startsWith('[') && line.endsWith(']')) {
             currentSection = line.slice(1, -1).toLowerCase();
+            if (currentSection === 'peer') {
+                currentPeer = { publicKey: '', presharedKey: '', endpoint: '', allowedIps: [] };
+                config.peers.push(currentPeer);
+            }
             continue;
         }
 
@@ -36,22 +45,41 @@ function parseWireGuardConfig(content) {
             if (normalizedKey === 'privatekey') {
                 config.privateKey = value;
             } else if (normalizedKey === 'address') {
-                // Remove subnet mask (e.g., /32) if present
-                config.sourceIp = value.split('/')[0].trim();
+                // Without subnet masks (e.g. /32); one IPv4 and optionally one IPv6 address
+                const addresses = value.split(',').map(s => s.split('/')[0].trim());
+                config.sourceIp = addresses.find(s => net.isIPv4(s)) || addresses[0];
+                const ip6 = addresses.find(s => net.isIPv6(s));
+                if (ip6) config.sourceIp6 = ip6;
+            } else if (normalizedKey === 'listenport') {
+                config.listenPort = parseInt(value, 10);
+            } else if (normalizedKey === 'dns') {
+                // Search domains may be listed alongside the servers, keep only addresses
+                config.dns = value.split(',').map(s => s.trim()).filter(s => net.isIPv4(s));
             }
         } else if (currentSection === 'peer') {
             if (normalizedKey === 'publickey') {
-                config.peerPublicKey = value;
+                currentPeer.publicKey = value;
             } else if (normalizedKey === 'presharedkey') {
-                config.presharedKey = value;
+                currentPeer.presharedKey = value;
             } else if (normalizedKey === 'endpoint') {
-                config.endpoint = value;
+                currentPeer.endpoint = value;
+            } else if (normalizedKey === 'allowedips') {
+                currentPeer.allowedIps.push(...value.split(',').map(s => s.trim()).filter(Boolean));
+            } else if (normalizedKey === 'persistentkeepalive') {
+                currentPeer.persistentKeepalive = parseInt(value, 10);
             }
         }
     }
 
-    if (!config.privateKey || !config.peerPublicKey || !config.endpoint) {
-        throw new Error('Invalid WireGuard config: Missing required fields (PrivateKey, PublicKey, or Endpoint)');
+    if (config.peers.length > 0) {
+        config.peerPublicKey = config.peers[0].publicKey;
+        config.presharedKey = config.peers[0].presharedKey;
+        config.endpoint = config.peers[0].endpoint;
+    }
+
+    // Without an endpoint we can only wait for the peer to connect in, which needs a ListenPort.
+    if (!config.privateKey || !config.peerPublicKey || (!config.endpoint && !config.listenPort)) {
+        throw new Error('Invalid WireGuard config: Missing required fields (PrivateKey, PublicKey, or Endpoint/ListenPort)');
     }
 
     return config;
@@ -0,0 +1,29 @@
+// Loads the native addon once for index.js and the lib modules.
+let binding;
+try {
+    binding = require('../wireshade.node');
+} catch (e) {
+    try {
+        binding = require('../wireshade.win32-x64-msvc.node');
+    } catch (e2) {
+        throw new Error('Could not load native binding: ' + e2.message);
+    }
+}
+
+// `for await (const chunk of conn)` reads a connection opened without callbacks until the remote end is done
+binding.Connection.prototype[Symbol.asyncIterator] = async function* () {
+    let chunk;
+    while ((chunk = await this.read()) !== null) {
+        yield chunk;
+    }
+};
+
+// `for await (const conn of listener)` accepts connections until the listener is closed
+binding.Listener.prototype[Symbol.asyncIterator] = async function* () {
+    let conn;
+    while ((conn = await this.accept()) !== null) {
+        yield conn;
+    }
+};
+
+module.exports = binding;
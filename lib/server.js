@@ -1,4 +1,4 @@
-const { Duplex } = require('stream');
+const { ConnectionSocket } = require('./socket');
 const EventEmitter = require('events');
 
 /**
@@ -11,7 +11,8 @@ class WireShadeServer extends EventEmitter {
         this.options = options;
         this.logging = options.logging !== false;
         this.log = this.logging ? console.log : () => { };
-        this.connections = new Map();
+        this.connections = new Set();
+        this.listener = null;
         this.port = null;
         this.listening = false;
     }
@@ -20,99 +21,40 @@ class WireShadeServer extends EventEmitter {
         this.port = port;
 
         try {
-            await this.gw.listen(
-                port,
-                // onConnection: napi-rs passes (err, connId, remoteIp, remotePort)
-                (err, connId, remoteIp, remotePort) => {
-                    if (err) {
-                        this.log(`[Server] Connection error: ${err}`);
-                        return;
-                    }
-                    this.log(`[Server] New connection ${connId} from ${remoteIp}:${remotePort}`);
-
-                    const stream = this._createStream(connId);
-                    stream.remoteAddress = remoteIp;
-                    stream.remotePort = remotePort;
-                    this.connections.set(connId, { stream });
-                    this.emit('connection', stream, { remoteAddress: remoteIp, remotePort });
-                },
-                // onData: napi-rs passes (err, connId, buffer)
-                (err, connId, buffer) => {
-                    if (err) return;
-                    const conn = this.connections.get(connId);
-                    if (conn && conn.stream && buffer) {
-                        this.log(`[Server] Received ${buffer.length} bytes on conn ${connId}`);
-                        conn.stream.push(buffer);
-                    }
-                },
-                // onClose: napi-rs passes (err, connId)
-                (err, connId) => {
-                    if (err) return;
-                    this.log(`[Server] Connection ${connId} closed`);
-                    const conn = this.connections.get(connId);
-                    if (conn && conn.stream) {
-                        conn.stream.push(null);
-                        conn.stream.emit('close');
-                        this.connections.delete(connId);
-                    }
-                }
-            );
-
-            this.listening = true;
-            this.log(`[Server] Listening on VPN port ${port}`);
-            this.emit('listening');
-            if (callback) callback();
+            this.listener = await this.gw.createListener(port);
         } catch (err) {
             this.emit('error', err);
             throw err;
         }
-    }
-
-    _createStream(connId) {
-        const self = this;
-        const log = this.log;
 
-        const stream = new Duplex({
-            allowHalfOpen: true,
-            read(size) { },
-            write(chunk, encoding, callback) {
-                log(`[Server] Writing ${chunk.length} bytes to conn ${connId}`);
-                self.gw.sendTo(connId, chunk)
-                    .then(() => callback())
-                    .catch((err) => {
-                        log(`[Server] Write error: ${err}`);
-                        callback(err);
-                    });
-            }
-        });
-
-        stream.remoteAddress = null;
-        stream.remotePort = null;
-        stream.connId = connId;
+        this.listening = true;
+        this.log(`[Server] Listening on VPN port ${port}`);
+        this.emit('listening');
+        if (callback) callback();
+        this._accept();
+    }
 
-        stream.end = (data, encoding, callback) => {
-            const finish = () => {
-                self.gw.closeConnection(connId).catch(() => { });
-                self.connections.delete(connId);
-                if (callback) callback();
-            };
-            if (data) {
-                stream.write(data, encoding, finish);
-            } else {
-                finish();
+    async _accept() {
+        try {
+            for await (const conn of this.listener) {
+                const { remoteAddress, remotePort } = conn;
+                this.log(`[Server] New connection from ${remoteAddress}:${remotePort}`);
+
+                const socket = new ConnectionSocket(conn);
+                this.connections.add(socket);
+                socket.on('close', () => this.connections.delete(socket));
+                this.emit('connection', socket, { remoteAddress, remotePort });
             }
-        };
-
-        stream.destroy = () => {
-            self.gw.closeConnection(connId).catch(() => { });
-            self.connections.delete(connId);
-        };
-
-        return stream;
+        } catch (err) {
+            // e.g. the tunnel stopped; don't crash servers nobody watches for errors
+            this.log(`[Server] Accept error: ${err}`);
+            if (this.listenerCount('error') > 0) this.emit('error', err);
+        }
     }
 
     close(callback) {
         this.listening = false;
+        if (this.listener) this.listener.close();
         this.emit('close');
         if (callback) callback();
     }
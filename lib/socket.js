@@ -0,0 +1,67 @@
+const { Duplex } = require('stream');
+
+/**
+ * ConnectionSocket - A net.Socket-like stream over a native Connection.
+ * Reads pull from the connection only as fast as the consumer takes data, leaving the
+ * rest in the TCP window; writes complete once the tunnel has taken them.
+ */
+class ConnectionSocket extends Duplex {
+    /**
+     * @param {Connection|Promise<Connection>} connection - Writes wait until it resolves.
+     */
+    constructor(connection) {
+        super({ allowHalfOpen: true });
+        this.connection = null;
+        this.remoteAddress = connection.remoteAddress || null;
+        this.remotePort = connection.remotePort || null;
+        this._connecting = connection;
+    }
+
+    _construct(callback) {
+        Promise.resolve(this._connecting).then(conn => {
+            this._connecting = null;
+            this.connection = conn;
+            this.remoteAddress = conn.remoteAddress;
+            this.remotePort = conn.remotePort;
+            callback();
+            this.emit('connect');
+        }, callback);
+    }
+
+    _read(size) {
+        this.connection.read(size).then(
+            // null: the remote end is done sending
+            (chunk) => this.push(chunk),
+            (err) => this.destroy(err)
+        );
+    }
+
+    _write(chunk, encoding, callback) {
+        this.connection.write(chunk).then(() => callback(), callback);
+    }
+
+    _final(callback) {
+        this.connection.close().then(() => callback(), callback);
+    }
+
+    _destroy(err, callback) {
+        if (this.connection) {
+            // Like a socket: a reset when destroyed on an error or with writes still pending
+            const stop = err || this.writableLength > 0 ? this.connection.abort() : this.connection.close();
+            stop.catch(() => { });
+        }
+        callback(err);
+    }
+
+    // Socket methods expected by http.Agent/ClientRequest and http.Server
+    setTimeout(msecs, callback) {
+        if (callback) this.once('timeout', callback);
+        return this;
+    }
+    setNoDelay(enable) { return this; }
+    setKeepAlive(enable, initialDelay) { return this; }
+    ref() { return this; }
+    unref() { return this; }
+}
+
+module.exports = { ConnectionSocket };
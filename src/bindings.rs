@@ -0,0 +1,789 @@
+//! The Node.js addon: JS-facing option objects and classes over [`Tunnel`].
+
+use crate::error::{promise, WireShadeError};
+use crate::logging::{self, LogRecord};
+use crate::{CaptureConfig, EndpointChange, HttpProxyConfig, NetworkConditions, PacketFilter, PeerSpec, PingConfig, ProxyAuth, ProxyServer, ReconnectPolicy, SimulatedTransport, StateChange, StreamReader, Target, TrafficStats, Transport, Tunnel, TunnelConfig, TunnelListener, TunnelStream, UdpTransport};
+use crate::peer::decode_key;
+use crate::proxy::Access;
+use bytes::Bytes;
+use napi::bindgen_prelude::*;
+use napi::threadsafe_function::{ThreadsafeFunction, ThreadsafeFunctionCallMode};
+use napi::{Env, JsObject};
+use smoltcp::wire::{IpAddress, IpCidr, IpEndpoint, Ipv4Address, Ipv6Address};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+
+// --- Configuration ---
+
+/// A WireGuard peer as passed from JavaScript (mirrors a `[Peer]` section).
+#[napi(object)]
+pub struct PeerConfig {
+    pub public_key: String,
+    pub preshared_key: Option<String>,
+    /// `host:port`. Leave empty for peers that connect to us; their endpoint is learned
+    /// from the first authenticated packet.
+    pub endpoint: Option<String>,
+    /// CIDRs routed to this peer. Plain addresses are treated as /32 (IPv4) or /128 (IPv6).
+    pub allowed_ips: Option<Vec<String>>,
+    pub persistent_keepalive: Option<u16>,
+}
+
+#[napi(object)]
+pub struct WireShadeOptions {
+    /// Bind the outer UDP socket to this port instead of an ephemeral one (`ListenPort`).
+    pub listen_port: Option<u16>,
+    /// IPv6 address inside the tunnel (a second `Address`), to reach IPv6 hosts.
+    pub source_ip6: Option<String>,
+    /// `AllowedIPs` of the peer given to the constructor; all addresses if not set.
+    pub allowed_ips: Option<Vec<String>>,
+    /// Peers in addition to the one given to the constructor.
+    pub peers: Option<Vec<PeerConfig>>,
+    /// Seconds between DNS lookups of peer endpoints given as hostnames. Endpoints are
+    /// always looked up again after repeated handshake failures; this adds a periodic check.
+    pub resolve_interval: Option<u32>,
+    pub reconnect: Option<ReconnectOptions>,
+    /// DNS servers inside the tunnel (`DNS`), used to resolve names for the proxies.
+    pub dns: Option<Vec<String>>,
+    /// Runs the tunnel over a simulated bad network, for testing.
+    pub simulate: Option<SimulateOptions>,
+}
+
+/// When a peer counts as dead and how handshakes with it are retried. Defaults match
+/// the JS client's `reconnect` option.
+#[napi(object)]
+pub struct ReconnectOptions {
+    pub enabled: Option<bool>,
+    /// Seconds without any authenticated packet from the peer after sending it data (default 15).
+    pub dead_peer_timeout: Option<u32>,
+    /// First retry delay in ms (default 1000).
+    pub delay: Option<u32>,
+    /// Upper bound for the retry delay in ms (default 30000).
+    pub max_delay: Option<u32>,
+    pub backoff_multiplier: Option<f64>,
+    /// Random spread applied to each delay, 0.2 = ±20% (default 0.2).
+    pub jitter: Option<f64>,
+    /// Give up after this many retries; 0 retries forever (default 10).
+    pub max_attempts: Option<u32>,
+}
+
+/// A simulated network for the `simulate` option, applied to each direction on its own.
+#[napi(object)]
+pub struct SimulateOptions {
+    /// Fraction of packets dropped, 0 to 1 (default 0).
+    pub loss: Option<f64>,
+    /// Latency added to every packet in ms (default 0).
+    pub delay_ms: Option<u32>,
+    /// Random extra latency up to this many ms (default 0).
+    pub jitter_ms: Option<u32>,
+    /// Fraction of packets delivered twice (default 0).
+    pub duplicate: Option<f64>,
+    /// Fraction of packets held back so that later ones overtake them (default 0).
+    pub reorder: Option<f64>,
+    /// Bytes per second (default: unlimited).
+    pub bandwidth: Option<u32>,
+    /// The same seed makes the same decisions for the same traffic (default 0).
+    pub seed: Option<u32>,
+    /// Affect the decrypted packets instead of the WireGuard datagrams (default false).
+    pub inner: Option<bool>,
+}
+
+/// Which decrypted packets `onPacket` reports. Empty matches everything.
+#[napi(object)]
+pub struct PacketFilterOptions {
+    /// IP protocol number, e.g. 1 (ICMP), 6 (TCP), 17 (UDP), 58 (ICMPv6).
+    pub protocol: Option<u8>,
+    /// TCP/UDP source or destination port.
+    pub port: Option<u16>,
+    /// Keep matching packets away from the TCP stack (default false: they are only copied).
+    pub intercept: Option<bool>,
+}
+
+/// Where `startSocks5` listens and whether clients must log in.
+#[napi(object)]
+pub struct Socks5Options {
+    /// Host address to listen on (default "127.0.0.1:1080").
+    pub bind: Option<String>,
+    pub auth: Option<ProxyAuth>,
+}
+
+/// Where `startExitNode` lets peers connect to.
+#[napi(object)]
+pub struct ExitNodeOptions {
+    /// Destination CIDRs peers may reach, e.g. ["0.0.0.0/0"] for anywhere. Required, so an
+    /// exit node is never an open relay by accident.
+    pub allowed_ips: Vec<String>,
+    /// Destination ports peers may reach (default: any).
+    pub allowed_ports: Option<Vec<u16>>,
+    /// Connections open at once; further ones are reset (default: no limit).
+    pub max_connections: Option<u32>,
+}
+
+/// Limits for `forwardRemote`.
+#[napi(object)]
+pub struct RemoteForwardOptions {
+    /// Connections open at once; further tunnel clients are reset (default: no limit).
+    pub max_connections: Option<u32>,
+}
+
+/// Where `startHttpProxy` listens, who may use it and where it may connect to.
+#[napi(object)]
+pub struct HttpProxyOptions {
+    /// Host address to listen on (default "127.0.0.1:8080").
+    pub bind: Option<String>,
+    /// Require `Proxy-Authorization: Basic` with these credentials.
+    pub auth: Option<ProxyAuth>,
+    /// Destination CIDRs clients may reach (default: any).
+    pub allowed_ips: Option<Vec<String>>,
+    /// Destination ports clients may reach (default: any).
+    pub allowed_ports: Option<Vec<u16>>,
+}
+
+/// Probes sent by `ping`.
+#[napi(object)]
+pub struct PingOptions {
+    /// Echo requests to send (default 4).
+    pub count: Option<u32>,
+    /// How long each probe waits for its reply, in ms (default 1000).
+    pub timeout_ms: Option<u32>,
+    /// Delay between probes in ms (default 1000).
+    pub interval_ms: Option<u32>,
+    /// Payload bytes per probe (default 56, at most 1392 to fit the tunnel MTU).
+    pub size: Option<u32>,
+}
+
+/// What `startCapture` records.
+#[napi(object)]
+pub struct CaptureOptions {
+    /// Decrypted IP packets (default true).
+    pub inner: Option<bool>,
+    /// Encrypted WireGuard datagrams, shown as UDP (default false).
+    pub outer: Option<bool>,
+    /// Bytes kept per packet (default 65535, 0 for whole packets).
+    pub snaplen: Option<u32>,
+}
+
+// --- WireShade ---
+
+#[napi]
+pub struct WireShade {
+    tunnel: Tunnel,
+}
+
+#[napi]
+impl WireShade {
+    #[napi(constructor)]
+    pub fn new(
+        env: Env,
+        private_key: String,
+        peer_public_key: String,
+        preshared_key: Option<String>,
+        endpoint: String,
+        source_ip: String,
+        options: Option<WireShadeOptions>,
+    ) -> Result<Self> {
+        logging::init();
+        let options = options.unwrap_or(WireShadeOptions { listen_port: None, source_ip6: None, allowed_ips: None, peers: None, resolve_interval: None, reconnect: None, dns: None, simulate: None });
+        Self::client_config(private_key, peer_public_key, preshared_key, endpoint, source_ip, options)
+            .and_then(Tunnel::start)
+            .map(|tunnel| Self { tunnel })
+            .map_err(|e| e.into_napi(&env))
+    }
+
+    fn client_config(
+        private_key: String,
+        peer_public_key: String,
+        preshared_key: Option<String>,
+        endpoint: String,
+        source_ip: String,
+        options: WireShadeOptions,
+    ) -> std::result::Result<TunnelConfig, WireShadeError> {
+
+        let mut peers = vec![parse_peer(PeerConfig {
+            public_key: peer_public_key,
+            preshared_key,
+            endpoint: Some(endpoint),
+            // Without its own `AllowedIPs`, the constructor peer is the default route
+            allowed_ips: Some(options.allowed_ips.unwrap_or_else(|| vec!["0.0.0.0/0".to_string(), "::/0".to_string()])),
+            persistent_keepalive: None,
+        })?];
+        for peer in options.peers.unwrap_or_default() {
+            peers.push(parse_peer(peer)?);
+        }
+        let (transport, inner_conditions) = match options.simulate {
+            Some(simulate) if simulate.inner == Some(true) => (None, Some(parse_simulate(simulate))),
+            Some(simulate) => {
+                let udp = UdpTransport::bind(options.listen_port.unwrap_or(0))?;
+                (Some(Box::new(SimulatedTransport::new(udp, parse_simulate(simulate))) as Box<dyn Transport>), None)
+            }
+            None => (None, None),
+        };
+
+        Ok(TunnelConfig {
+            private_key: decode_key(&private_key, "private key")?,
+            source_ip: parse_ip(&source_ip)?,
+            source_ip6: options.source_ip6.as_deref().map(parse_ip6).transpose()?,
+            listen_port: options.listen_port,
+            peers,
+            resolve_interval: options.resolve_interval.filter(|&secs| secs > 0).map(|secs| Duration::from_secs(secs.into())),
+            reconnect: parse_reconnect(options.reconnect),
+            dns_servers: options.dns.unwrap_or_default().iter().map(|s| parse_ip(s).map(IpAddress::Ipv4)).collect::<std::result::Result<_, _>>()?,
+            transport,
+            inner_conditions,
+        })
+    }
+
+    /// Responder mode: bind to `listen_port` and wait for the given peers to connect in,
+    /// like a `wg` interface with `ListenPort` and no peer endpoints. `source_ip6` adds an
+    /// IPv6 address inside the tunnel.
+    #[napi(factory)]
+    pub fn server(env: Env, private_key: String, source_ip: String, listen_port: u16, peers: Vec<PeerConfig>, source_ip6: Option<String>) -> Result<Self> {
+        logging::init();
+        Self::server_config(private_key, source_ip, listen_port, peers, source_ip6)
+            .and_then(Tunnel::start)
+            .map(|tunnel| Self { tunnel })
+            .map_err(|e| e.into_napi(&env))
+    }
+
+    fn server_config(private_key: String, source_ip: String, listen_port: u16, peers: Vec<PeerConfig>, source_ip6: Option<String>) -> std::result::Result<TunnelConfig, WireShadeError> {
+        Ok(TunnelConfig {
+            private_key: decode_key(&private_key, "private key")?,
+            source_ip: parse_ip(&source_ip)?,
+            source_ip6: source_ip6.as_deref().map(parse_ip6).transpose()?,
+            listen_port: Some(listen_port),
+            peers: peers.into_iter().map(parse_peer).collect::<std::result::Result<_, _>>()?,
+            resolve_interval: None,
+            reconnect: ReconnectPolicy::default(),
+            dns_servers: Vec::new(),
+            transport: None,
+            inner_conditions: None,
+        })
+    }
+
+    /// Route native logs at `level` ("off", "error", "warn", "info", "debug", "trace") to
+    /// `callback`, or to stderr without one. Applies to all instances.
+    #[napi]
+    pub fn set_logger(env: Env, level: String, callback: Option<ThreadsafeFunction<LogRecord>>) -> Result<()> {
+        let level = log::LevelFilter::from_str(&level)
+            .map_err(|_| WireShadeError::InvalidArgument(format!("log level {}", level)).into_napi(&env))?;
+        let callback = match callback {
+            Some(mut callback) => {
+                // Logging must not keep the process alive on its own
+                callback.unref(&env)?;
+                Some(callback)
+            }
+            None => None,
+        };
+        logging::set_sink(level, callback);
+        Ok(())
+    }
+
+    /// Without callbacks, resolves once the connection is established and its data is read
+    /// with `read()`. With them, resolves right away and data goes to `on_data`; `on_close`
+    /// receives the reason as a coded error (ECONNREFUSED, ECONNRESET, ETIMEDOUT) or nothing
+    /// when the connection was closed in an orderly way.
+    #[napi(ts_return_type = "Promise<Connection>")]
+    pub fn connect(&self, env: Env, dest_ip: String, dest_port: u16, on_data: Option<ThreadsafeFunction<Buffer>>, on_close: Option<ThreadsafeFunction<Option<WireShadeError>>>) -> Result<JsObject> {
+        let tunnel = self.tunnel.clone();
+        promise(&env, async move {
+            let dest_ip = parse_ip(&dest_ip)?;
+            match (on_data, on_close) {
+                (None, None) => {
+                    let stream = tunnel.connect(dest_ip, dest_port).await?;
+                    Ok(Connection::reading(stream, tunnel, IpEndpoint::new(dest_ip.into(), dest_port)))
+                }
+                (Some(on_data), Some(on_close)) => {
+                    let id = tunnel.connect_with(
+                        dest_ip,
+                        dest_port,
+                        Arc::new(move |_, data| { on_data.call(Ok(Buffer::from(Vec::from(data))), ThreadsafeFunctionCallMode::NonBlocking); }),
+                        Arc::new(move |_, reason| { on_close.call(Ok(reason), ThreadsafeFunctionCallMode::NonBlocking); }),
+                    ).await?;
+                    Ok(Connection { id, tunnel, remote: IpEndpoint::new(dest_ip.into(), dest_port), reader: None })
+                }
+                _ => Err(WireShadeError::InvalidArgument("callbacks: pass both onData and onClose, or neither".to_string())),
+            }
+        })
+    }
+
+    /// Listens on `port` at the tunnel address. Each connection comes out of the listener's
+    /// `accept()` as a `Connection` of its own, read like one opened with `connect`.
+    #[napi(ts_return_type = "Promise<Listener>")]
+    pub fn create_listener(&self, env: Env, port: u16) -> Result<JsObject> {
+        let tunnel = self.tunnel.clone();
+        promise(&env, async move {
+            let listener = tunnel.listen(port).await?;
+            Ok(Listener {
+                local: listener.local_addr(),
+                tunnel,
+                listener: Arc::new(tokio::sync::Mutex::new(Some(listener))),
+                closed: Arc::new(watch::channel(false).0),
+            })
+        })
+    }
+
+    /// Replace the outer UDP socket and all WireGuard sessions, e.g. after a network change.
+    /// The TCP/IP stack is kept, so open connections resume once the new handshake is done.
+    /// Resolves to the new local UDP address.
+    #[napi(ts_return_type = "Promise<string>")]
+    pub fn rebind(&self, env: Env, listen_port: Option<u16>) -> Result<JsObject> {
+        let tunnel = self.tunnel.clone();
+        promise(&env, async move {
+            tunnel.rebind(listen_port).await.map(|addr| addr.to_string())
+        })
+    }
+
+    /// Register a callback invoked with a `StateChange` whenever a peer connects, is
+    /// detected dead, is retried or is given up on
+    #[napi]
+    pub fn on_state_change(&self, env: Env, callback: ThreadsafeFunction<StateChange>) -> Result<()> {
+        self.tunnel.on_state_change(move |change| { callback.call(Ok(change), ThreadsafeFunctionCallMode::NonBlocking); })
+            .map_err(|e| e.into_napi(&env))
+    }
+
+    /// Register a callback invoked with an `EndpointChange` whenever a peer's endpoint moves
+    #[napi]
+    pub fn on_endpoint_change(&self, env: Env, callback: ThreadsafeFunction<EndpointChange>) -> Result<()> {
+        self.tunnel.on_endpoint_change(move |change| { callback.call(Ok(change), ThreadsafeFunctionCallMode::NonBlocking); })
+            .map_err(|e| e.into_napi(&env))
+    }
+
+    /// Register a callback invoked once with the root cause if the tunnel dies (an error or a
+    /// bug in the native loop). Every call after that rejects with `ERR_WIRESHADE_TUNNEL_FAILED`.
+    #[napi]
+    pub fn on_fatal_error(&self, env: Env, mut callback: ThreadsafeFunction<WireShadeError>) -> Result<()> {
+        // Only a report: it must not keep the process alive on its own
+        callback.unref(&env)?;
+        self.tunnel.on_fatal_error(move |err| { callback.call(Ok(err), ThreadsafeFunctionCallMode::NonBlocking); });
+        Ok(())
+    }
+
+    /// Send ICMP echo requests to `ip` through the tunnel. Resolves with the round-trip
+    /// time of each probe and the loss once every probe is answered or timed out.
+    #[napi(ts_return_type = "Promise<PingResult>")]
+    pub fn ping(&self, env: Env, ip: String, options: Option<PingOptions>) -> Result<JsObject> {
+        let tunnel = self.tunnel.clone();
+        promise(&env, async move {
+            let dest_ip = parse_ip(&ip)?;
+            tunnel.ping(dest_ip, parse_ping(options)).await
+        })
+    }
+
+    /// Run a SOCKS5 proxy on a host port that opens its connections through the tunnel.
+    /// Supports CONNECT to IPv4 addresses and to names, which are resolved with the `dns` servers.
+    #[napi(ts_return_type = "Promise<ProxyServer>")]
+    pub fn start_socks5(&self, env: Env, options: Option<Socks5Options>) -> Result<JsObject> {
+        let tunnel = self.tunnel.clone();
+        let (bind, auth) = options.map_or((None, None), |o| (o.bind, o.auth));
+        promise(&env, async move {
+            let bind = bind.unwrap_or_else(|| "127.0.0.1:1080".to_string());
+            tunnel.start_socks5(&bind, auth).await
+        })
+    }
+
+    /// Run an HTTP proxy on a host port that opens its connections through the tunnel:
+    /// `CONNECT host:port` for HTTPS and other TCP, and plain `http://` requests.
+    #[napi(ts_return_type = "Promise<ProxyServer>")]
+    pub fn start_http_proxy(&self, env: Env, options: Option<HttpProxyOptions>) -> Result<JsObject> {
+        let tunnel = self.tunnel.clone();
+        let options = options.unwrap_or(HttpProxyOptions { bind: None, auth: None, allowed_ips: None, allowed_ports: None });
+        promise(&env, async move {
+            let config = HttpProxyConfig {
+                auth: options.auth,
+                access: parse_access(options.allowed_ips, options.allowed_ports)?,
+            };
+            let bind = options.bind.unwrap_or_else(|| "127.0.0.1:8080".to_string());
+            tunnel.start_http_proxy(&bind, config).await
+        })
+    }
+
+    /// Listen on `local_bind` on the host (e.g. "127.0.0.1:3333") and connect every client to
+    /// `remote_ip:remote_port` through the tunnel. Data is copied natively, without JS.
+    #[napi(ts_return_type = "Promise<ProxyServer>")]
+    pub fn forward_local(&self, env: Env, local_bind: String, remote_ip: String, remote_port: u16) -> Result<JsObject> {
+        let tunnel = self.tunnel.clone();
+        promise(&env, async move {
+            let remote_ip = parse_ip(&remote_ip)?;
+            tunnel.forward_local(&local_bind, remote_ip, remote_port).await
+        })
+    }
+
+    /// Listen on `tunnel_port` at the tunnel address and connect every client to
+    /// `target_host:target_port` on the host. A `target_host` of "unix:/path" connects to a
+    /// Unix socket instead (no port). Data is copied natively, without JS.
+    #[napi(ts_return_type = "Promise<ProxyServer>")]
+    pub fn forward_remote(&self, env: Env, tunnel_port: u16, target_host: String, target_port: Option<u16>, options: Option<RemoteForwardOptions>) -> Result<JsObject> {
+        let tunnel = self.tunnel.clone();
+        let max_connections = options.and_then(|o| o.max_connections);
+        promise(&env, async move {
+            let target = match (target_host.strip_prefix("unix:"), target_port) {
+                #[cfg(unix)]
+                (Some(path), _) => Target::Unix(path.into()),
+                #[cfg(not(unix))]
+                (Some(_), _) => return Err(WireShadeError::InvalidArgument("Unix sockets are not supported on this platform".to_string())),
+                (None, Some(port)) if target_host.contains(':') => Target::Tcp(format!("[{}]:{}", target_host, port)),
+                (None, Some(port)) => Target::Tcp(format!("{}:{}", target_host, port)),
+                (None, None) => return Err(WireShadeError::InvalidArgument(format!("no port for {}", target_host))),
+            };
+            tunnel.forward_remote(tunnel_port, target, max_connections).await
+        })
+    }
+
+    /// Act as an exit node: TCP connections peers make through us to hosts in
+    /// `options.allowedIps` are accepted and made from this machine instead, and the data
+    /// relayed natively. Peers route those destinations to us in their AllowedIPs.
+    #[napi(ts_return_type = "Promise<ProxyServer>")]
+    pub fn start_exit_node(&self, env: Env, options: ExitNodeOptions) -> Result<JsObject> {
+        let tunnel = self.tunnel.clone();
+        promise(&env, async move {
+            let access = parse_access(Some(options.allowed_ips), options.allowed_ports)?;
+            tunnel.start_exit_node(access, options.max_connections).await
+        })
+    }
+
+    /// Inject a raw IPv4 or IPv6 packet into the tunnel. It is routed to a peer by its
+    /// destination like packets from the TCP stack, and sent as is.
+    #[napi(ts_return_type = "Promise<void>")]
+    pub fn send_packet(&self, env: Env, packet: Buffer) -> Result<JsObject> {
+        let packet: Vec<u8> = packet.into();
+        let tunnel = self.tunnel.clone();
+        promise(&env, async move { tunnel.send_packet(packet).await })
+    }
+
+    /// Register a callback for decrypted packets from the tunnel that match `filter`,
+    /// called with the raw IP packet. Returns an id for `offPacket`.
+    #[napi]
+    pub fn on_packet(&self, env: Env, filter: Option<PacketFilterOptions>, callback: ThreadsafeFunction<Buffer>) -> Result<u32> {
+        let filter = PacketFilter {
+            protocol: filter.as_ref().and_then(|f| f.protocol),
+            port: filter.as_ref().and_then(|f| f.port),
+            intercept: filter.as_ref().and_then(|f| f.intercept).unwrap_or(false),
+        };
+        self.tunnel.on_packet(filter, move |packet| {
+            callback.call(Ok(Buffer::from(packet.to_vec())), ThreadsafeFunctionCallMode::NonBlocking);
+        }).map_err(|e| e.into_napi(&env))
+    }
+
+    #[napi]
+    pub fn off_packet(&self, env: Env, id: u32) -> Result<()> {
+        self.tunnel.off_packet(id).map_err(|e| e.into_napi(&env))
+    }
+
+    /// Write packets to a pcapng file at `path` for Wireshark until `stopCapture()`.
+    /// Starting again switches to the new file.
+    #[napi]
+    pub fn start_capture(&self, env: Env, path: String, options: Option<CaptureOptions>) -> Result<()> {
+        let defaults = CaptureConfig::default();
+        let config = CaptureConfig {
+            inner: options.as_ref().and_then(|o| o.inner).unwrap_or(defaults.inner),
+            outer: options.as_ref().and_then(|o| o.outer).unwrap_or(defaults.outer),
+            snaplen: options.as_ref().and_then(|o| o.snaplen).unwrap_or(defaults.snaplen),
+        };
+        self.tunnel.start_capture(&path, config).map_err(|e| e.into_napi(&env))
+    }
+
+    /// Stop the running capture and flush it to disk.
+    #[napi]
+    pub fn stop_capture(&self, env: Env) -> Result<()> {
+        self.tunnel.stop_capture().map_err(|e| e.into_napi(&env))
+    }
+}
+
+/// Bytes `read` returns at most when the caller doesn't say.
+const READ_SIZE: u32 = 64 * 1024;
+
+#[napi]
+pub struct Connection {
+    id: u32,
+    tunnel: Tunnel,
+    remote: IpEndpoint,
+    /// Set when the connection was opened without callbacks, so its data waits for `read`.
+    reader: Option<Arc<tokio::sync::Mutex<Reader>>>,
+}
+
+/// Received data of a connection read with `read`, including the rest of a chunk that
+/// was larger than asked for.
+struct Reader {
+    stream: StreamReader,
+    unread: Bytes,
+}
+
+impl Reader {
+    async fn read(&mut self, max: usize) -> std::result::Result<Option<Bytes>, WireShadeError> {
+        if self.unread.is_empty() {
+            match self.stream.read().await? {
+                Some(data) => self.unread = data,
+                None => return Ok(None),
+            }
+        }
+        if self.unread.len() <= max {
+            return Ok(Some(std::mem::take(&mut self.unread)));
+        }
+        Ok(Some(self.unread.split_to(max)))
+    }
+}
+
+impl Connection {
+    /// A connection whose data waits for `read`.
+    fn reading(stream: TunnelStream, tunnel: Tunnel, remote: IpEndpoint) -> Self {
+        let (id, stream) = stream.into_reader();
+        let reader = Reader { stream, unread: Bytes::new() };
+        Connection { id, tunnel, remote, reader: Some(Arc::new(tokio::sync::Mutex::new(reader))) }
+    }
+}
+
+#[napi]
+impl Connection {
+    /// Address of the other end: where we connected to, or where an accepted connection
+    /// came from.
+    #[napi(getter)]
+    pub fn remote_address(&self) -> String {
+        self.remote.addr.to_string()
+    }
+
+    #[napi(getter)]
+    pub fn remote_port(&self) -> u16 {
+        self.remote.port
+    }
+
+    /// Queue `data` for sending. Prefer `write` to wait until the tunnel has taken it.
+    /// `data` is copied, so the caller may reuse it right away.
+    #[napi(ts_return_type = "Promise<void>")]
+    pub fn send(&self, env: Env, data: Buffer) -> Result<JsObject> {
+        send_data(&env, self.tunnel.clone(), self.id, data)
+    }
+
+    /// Resolves once `data` is in the TCP send buffer, so a writer waiting on it never runs
+    /// ahead of the tunnel. Rejects with the reason if the connection is gone.
+    #[napi(ts_return_type = "Promise<void>")]
+    pub fn write(&self, env: Env, data: Buffer) -> Result<JsObject> {
+        // Copied: JS may reuse the Buffer as soon as this returns
+        let data = Bytes::copy_from_slice(&data);
+        let (tunnel, id) = (self.tunnel.clone(), self.id);
+        promise(&env, async move { tunnel.write_data(id, data).await })
+    }
+
+    /// Next chunk of at most `max_bytes` (default 64 KiB) received data, or `null` once the
+    /// remote end has finished sending. Rejects with the reason (e.g. ECONNRESET) if the
+    /// connection broke. Only for connections opened without callbacks.
+    #[napi(ts_return_type = "Promise<Buffer | null>")]
+    pub fn read(&self, env: Env, max_bytes: Option<u32>) -> Result<JsObject> {
+        let reader = self.reader.clone();
+        promise(&env, async move {
+            let Some(reader) = reader else {
+                return Err(WireShadeError::InvalidArgument("read: this connection delivers its data to onData".to_string()));
+            };
+            let max = match max_bytes.unwrap_or(READ_SIZE) {
+                0 => return Err(WireShadeError::InvalidArgument("read size".to_string())),
+                max => max as usize,
+            };
+            let data = reader.lock().await.read(max).await?;
+            Ok(data.map(|data| Buffer::from(Vec::from(data))))
+        })
+    }
+
+    /// Send FIN after everything written so far; data can still be received.
+    #[napi(ts_return_type = "Promise<void>")]
+    pub fn close(&self, env: Env) -> Result<JsObject> {
+        close_connection(&env, self.tunnel.clone(), self.id)
+    }
+
+    /// Reset the connection right away, dropping anything not sent yet.
+    #[napi(ts_return_type = "Promise<void>")]
+    pub fn abort(&self, env: Env) -> Result<JsObject> {
+        let (tunnel, id) = (self.tunnel.clone(), self.id);
+        promise(&env, async move { tunnel.abort_connection(id).await })
+    }
+}
+
+/// Connections arriving on a tunnel port, from `createListener`.
+#[napi]
+pub struct Listener {
+    local: IpEndpoint,
+    tunnel: Tunnel,
+    /// Taken by `close`, which stops listening.
+    listener: Arc<tokio::sync::Mutex<Option<TunnelListener>>>,
+    closed: Arc<watch::Sender<bool>>,
+}
+
+#[napi]
+impl Listener {
+    /// Address it listens on, e.g. "10.0.0.2:80".
+    #[napi(getter)]
+    pub fn address(&self) -> String {
+        self.local.to_string()
+    }
+
+    #[napi(getter)]
+    pub fn port(&self) -> u16 {
+        self.local.port
+    }
+
+    /// Next connection, or `null` once the listener is closed.
+    #[napi(ts_return_type = "Promise<Connection | null>")]
+    pub fn accept(&self, env: Env) -> Result<JsObject> {
+        let (listener, tunnel, mut closed) = (self.listener.clone(), self.tunnel.clone(), self.closed.subscribe());
+        promise(&env, async move {
+            let mut guard = listener.lock().await;
+            let Some(listener) = guard.as_mut() else { return Ok(None) };
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = closed.wait_for(|closed| *closed) => {
+                    // close() couldn't take it while we held it
+                    guard.take();
+                    return Ok(None);
+                }
+            };
+            let (stream, _, remote) = accepted?;
+            Ok(Some(Connection::reading(stream, tunnel, remote)))
+        })
+    }
+
+    /// Stop listening. Pending and later `accept` calls resolve to `null`; connections
+    /// already accepted stay open.
+    #[napi]
+    pub fn close(&self) {
+        self.closed.send_replace(true);
+        if let Ok(mut listener) = self.listener.try_lock() {
+            listener.take();
+        }
+    }
+}
+
+#[napi]
+impl ProxyServer {
+    /// Address it listens on, e.g. "127.0.0.1:1080".
+    #[napi(getter)]
+    pub fn address(&self) -> String {
+        self.local_addr().to_string()
+    }
+
+    #[napi(getter)]
+    pub fn port(&self) -> u16 {
+        self.local_addr().port()
+    }
+
+    #[napi(js_name = "stats")]
+    pub fn js_stats(&self) -> TrafficStats {
+        self.stats()
+    }
+
+    /// Stop listening and drop every connection it opened.
+    #[napi(js_name = "close")]
+    pub fn js_close(&self) {
+        self.close()
+    }
+}
+
+fn send_data(env: &Env, tunnel: Tunnel, connection_id: u32, data: Buffer) -> Result<JsObject> {
+    let data = Bytes::copy_from_slice(&data);
+    promise(env, async move { tunnel.send_data(connection_id, data).await })
+}
+
+fn close_connection(env: &Env, tunnel: Tunnel, connection_id: u32) -> Result<JsObject> {
+    promise(env, async move { tunnel.close_connection(connection_id).await })
+}
+
+pub(crate) fn parse_peer(config: PeerConfig) -> std::result::Result<PeerSpec, WireShadeError> {
+    let public_key = decode_key(&config.public_key, "peer key")?;
+    // The JS client passes "" when there is no preshared key.
+    let preshared_key = match config.preshared_key.filter(|psk| !psk.is_empty()) {
+        Some(psk) => Some(decode_key(&psk, "psk")?),
+        None => None,
+    };
+
+    let endpoint_str = config.endpoint.filter(|e| !e.is_empty());
+    let endpoint = match &endpoint_str {
+        Some(endpoint) => {
+            log::debug!("Resolving endpoint {}", endpoint);
+            let addr = endpoint.to_socket_addrs().ok().and_then(|mut addrs| addrs.next())
+                .ok_or_else(|| WireShadeError::EndpointNotFound(endpoint.clone()))?;
+            log::debug!("{} resolved to {}", endpoint, addr);
+            Some(addr)
+        }
+        None => None,
+    };
+    // Literal addresses never change, only hostnames are worth looking up again.
+    let endpoint_host = endpoint_str.filter(|e| SocketAddr::from_str(e).is_err());
+
+    let allowed_ips = config.allowed_ips.unwrap_or_default().iter()
+        .map(|cidr| parse_cidr(cidr).ok_or_else(|| WireShadeError::InvalidAddress(cidr.clone())))
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+
+    Ok(PeerSpec {
+        public_key,
+        preshared_key,
+        endpoint,
+        endpoint_host,
+        allowed_ips,
+        persistent_keepalive: config.persistent_keepalive,
+    })
+}
+
+fn parse_reconnect(options: Option<ReconnectOptions>) -> ReconnectPolicy {
+    let defaults = ReconnectPolicy::default();
+    let Some(options) = options else { return defaults };
+    ReconnectPolicy {
+        enabled: options.enabled.unwrap_or(defaults.enabled),
+        dead_peer_timeout: options.dead_peer_timeout.map_or(defaults.dead_peer_timeout, |s| Duration::from_secs(s.into())),
+        delay: options.delay.map_or(defaults.delay, |ms| Duration::from_millis(ms.into())),
+        max_delay: options.max_delay.map_or(defaults.max_delay, |ms| Duration::from_millis(ms.into())),
+        backoff_multiplier: options.backoff_multiplier.unwrap_or(defaults.backoff_multiplier).max(1.0),
+        jitter: options.jitter.unwrap_or(defaults.jitter).clamp(0.0, 1.0),
+        max_attempts: options.max_attempts.unwrap_or(defaults.max_attempts),
+    }
+}
+
+fn parse_simulate(options: SimulateOptions) -> NetworkConditions {
+    NetworkConditions {
+        loss: options.loss.unwrap_or(0.0).clamp(0.0, 1.0),
+        delay: Duration::from_millis(options.delay_ms.unwrap_or(0).into()),
+        jitter: Duration::from_millis(options.jitter_ms.unwrap_or(0).into()),
+        duplicate: options.duplicate.unwrap_or(0.0).clamp(0.0, 1.0),
+        reorder: options.reorder.unwrap_or(0.0).clamp(0.0, 1.0),
+        bandwidth: options.bandwidth.map(u64::from),
+        seed: options.seed.unwrap_or(0).into(),
+    }
+}
+
+fn parse_ping(options: Option<PingOptions>) -> PingConfig {
+    let defaults = PingConfig::default();
+    let Some(options) = options else { return defaults };
+    PingConfig {
+        count: options.count.unwrap_or(defaults.count),
+        size: options.size.map_or(defaults.size, |size| size as usize),
+        interval: options.interval_ms.map_or(defaults.interval, |ms| Duration::from_millis(ms.into())),
+        timeout: options.timeout_ms.map_or(defaults.timeout, |ms| Duration::from_millis(ms.into())),
+    }
+}
+
+pub(crate) fn parse_access(ips: Option<Vec<String>>, ports: Option<Vec<u16>>) -> std::result::Result<Access, WireShadeError> {
+    let ips = ips.unwrap_or_default().iter()
+        .map(|s| parse_cidr(s).ok_or_else(|| WireShadeError::InvalidAddress(s.clone())))
+        .collect::<std::result::Result<_, _>>()?;
+    Ok(Access { ips, ports: ports.unwrap_or_default() })
+}
+
+pub(crate) fn parse_cidr(s: &str) -> Option<IpCidr> {
+    let s = s.trim();
+    if s.contains('/') {
+        IpCidr::from_str(s).ok()
+    } else {
+        let ip = IpAddress::from_str(s).ok()?;
+        let prefix_len = match ip {
+            IpAddress::Ipv4(_) => 32,
+            IpAddress::Ipv6(_) => 128,
+        };
+        Some(IpCidr::new(ip, prefix_len))
+    }
+}
+
+pub(crate) fn parse_ip(s: &str) -> std::result::Result<Ipv4Address, WireShadeError> {
+    Ipv4Address::from_str(s).map_err(|_| WireShadeError::InvalidAddress(s.to_string()))
+}
+
+fn parse_ip6(s: &str) -> std::result::Result<Ipv6Address, WireShadeError> {
+    Ipv6Address::from_str(s).map_err(|_| WireShadeError::InvalidAddress(s.to_string()))
+}
@@ -0,0 +1,325 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const SECTION_HEADER: u32 = 0x0A0D_0D0A;
+const INTERFACE_DESCRIPTION: u32 = 1;
+const ENHANCED_PACKET: u32 = 6;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+/// LINKTYPE_RAW: the packet starts with the IPv4/IPv6 header.
+const LINKTYPE_RAW: u16 = 101;
+const OPT_END: u16 = 0;
+const OPT_IF_NAME: u16 = 2;
+
+pub struct CaptureConfig {
+    /// Decrypted packets as they enter and leave the tunnel.
+    pub inner: bool,
+    /// The encrypted WireGuard datagrams, wrapped in made-up IPv4/UDP headers.
+    pub outer: bool,
+    /// Bytes kept of each packet; 0 keeps them whole, as in pcapng.
+    pub snaplen: u32,
+}
+
+impl Default for CaptureConfig {
+    fn default() -> Self {
+        Self { inner: true, outer: false, snaplen: 65535 }
+    }
+}
+
+/// A pcapng capture of the tunnel that can be started and stopped while it runs.
+/// Shared between the JS handle and the loop; idle captures cost one atomic load per packet.
+#[derive(Default)]
+pub(crate) struct Capture {
+    active: AtomicBool,
+    writer: Mutex<Option<Writer>>,
+}
+
+impl Capture {
+    /// Starts writing to `path`, replacing a capture that is already running.
+    pub fn start(&self, path: &str, config: CaptureConfig) -> std::io::Result<()> {
+        let writer = Writer::create(path, config)?;
+        *self.lock() = Some(writer);
+        self.active.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    /// Stops the capture and flushes the file. Does nothing if none is running.
+    pub fn stop(&self) -> std::io::Result<()> {
+        self.active.store(false, Ordering::Release);
+        match self.lock().take() {
+            Some(mut writer) => writer.out.flush(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active.load(Ordering::Acquire)
+    }
+
+    pub fn inner(&self, packet: &[u8]) {
+        if self.is_active() {
+            self.write(|writer| match writer.inner_if {
+                Some(interface) => writer.packet(interface, packet),
+                None => Ok(()),
+            });
+        }
+    }
+
+    pub fn outer(&self, src: SocketAddr, dst: SocketAddr, payload: &[u8]) {
+        if self.is_active() {
+            self.write(|writer| match (writer.outer_if, src, dst) {
+                (Some(interface), SocketAddr::V4(src), SocketAddr::V4(dst)) => {
+                    let mut packet = ipv4_udp_header(src, dst, payload.len());
+                    packet.extend_from_slice(payload);
+                    writer.packet(interface, &packet)
+                }
+                // The outer socket is IPv4-only
+                _ => Ok(()),
+            });
+        }
+    }
+
+    /// Pushes buffered packets to disk, so a capture read while running is reasonably current.
+    pub fn flush(&self) {
+        if self.is_active() {
+            self.write(|writer| writer.out.flush());
+        }
+    }
+
+    fn write(&self, f: impl FnOnce(&mut Writer) -> std::io::Result<()>) {
+        let mut guard = self.lock();
+        let Some(writer) = guard.as_mut() else { return };
+        if let Err(e) = f(writer) {
+            log::warn!("Writing capture failed, stopping it: {}", e);
+            *guard = None;
+            self.active.store(false, Ordering::Release);
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Option<Writer>> {
+        self.writer.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+struct Writer {
+    out: BufWriter<File>,
+    inner_if: Option<u32>,
+    outer_if: Option<u32>,
+    snaplen: usize,
+}
+
+impl Writer {
+    fn create(path: &str, config: CaptureConfig) -> std::io::Result<Self> {
+        let mut out = BufWriter::new(File::create(path)?);
+
+        // Section header: byte order magic, version 1.0, unknown section length
+        let mut body = Vec::with_capacity(16);
+        body.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+        body.extend_from_slice(&1u16.to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes());
+        body.extend_from_slice(&(-1i64).to_le_bytes());
+        write_block(&mut out, SECTION_HEADER, &body)?;
+
+        let mut next_if = 0;
+        let mut interface = |out: &mut BufWriter<File>, enabled: bool, name: &str| -> std::io::Result<Option<u32>> {
+            if !enabled {
+                return Ok(None);
+            }
+            let mut body = Vec::new();
+            body.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+            body.extend_from_slice(&0u16.to_le_bytes());
+            body.extend_from_slice(&config.snaplen.to_le_bytes());
+            push_option(&mut body, OPT_IF_NAME, name.as_bytes());
+            push_option(&mut body, OPT_END, &[]);
+            write_block(out, INTERFACE_DESCRIPTION, &body)?;
+            next_if += 1;
+            Ok(Some(next_if - 1))
+        };
+        let inner_if = interface(&mut out, config.inner, "wireshade-inner")?;
+        let outer_if = interface(&mut out, config.outer, "wireshade-outer")?;
+
+        let snaplen = match config.snaplen {
+            0 => usize::MAX,
+            snaplen => snaplen as usize,
+        };
+        Ok(Self { out, inner_if, outer_if, snaplen })
+    }
+
+    fn packet(&mut self, interface: u32, packet: &[u8]) -> std::io::Result<()> {
+        // Default interface timestamp resolution is microseconds
+        let micros = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_micros() as u64;
+        let captured = &packet[..packet.len().min(self.snaplen)];
+
+        let mut body = Vec::with_capacity(20 + captured.len() + 3);
+        body.extend_from_slice(&interface.to_le_bytes());
+        body.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+        body.extend_from_slice(&(micros as u32).to_le_bytes());
+        body.extend_from_slice(&(captured.len() as u32).to_le_bytes());
+        body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        body.extend_from_slice(captured);
+        pad(&mut body);
+        write_block(&mut self.out, ENHANCED_PACKET, &body)
+    }
+}
+
+/// Writes one block; `body` must already be padded to 32 bits.
+fn write_block(out: &mut impl Write, block_type: u32, body: &[u8]) -> std::io::Result<()> {
+    let total = (body.len() + 12) as u32;
+    out.write_all(&block_type.to_le_bytes())?;
+    out.write_all(&total.to_le_bytes())?;
+    out.write_all(body)?;
+    out.write_all(&total.to_le_bytes())
+}
+
+fn push_option(body: &mut Vec<u8>, code: u16, value: &[u8]) {
+    body.extend_from_slice(&code.to_le_bytes());
+    body.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    body.extend_from_slice(value);
+    pad(body);
+}
+
+fn pad(body: &mut Vec<u8>) {
+    body.resize(body.len().next_multiple_of(4), 0);
+}
+
+/// IPv4 and UDP headers for an outer datagram, so Wireshark decodes it as WireGuard.
+fn ipv4_udp_header(src: std::net::SocketAddrV4, dst: std::net::SocketAddrV4, payload_len: usize) -> Vec<u8> {
+    let udp_len = (8 + payload_len) as u16;
+    let total_len = 20 + udp_len;
+    let mut header = Vec::with_capacity(28 + payload_len);
+    header.extend_from_slice(&[0x45, 0]);
+    header.extend_from_slice(&total_len.to_be_bytes());
+    // id 0, don't fragment, ttl 64, UDP, checksum filled in below
+    header.extend_from_slice(&[0, 0, 0x40, 0, 64, 17, 0, 0]);
+    header.extend_from_slice(&src.ip().octets());
+    header.extend_from_slice(&dst.ip().octets());
+    let mut sum: u32 = header.chunks(2).map(|word| u32::from(u16::from_be_bytes([word[0], word[1]]))).sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    header[10..12].copy_from_slice(&(!(sum as u16)).to_be_bytes());
+
+    header.extend_from_slice(&src.port().to_be_bytes());
+    header.extend_from_slice(&dst.port().to_be_bytes());
+    header.extend_from_slice(&udp_len.to_be_bytes());
+    // A zero UDP checksum means "not computed", which IPv4 allows
+    header.extend_from_slice(&[0, 0]);
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Captures `packets` on the inner interface and returns the file.
+    fn capture(config: CaptureConfig, packets: &[&[u8]]) -> Vec<u8> {
+        static NEXT: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+        let n = NEXT.fetch_add(1, Ordering::Relaxed);
+        let path = std::env::temp_dir().join(format!("wireshade-capture-{}-{}.pcapng", std::process::id(), n));
+        let capture = Capture::default();
+        capture.start(path.to_str().unwrap(), config).unwrap();
+        for packet in packets {
+            capture.inner(packet);
+        }
+        capture.stop().unwrap();
+        let file = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        file
+    }
+
+    /// Splits a file into (type, body) blocks, checking both length fields and alignment.
+    fn blocks(mut file: &[u8]) -> Vec<(u32, &[u8])> {
+        let word = |bytes: &[u8]| u32::from_le_bytes(bytes[..4].try_into().unwrap());
+        let mut blocks = Vec::new();
+        while !file.is_empty() {
+            let total = word(&file[4..]) as usize;
+            assert_eq!(total % 4, 0, "block length {} not 32-bit aligned", total);
+            assert_eq!(word(&file[total - 4..]), total as u32, "trailing length");
+            blocks.push((word(file), &file[8..total - 4]));
+            file = &file[total..];
+        }
+        blocks
+    }
+
+    #[test]
+    fn writes_section_and_interface_headers() {
+        let file = capture(CaptureConfig::default(), &[]);
+        #[rustfmt::skip]
+        let expected: &[u8] = &[
+            // Section header, 28 bytes
+            0x0a, 0x0d, 0x0d, 0x0a, 28, 0, 0, 0,
+            0x4d, 0x3c, 0x2b, 0x1a, 1, 0, 0, 0,
+            0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+            28, 0, 0, 0,
+            // Interface description, 44 bytes: raw IP, snaplen 65535
+            1, 0, 0, 0, 44, 0, 0, 0,
+            101, 0, 0, 0, 0xff, 0xff, 0, 0,
+            // if_name, 15 bytes padded to 16
+            2, 0, 15, 0, b'w', b'i', b'r', b'e', b's', b'h', b'a', b'd', b'e', b'-', b'i', b'n', b'n', b'e', b'r', 0,
+            // opt_endofopt
+            0, 0, 0, 0,
+            44, 0, 0, 0,
+        ];
+        assert_eq!(file, expected);
+    }
+
+    #[test]
+    fn numbers_the_interfaces_in_order() {
+        let file = capture(CaptureConfig { outer: true, ..CaptureConfig::default() }, &[]);
+        let blocks = blocks(&file);
+        assert_eq!(blocks.iter().map(|(kind, _)| *kind).collect::<Vec<_>>(), [SECTION_HEADER, INTERFACE_DESCRIPTION, INTERFACE_DESCRIPTION]);
+        assert_eq!(&blocks[2].1[12..27], b"wireshade-outer");
+    }
+
+    #[test]
+    fn pads_packets_to_32_bits() {
+        let file = capture(CaptureConfig::default(), &[&[1, 2, 3, 4, 5], &[6, 7, 8, 9]]);
+        let blocks = blocks(&file);
+        assert_eq!(blocks.len(), 4);
+        for ((kind, body), packet) in blocks[2..].iter().zip([&[1u8, 2, 3, 4, 5][..], &[6, 7, 8, 9]]) {
+            assert_eq!(*kind, ENHANCED_PACKET);
+            // Interface 0, then the timestamp, then captured and original length
+            assert_eq!(&body[..4], &[0, 0, 0, 0]);
+            assert_eq!(&body[12..20], &[packet.len() as u8, 0, 0, 0, packet.len() as u8, 0, 0, 0]);
+            assert_eq!(&body[20..20 + packet.len()], packet);
+            assert_eq!(body.len(), 20 + packet.len().next_multiple_of(4));
+            assert!(body[20 + packet.len()..].iter().all(|&b| b == 0));
+        }
+        // 5 bytes of packet and 3 of padding
+        assert_eq!(&file[file.len() - 76..file.len() - 68], &[6, 0, 0, 0, 40, 0, 0, 0]);
+    }
+
+    #[test]
+    fn cuts_packets_at_snaplen() {
+        let file = capture(CaptureConfig { snaplen: 3, ..CaptureConfig::default() }, &[&[1, 2, 3, 4, 5]]);
+        let blocks = blocks(&file);
+        assert_eq!(&blocks[1].1[4..8], &[3, 0, 0, 0]);
+        let body = blocks[2].1;
+        assert_eq!(&body[12..20], &[3, 0, 0, 0, 5, 0, 0, 0]);
+        assert_eq!(&body[20..], &[1, 2, 3, 0]);
+    }
+
+    #[test]
+    fn snaplen_0_keeps_whole_packets() {
+        let packet = [7u8; 1500];
+        let file = capture(CaptureConfig { snaplen: 0, ..CaptureConfig::default() }, &[&packet]);
+        let blocks = blocks(&file);
+        assert_eq!(&blocks[1].1[4..8], &[0, 0, 0, 0]);
+        let body = blocks[2].1;
+        assert_eq!(&body[12..20], &[0xdc, 0x05, 0, 0, 0xdc, 0x05, 0, 0]);
+        assert_eq!(&body[20..], &packet);
+    }
+
+    #[test]
+    fn wraps_outer_datagrams_in_ipv4_and_udp() {
+        let header = ipv4_udp_header("192.0.2.1:51820".parse().unwrap(), "192.0.2.2:443".parse().unwrap(), 4);
+        #[rustfmt::skip]
+        assert_eq!(header, [
+            0x45, 0, 0, 32, 0, 0, 0x40, 0, 64, 17, 0xb6, 0xc9, 192, 0, 2, 1, 192, 0, 2, 2,
+            0xca, 0x6c, 0x01, 0xbb, 0, 12, 0, 0,
+        ]);
+    }
+}
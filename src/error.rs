@@ -0,0 +1,129 @@
+#[cfg(feature = "napi")]
+use napi::bindgen_prelude::*;
+#[cfg(feature = "napi")]
+use napi::{Env, JsError, JsObject, JsUnknown, NapiValue};
+use smoltcp::wire::IpAddress;
+#[cfg(feature = "napi")]
+use std::future::Future;
+
+/// Everything the tunnel can fail with. Each variant maps to a stable `code` on the JS
+/// error, using the Node.js code where one exists so `err.code === 'ECONNREFUSED'` works
+/// the same as with `net`.
+#[derive(Debug, thiserror::Error)]
+pub enum WireShadeError {
+    #[error("Invalid {kind}: {reason}")]
+    InvalidKey { kind: &'static str, reason: String },
+    #[error("Invalid IP address: {0}")]
+    InvalidAddress(String),
+    #[error("Invalid {0}")]
+    InvalidArgument(String),
+    #[error("Endpoint {0} did not resolve")]
+    EndpointNotFound(String),
+    /// A name was to be resolved inside the tunnel, which has no `DNS` servers.
+    #[error("No DNS server configured for the tunnel")]
+    NoDnsServer,
+    #[error("No peer routes {0}")]
+    NoRoute(IpAddress),
+    #[error("Handshake with the peer timed out")]
+    HandshakeTimeout,
+    #[error("Tunnel is closed")]
+    TunnelClosed,
+    /// The tunnel loop has too many commands queued to take one more without waiting.
+    #[error("Tunnel is busy, try again")]
+    Busy,
+    /// The tunnel task hit a fatal error or panicked; carries the root cause.
+    #[error("Tunnel failed: {0}")]
+    TunnelFailed(String),
+    #[error("Connection refused")]
+    ConnectionRefused,
+    #[error("Connection reset by peer")]
+    ConnectionReset,
+    #[error("Connection timed out")]
+    Timeout,
+    #[error("Too many open sockets")]
+    SocketLimit,
+    #[error("Port {0} is already in use")]
+    AddressInUse(u16),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+impl WireShadeError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            WireShadeError::InvalidKey { .. } => "ERR_WIRESHADE_INVALID_KEY",
+            WireShadeError::InvalidAddress(_) => "ERR_INVALID_IP_ADDRESS",
+            WireShadeError::InvalidArgument(_) => "ERR_INVALID_ARG_VALUE",
+            WireShadeError::EndpointNotFound(_) => "ENOTFOUND",
+            WireShadeError::NoDnsServer => "ERR_WIRESHADE_NO_DNS",
+            WireShadeError::NoRoute(_) => "EHOSTUNREACH",
+            WireShadeError::HandshakeTimeout => "ERR_WIRESHADE_HANDSHAKE_TIMEOUT",
+            WireShadeError::TunnelClosed => "ERR_WIRESHADE_TUNNEL_CLOSED",
+            WireShadeError::Busy => "EAGAIN",
+            WireShadeError::TunnelFailed(_) => "ERR_WIRESHADE_TUNNEL_FAILED",
+            WireShadeError::ConnectionRefused => "ECONNREFUSED",
+            WireShadeError::ConnectionReset => "ECONNRESET",
+            WireShadeError::Timeout => "ETIMEDOUT",
+            WireShadeError::SocketLimit => "EMFILE",
+            WireShadeError::AddressInUse(_) => "EADDRINUSE",
+            WireShadeError::Io(e) => match e.kind() {
+                std::io::ErrorKind::AddrInUse => "EADDRINUSE",
+                std::io::ErrorKind::AddrNotAvailable => "EADDRNOTAVAIL",
+                std::io::ErrorKind::PermissionDenied => "EACCES",
+                std::io::ErrorKind::NotFound => "ENOENT",
+                _ => "EIO",
+            },
+        }
+    }
+}
+
+/// For the `tokio::io` traits of `WgTcpStream`, keeping the kinds callers branch on.
+impl From<WireShadeError> for std::io::Error {
+    fn from(e: WireShadeError) -> Self {
+        use std::io::ErrorKind;
+        let kind = match e {
+            WireShadeError::Io(e) => return e,
+            WireShadeError::ConnectionRefused => ErrorKind::ConnectionRefused,
+            WireShadeError::ConnectionReset => ErrorKind::ConnectionReset,
+            WireShadeError::Timeout | WireShadeError::HandshakeTimeout => ErrorKind::TimedOut,
+            WireShadeError::NoRoute(_) => ErrorKind::HostUnreachable,
+            WireShadeError::AddressInUse(_) => ErrorKind::AddrInUse,
+            WireShadeError::TunnelClosed | WireShadeError::TunnelFailed(_) => ErrorKind::NotConnected,
+            WireShadeError::InvalidKey { .. } | WireShadeError::InvalidAddress(_) | WireShadeError::InvalidArgument(_) => ErrorKind::InvalidInput,
+            WireShadeError::EndpointNotFound(_) | WireShadeError::NoDnsServer => ErrorKind::NotFound,
+            WireShadeError::Busy => ErrorKind::WouldBlock,
+            WireShadeError::SocketLimit => ErrorKind::Other,
+        };
+        std::io::Error::new(kind, e)
+    }
+}
+
+#[cfg(feature = "napi")]
+impl WireShadeError {
+    /// Builds the coded JS error. Must run on the JS thread.
+    pub fn into_napi(self, env: &Env) -> Error {
+        match unsafe { ToNapiValue::to_napi_value(env.raw(), self) } {
+            Ok(value) => Error::from(unsafe { JsUnknown::from_raw_unchecked(env.raw(), value) }),
+            Err(e) => e,
+        }
+    }
+}
+
+/// Lets an error be handed to JS callbacks as a value, e.g. the reason a connection closed.
+#[cfg(feature = "napi")]
+impl ToNapiValue for WireShadeError {
+    unsafe fn to_napi_value(env: sys::napi_env, val: Self) -> Result<sys::napi_value> {
+        Ok(JsError::from(Error::new(val.code(), val.to_string())).into_value(env))
+    }
+}
+
+/// Runs `fut` on the napi runtime and returns a promise for its output. Used instead of
+/// `#[napi] async fn`, which can only reject with a napi `Status` as the error code.
+#[cfg(feature = "napi")]
+pub(crate) fn promise<T, F>(env: &Env, fut: F) -> Result<JsObject>
+where
+    T: ToNapiValue + Send + 'static,
+    F: Future<Output = std::result::Result<T, WireShadeError>> + Send + 'static,
+{
+    env.execute_tokio_future(async move { Ok(fut.await) }, |env, res| res.map_err(|e| e.into_napi(env)))
+}
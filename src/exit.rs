@@ -0,0 +1,136 @@
+use crate::proxy::Access;
+use crate::stream::{Accepted, STREAM_EVENTS};
+use crate::ConnectionContext;
+use smoltcp::iface::{SocketHandle, SocketSet};
+use smoltcp::socket::{tcp, Socket};
+use smoltcp::wire::{IpEndpoint, IpProtocol, Ipv4Address, Ipv4Packet, TcpPacket};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+/// How long a socket opened for a SYN may take to complete the handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where exit-node connections go and who takes them.
+pub(crate) struct ExitListener {
+    pub access: Access,
+    pub accepted: mpsc::Sender<Accepted>,
+}
+
+struct Handshake {
+    handle: SocketHandle,
+    local: IpEndpoint,
+    remote: IpEndpoint,
+    started: Instant,
+}
+
+/// Catch-all TCP listener for exit-node mode. smoltcp can't listen on every port, so each
+/// SYN to an allowed destination gets a socket listening on exactly that address and port.
+pub(crate) struct Exit {
+    listener: ExitListener,
+    handshakes: Vec<Handshake>,
+}
+
+impl Exit {
+    pub fn new(listener: ExitListener) -> Self {
+        Self { listener, handshakes: Vec::new() }
+    }
+
+    /// Looks at packets from peers before smoltcp does. Drops what exit mode doesn't relay
+    /// (anything but TCP to foreign addresses, so nothing else gets answered on their behalf)
+    /// and opens a socket for each new connection to an allowed destination. SYNs to other
+    /// destinations pass, and smoltcp resets them.
+    pub fn admit(&mut self, packets: &mut VecDeque<Vec<u8>>, sockets: &mut SocketSet<'_>, own_ip: Ipv4Address, max_sockets: usize) {
+        packets.retain(|packet| self.admit_packet(packet, sockets, own_ip, max_sockets));
+    }
+
+    fn admit_packet(&mut self, packet: &[u8], sockets: &mut SocketSet<'_>, own_ip: Ipv4Address, max_sockets: usize) -> bool {
+        // Exit mode relays IPv4 only; IPv6 is for our own address
+        if packet.first().map(|b| b >> 4) != Some(4) {
+            return true;
+        }
+        let Ok(ip) = Ipv4Packet::new_checked(packet) else { return true };
+        if ip.dst_addr() == own_ip {
+            return true;
+        }
+        if ip.next_header() != IpProtocol::Tcp {
+            return false;
+        }
+        let Ok(tcp) = TcpPacket::new_checked(ip.payload()) else { return false };
+        if !tcp.syn() || tcp.ack() {
+            return true;
+        }
+
+        let local = IpEndpoint::new(ip.dst_addr().into(), tcp.dst_port());
+        let remote = IpEndpoint::new(ip.src_addr().into(), tcp.src_port());
+        if !self.listener.access.allows(ip.dst_addr().into(), tcp.dst_port()) {
+            log::debug!(target: "wireshade::tcp", "Exit: {} may not connect to {}", remote, local);
+            return true;
+        }
+        // A retransmitted SYN belongs to the socket the first one opened
+        let known = self.handshakes.iter().any(|h| h.local == local && h.remote == remote)
+            || sockets.iter().any(|(_, socket)| matches!(socket,
+                Socket::Tcp(s) if s.local_endpoint() == Some(local) && s.remote_endpoint() == Some(remote)));
+        if known {
+            return true;
+        }
+        if sockets.iter().count() >= max_sockets {
+            log::warn!(target: "wireshade::tcp", "Socket limit reached, refusing exit connection to {}", local);
+            return true;
+        }
+
+        let rx_buffer = tcp::SocketBuffer::new(vec![0; 65535]);
+        let tx_buffer = tcp::SocketBuffer::new(vec![0; 65535]);
+        let mut socket = tcp::Socket::new(rx_buffer, tx_buffer);
+        if socket.listen(local).is_err() {
+            return true;
+        }
+        let handle = sockets.add(socket);
+        self.handshakes.push(Handshake { handle, local, remote, started: Instant::now() });
+        true
+    }
+
+    /// Hands over connections whose handshake completed and returns them with their ids,
+    /// for the caller to track. Sockets whose handshake failed or stalled are removed.
+    pub fn accept(&mut self, sockets: &mut SocketSet<'_>, next_id: &mut u32) -> Vec<(u32, SocketHandle, ConnectionContext)> {
+        let mut accepted = Vec::new();
+        let now = Instant::now();
+        self.handshakes.retain(|handshake| {
+            let state = sockets.get::<tcp::Socket>(handshake.handle).state();
+            match state {
+                tcp::State::Listen | tcp::State::SynReceived if now - handshake.started < HANDSHAKE_TIMEOUT => return true,
+                tcp::State::Listen | tcp::State::SynReceived | tcp::State::Closed | tcp::State::TimeWait => {
+                    sockets.remove(handshake.handle);
+                    return false;
+                }
+                _ => {}
+            }
+
+            let id = *next_id;
+            let (events_tx, events) = mpsc::channel(STREAM_EVENTS);
+            let exit = Accepted { id, events, local: handshake.local, remote: handshake.remote };
+            if self.listener.accepted.try_send(exit).is_err() {
+                log::debug!(target: "wireshade::tcp", "Exit: nobody accepting, dropping {} -> {}", handshake.remote, handshake.local);
+                sockets.remove(handshake.handle);
+                return false;
+            }
+            *next_id += 1;
+            log::debug!(target: "wireshade::tcp", "Exit: accepted connection {} from {} to {}", id, handshake.remote, handshake.local);
+            accepted.push((id, handshake.handle, ConnectionContext::Stream { events: events_tx, eof_sent: false }));
+            false
+        });
+        accepted
+    }
+
+    /// The `TunnelListener` is gone, nobody will accept from this exit again.
+    pub fn is_abandoned(&self) -> bool {
+        self.listener.accepted.is_closed()
+    }
+
+    /// Removes the sockets of connections still in their handshake.
+    pub fn close(self, sockets: &mut SocketSet<'_>) {
+        for handshake in self.handshakes {
+            sockets.remove(handshake.handle);
+        }
+    }
+}
@@ -0,0 +1,361 @@
+//! Entry points for the cargo-fuzz targets in `fuzz/`. Not part of the API: they exist so
+//! the targets can reach what the tunnel keeps private.
+
+use crate::peer;
+use crate::{decode_key, Access, CallbackTransport, Inbound, PacketFilter, PeerSpec, PingConfig, ReconnectPolicy, StreamReader, StreamWriter, TunnelListener};
+use crate::{Tunnel, TunnelConfig};
+use boringtun::noise::{Tunn, TunnResult};
+use boringtun::x25519::{PublicKey, StaticSecret};
+use smoltcp::phy::ChecksumCapabilities;
+use smoltcp::wire::{Icmpv4Message, Icmpv4Packet, Icmpv4Repr, IpProtocol, Ipv4Address, Ipv4Packet, Ipv4Repr};
+use std::net::{SocketAddr, SocketAddrV4};
+use std::sync::OnceLock;
+use std::time::Duration;
+use tokio::runtime::Runtime;
+use tokio::sync::{mpsc, Mutex, OnceCell};
+use tokio::time::timeout;
+
+const TUNNEL_KEY: [u8; 32] = [1; 32];
+const PEER_KEY: [u8; 32] = [2; 32];
+const TUNNEL_IP: Ipv4Address = Ipv4Address::new(10, 0, 0, 1);
+const PEER_IP: Ipv4Address = Ipv4Address::new(10, 0, 0, 2);
+/// How long one step waits for the tunnel before moving on. Most steps aren't meant to
+/// succeed; the ones that do are done long before, the peers being in-process and past
+/// their handshake.
+const STEP: Duration = Duration::from_millis(5);
+/// Commands run per input, so that no input takes long.
+const MAX_COMMANDS: usize = 32;
+
+/// A `decode_key` that accepts only keys, which `encode_key` gives back unchanged.
+pub fn key(data: &[u8]) {
+    let Ok(text) = std::str::from_utf8(data) else { return };
+    if let Ok(key) = decode_key(text, "key") {
+        assert_eq!(decode_key(&peer::encode_key(&key), "key").unwrap(), key);
+    }
+}
+
+/// A peer as the Node.js constructor takes it, one field per line: public key, preshared
+/// key, endpoint, allowed IPs separated by commas, keepalive. The peer that parses goes
+/// on to the peer table.
+#[cfg(feature = "napi")]
+pub fn config(data: &[u8]) {
+    use crate::bindings::{parse_access, parse_cidr, parse_ip, parse_peer, PeerConfig};
+    use crate::peer::PeerTable;
+
+    let Ok(text) = std::str::from_utf8(data) else { return };
+    let mut fields = text.split('\n').map(str::to_string);
+    let config = PeerConfig {
+        public_key: fields.next().unwrap_or_default(),
+        preshared_key: fields.next(),
+        // Hostnames would go to DNS
+        endpoint: fields.next().filter(|e| e.is_empty() || e.parse::<SocketAddr>().is_ok()),
+        allowed_ips: fields.next().map(|ips| ips.split(',').map(str::to_string).collect()),
+        persistent_keepalive: fields.next().and_then(|keepalive| keepalive.parse().ok()),
+    };
+    let _ = parse_access(config.allowed_ips.clone(), None);
+    for line in text.lines() {
+        let _ = (parse_cidr(line), parse_ip(line));
+    }
+    if let Ok(spec) = parse_peer(config) {
+        let _ = PeerTable::new(TUNNEL_KEY, vec![spec]);
+    }
+}
+
+/// Packets into a tunnel from an authenticated peer that may send anything. `data` is a
+/// series of packets, each after its length in two bytes. They go encrypted from the peer,
+/// or as a bare datagram if the length has its top bit set.
+pub fn inbound(data: &[u8]) {
+    static PEER: OnceCell<Mutex<InboundPeer>> = OnceCell::const_new();
+    runtime().block_on(async {
+        let mut peer = PEER.get_or_init(|| async { Mutex::new(InboundPeer::start().await) }).await.lock().await;
+        let mut rest = data;
+        while let [high, low, tail @ ..] = rest {
+            let header = u16::from_be_bytes([*high, *low]);
+            let (packet, tail) = tail.split_at(((header & 0x7fff) as usize).min(tail.len()));
+            rest = tail;
+            match header & 0x8000 {
+                0 => peer.send(packet),
+                _ => {
+                    peer.inbound.deliver(packet.to_vec(), PEER_ADDR);
+                }
+            }
+        }
+
+        // Its reply means the tunnel got through everything before it
+        peer.seq_no = peer.seq_no.wrapping_add(1);
+        let seq_no = peer.seq_no;
+        peer.send(&echo_request(seq_no));
+        let _ = timeout(STEP * 10, async {
+            while let Some(datagram) = peer.replies.recv().await {
+                if peer.receive(&datagram).is_some_and(|packet| is_echo_reply(&packet, seq_no)) {
+                    return;
+                }
+            }
+        })
+        .await;
+    });
+}
+
+const TUNNEL_ADDR: SocketAddr = SocketAddr::V4(SocketAddrV4::new(std::net::Ipv4Addr::new(192, 0, 2, 1), 51820));
+const PEER_ADDR: SocketAddr = SocketAddr::V4(SocketAddrV4::new(std::net::Ipv4Addr::new(192, 0, 2, 2), 51820));
+
+/// The tunnel `inbound` feeds and the boringtun peer it talks to, set up once: a handshake
+/// per input would cost more than the input itself.
+struct InboundPeer {
+    _tunnel: Tunnel,
+    _listener: TunnelListener,
+    inbound: Inbound,
+    replies: mpsc::UnboundedReceiver<Vec<u8>>,
+    tunn: Tunn,
+    seq_no: u16,
+}
+
+impl InboundPeer {
+    async fn start() -> Self {
+        let (tx, replies) = mpsc::unbounded_channel();
+        let (transport, inbound) = CallbackTransport::new(TUNNEL_ADDR, move |datagram, _| {
+            let _ = tx.send(datagram.to_vec());
+            Ok(())
+        });
+        let tunnel = Tunnel::start(TunnelConfig {
+            transport: Some(Box::new(transport)),
+            ..tunnel_config(TUNNEL_KEY, TUNNEL_IP, PEER_KEY, None, &["0.0.0.0/0", "::/0"])
+        })
+        .unwrap();
+        // Somewhere for TCP to go, and a hook to look at every packet
+        let listener = tunnel.listen(80).await.unwrap();
+        tunnel.on_packet(PacketFilter { port: Some(53), ..PacketFilter::default() }, |_| {}).unwrap();
+
+        let public = PublicKey::from(&StaticSecret::from(TUNNEL_KEY));
+        let tunn = Tunn::new(StaticSecret::from(PEER_KEY), public, None, None, 0, None).unwrap();
+        let mut peer = Self { _tunnel: tunnel, _listener: listener, inbound, replies, tunn, seq_no: 0 };
+        let mut buf = vec![0u8; 148];
+        if let TunnResult::WriteToNetwork(initiation) = peer.tunn.format_handshake_initiation(&mut buf, false) {
+            peer.inbound.deliver(initiation.to_vec(), PEER_ADDR);
+        }
+        let response = timeout(Duration::from_secs(5), peer.replies.recv()).await.expect("handshake response").unwrap();
+        peer.receive(&response);
+        peer
+    }
+
+    /// Encrypts `packet` for the tunnel. After a rekey it waits in boringtun's queue until
+    /// the handshake response arrives.
+    fn send(&mut self, packet: &[u8]) {
+        let mut buf = vec![0u8; packet.len() + 148];
+        if let TunnResult::WriteToNetwork(datagram) = self.tunn.encapsulate(packet, &mut buf) {
+            self.inbound.deliver(datagram.to_vec(), PEER_ADDR);
+        }
+    }
+
+    /// Decrypts a datagram from the tunnel, answering handshakes and sending what was queued.
+    fn receive(&mut self, datagram: &[u8]) -> Option<Vec<u8>> {
+        let mut buf = vec![0u8; 65535];
+        match self.tunn.decapsulate(None, datagram, &mut buf) {
+            TunnResult::WriteToNetwork(reply) => {
+                self.inbound.deliver(reply.to_vec(), PEER_ADDR);
+                while let TunnResult::WriteToNetwork(queued) = self.tunn.decapsulate(None, &[], &mut buf) {
+                    self.inbound.deliver(queued.to_vec(), PEER_ADDR);
+                }
+                None
+            }
+            TunnResult::WriteToTunnelV4(packet, _) => Some(packet.to_vec()),
+            _ => None,
+        }
+    }
+}
+
+/// The tunnel's commands in the order and with the arguments `data` spells out, on two
+/// tunnels connected to each other. The tunnels are shared by every input; what an input
+/// opens is dropped at its end.
+pub fn commands(data: &[u8]) {
+    static TUNNELS: OnceCell<[Tunnel; 2]> = OnceCell::const_new();
+    runtime().block_on(async {
+        let tunnels = TUNNELS.get_or_init(tunnel_pair).await;
+        let mut input = Input(data);
+        let mut streams: Vec<(StreamReader, StreamWriter)> = Vec::new();
+        let mut listeners: Vec<TunnelListener> = Vec::new();
+        let mut hooks = Vec::new();
+
+        for _ in 0..MAX_COMMANDS {
+            if input.0.is_empty() {
+                break;
+            }
+            let command = input.byte();
+            let tunnel = &tunnels[input.byte() as usize % tunnels.len()];
+            match command % 14 {
+                0 => {
+                    let dest = Ipv4Address::new(10, 0, 0, input.byte());
+                    if let Ok(Ok(stream)) = timeout(STEP, tunnel.connect(dest, input.port())).await {
+                        match input.byte() % 4 {
+                            0 => stream.abort(),
+                            _ => streams.push(stream.into_split()),
+                        }
+                    }
+                }
+                1 => {
+                    if let Ok(listener) = tunnel.listen(input.port()).await {
+                        listeners.push(listener);
+                    }
+                }
+                2 => {
+                    if let Ok(listener) = tunnel.listen_any(Access { ips: Vec::new(), ports: Vec::new() }).await {
+                        listeners.push(listener);
+                    }
+                }
+                3 => {
+                    let Some(listener) = pick(&mut listeners, input.byte()) else { continue };
+                    if let Ok(Ok((stream, _, _))) = timeout(STEP, listener.accept()).await {
+                        streams.push(stream.into_split());
+                    }
+                }
+                4 => {
+                    let index = input.byte();
+                    if !listeners.is_empty() {
+                        listeners.swap_remove(index as usize % listeners.len());
+                    }
+                }
+                5 => {
+                    let data = input.bytes();
+                    let Some((_, writer)) = pick(&mut streams, input.byte()) else { continue };
+                    let _ = timeout(STEP, writer.write(data)).await;
+                }
+                6 => {
+                    let Some((reader, _)) = pick(&mut streams, input.byte()) else { continue };
+                    let _ = timeout(STEP, reader.read()).await;
+                }
+                7 => {
+                    let Some((_, writer)) = pick(&mut streams, input.byte()) else { continue };
+                    let _ = timeout(STEP, writer.shutdown()).await;
+                }
+                8 => {
+                    let index = input.byte();
+                    if !streams.is_empty() {
+                        streams.swap_remove(index as usize % streams.len());
+                    }
+                }
+                9 => {
+                    let _ = timeout(STEP, tunnel.send_packet(input.bytes())).await;
+                }
+                10 => {
+                    let dest = Ipv4Address::new(10, 0, 0, input.byte());
+                    let config = PingConfig { count: 1, size: input.byte() as usize * 16, interval: STEP, timeout: STEP };
+                    let _ = timeout(STEP * 2, tunnel.ping(dest, config)).await;
+                }
+                11 => {
+                    let (protocol, port, intercept) = (input.byte(), input.port(), input.byte() % 2 == 1);
+                    let filter = PacketFilter { protocol: Some(protocol).filter(|&p| p != 0), port: Some(port).filter(|&p| p != 0), intercept };
+                    if let Ok(id) = tunnel.on_packet(filter, |_| {}) {
+                        hooks.push((tunnel.clone(), id));
+                    }
+                }
+                12 => {
+                    let index = input.byte();
+                    if !hooks.is_empty() {
+                        let (tunnel, id) = hooks.swap_remove(index as usize % hooks.len());
+                        let _ = tunnel.off_packet(id);
+                    }
+                }
+                _ => {
+                    let name = String::from_utf8_lossy(&input.bytes()).into_owned();
+                    let _ = timeout(STEP, tunnel.resolve(&name)).await;
+                }
+            }
+        }
+    });
+}
+
+/// Reads the fuzzer's bytes as arguments; zeros once they run out.
+struct Input<'a>(&'a [u8]);
+
+impl Input<'_> {
+    fn byte(&mut self) -> u8 {
+        let Some((&byte, rest)) = self.0.split_first() else { return 0 };
+        self.0 = rest;
+        byte
+    }
+
+    fn port(&mut self) -> u16 {
+        u16::from_be_bytes([self.byte(), self.byte()])
+    }
+
+    /// A length byte and up to that many bytes.
+    fn bytes(&mut self) -> Vec<u8> {
+        let len = (self.byte() as usize).min(self.0.len());
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        bytes.to_vec()
+    }
+}
+
+fn pick<T>(items: &mut [T], index: u8) -> Option<&mut T> {
+    let len = items.len();
+    items.get_mut(index as usize % len.max(1))
+}
+
+/// One runtime for every input, which also runs the shared tunnels between inputs.
+fn runtime() -> &'static Runtime {
+    static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+    RUNTIME.get_or_init(|| tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap())
+}
+
+/// Two tunnels connected in process, done with their handshake.
+async fn tunnel_pair() -> [Tunnel; 2] {
+    let (server_transport, client_transport) = CallbackTransport::pair(TUNNEL_ADDR, PEER_ADDR);
+    let tunnels = [
+        Tunnel::start(TunnelConfig {
+            transport: Some(Box::new(server_transport)),
+            ..tunnel_config(TUNNEL_KEY, TUNNEL_IP, PEER_KEY, None, &["10.0.0.2/32"])
+        })
+        .unwrap(),
+        Tunnel::start(TunnelConfig {
+            transport: Some(Box::new(client_transport)),
+            ..tunnel_config(PEER_KEY, PEER_IP, TUNNEL_KEY, Some(TUNNEL_ADDR), &["10.0.0.0/24"])
+        })
+        .unwrap(),
+    ];
+    let config = PingConfig { count: 1, size: 0, interval: Duration::ZERO, timeout: Duration::from_secs(5) };
+    assert_eq!(tunnels[1].ping(TUNNEL_IP, config).await.unwrap().received, 1, "handshake");
+    tunnels
+}
+
+fn tunnel_config(key: [u8; 32], ip: Ipv4Address, peer_key: [u8; 32], endpoint: Option<SocketAddr>, allowed_ips: &[&str]) -> TunnelConfig {
+    let peer = PeerSpec {
+        public_key: PublicKey::from(&StaticSecret::from(peer_key)).to_bytes(),
+        preshared_key: None,
+        endpoint,
+        endpoint_host: None,
+        allowed_ips: allowed_ips.iter().map(|cidr| cidr.parse().unwrap()).collect(),
+        persistent_keepalive: None,
+    };
+    TunnelConfig {
+        private_key: key,
+        source_ip: ip,
+        source_ip6: None,
+        listen_port: None,
+        peers: vec![peer],
+        resolve_interval: None,
+        // The tunnels live for the whole run; a peer that sent garbage shouldn't get them to reconnect
+        reconnect: ReconnectPolicy { enabled: false, ..ReconnectPolicy::default() },
+        dns_servers: Vec::new(),
+        transport: None,
+        inner_conditions: None,
+    }
+}
+
+fn echo_request(seq_no: u16) -> Vec<u8> {
+    let icmp = Icmpv4Repr::EchoRequest { ident: 1, seq_no, data: b"fuzz" };
+    let ip = Ipv4Repr { src_addr: PEER_IP, dst_addr: TUNNEL_IP, next_header: IpProtocol::Icmp, payload_len: icmp.buffer_len(), hop_limit: 64 };
+    let mut packet = vec![0; ip.buffer_len() + icmp.buffer_len()];
+    let mut ipv4 = Ipv4Packet::new_unchecked(&mut packet[..]);
+    ip.emit(&mut ipv4, &ChecksumCapabilities::default());
+    icmp.emit(&mut Icmpv4Packet::new_unchecked(ipv4.payload_mut()), &ChecksumCapabilities::default());
+    packet
+}
+
+/// The reply to `echo_request(seq_no)`, not one left over from an earlier input.
+fn is_echo_reply(packet: &[u8], seq_no: u16) -> bool {
+    let Ok(ip) = Ipv4Packet::new_checked(packet) else { return false };
+    Icmpv4Packet::new_checked(ip.payload()).is_ok_and(|icmp| {
+        ip.next_header() == IpProtocol::Icmp && icmp.msg_type() == Icmpv4Message::EchoReply && icmp.echo_seq_no() == seq_no
+    })
+}
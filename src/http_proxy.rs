@@ -0,0 +1,393 @@
+use crate::error::WireShadeError;
+use crate::proxy::{Access, ProxyAuth, Stats};
+use crate::stream::{relay, TunnelStream};
+use crate::tunnel::Tunnel;
+use base64::{engine::general_purpose, Engine as _};
+use bytes::Bytes;
+use smoltcp::wire::IpAddress;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Largest request line plus headers we accept.
+const MAX_HEAD: usize = 16 * 1024;
+
+/// Headers for the hop between client and proxy, dropped on the way through.
+const HOP_HEADERS: [&str; 4] = ["proxy-authorization", "proxy-connection", "connection", "keep-alive"];
+
+pub struct HttpProxyConfig {
+    pub auth: Option<ProxyAuth>,
+    pub access: Access,
+}
+
+/// Serves one request: a `CONNECT host:port` tunnel, or a plain `http://` request in
+/// absolute form. Each client connection carries a single request; anything the client
+/// sends after a plain request's body is not read.
+pub(crate) async fn handle(mut client: TcpStream, tunnel: Tunnel, config: Arc<HttpProxyConfig>, stats: Arc<Stats>) -> Result<(), WireShadeError> {
+    let (head, body) = match read_head(&mut client).await? {
+        Some(read) => read,
+        None => return respond(&mut client, "400 Bad Request").await,
+    };
+    let Some(request) = Request::parse(&head) else {
+        return respond(&mut client, "400 Bad Request").await;
+    };
+
+    if let Some(auth) = &config.auth {
+        if !request.authorized(auth) {
+            client.write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\nProxy-Authenticate: Basic realm=\"wireshade\"\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").await?;
+            return Ok(());
+        }
+    }
+
+    let Some((host, port, path)) = request.destination() else {
+        return respond(&mut client, "400 Bad Request").await;
+    };
+    // We have to know where the request ends, chunked bodies aren't decoded
+    let Some(length) = request.content_length() else {
+        return respond(&mut client, "411 Length Required").await;
+    };
+
+    let dest = match host.parse::<IpAddress>() {
+        Ok(ip) => ip,
+        Err(_) => match tunnel.resolve(host).await {
+            Ok(ip) => ip.into(),
+            Err(_) => return respond(&mut client, "502 Bad Gateway").await,
+        },
+    };
+    if !config.access.allows(dest, port) {
+        log::debug!("HTTP proxy: {}:{} is not allowed", dest, port);
+        return respond(&mut client, "403 Forbidden").await;
+    }
+
+    let stream = match tunnel.connect_ip(dest, port).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            let status = match e {
+                WireShadeError::Timeout | WireShadeError::HandshakeTimeout => "504 Gateway Timeout",
+                _ => "502 Bad Gateway",
+            };
+            respond(&mut client, status).await?;
+            return Err(e);
+        }
+    };
+    log::debug!(target: "wireshade::tcp", "HTTP proxy {} {}:{}", request.method, dest, port);
+
+    match path {
+        None => {
+            client.write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n").await?;
+            if !body.is_empty() {
+                stats.sent(body.len());
+                stream.write(body).await?;
+            }
+            relay(client, stream, &stats).await?;
+        }
+        Some(path) => forward(client, stream, &request, path, body, length, &stats).await?,
+    }
+    Ok(())
+}
+
+/// Passes on one plain request and its response, then closes both connections. Later
+/// requests on a kept-alive client connection may be for other hosts and would reach
+/// this one unrewritten, so both sides are told `Connection: close`.
+async fn forward(
+    client: TcpStream,
+    stream: TunnelStream,
+    request: &Request<'_>,
+    path: &str,
+    mut body: Vec<u8>,
+    length: usize,
+    stats: &Stats,
+) -> Result<(), WireShadeError> {
+    stream.write(request_head(request, path)).await?;
+    let (mut reader, writer) = stream.into_split();
+    let (mut client_rx, mut client_tx) = client.into_split();
+
+    let upload = async {
+        body.truncate(length);
+        let mut remaining = length - body.len();
+        if !body.is_empty() {
+            stats.sent(body.len());
+            writer.write(body).await?;
+        }
+        let mut chunk = vec![0u8; 16 * 1024];
+        while remaining > 0 {
+            let limit = remaining.min(chunk.len());
+            let n = client_rx.read(&mut chunk[..limit]).await?;
+            if n == 0 {
+                break;
+            }
+            writer.write(Bytes::copy_from_slice(&chunk[..n])).await?;
+            stats.sent(n);
+            remaining -= n;
+        }
+        // Done when the response is; keeping the writer open in the meantime
+        std::future::pending::<Result<(), WireShadeError>>().await
+    };
+    let download = async {
+        let mut buf = Vec::new();
+        // Interim 1xx responses go through as they are, then the final one is rewritten
+        loop {
+            let mut end = head_end(&buf);
+            while end.is_none() && buf.len() <= MAX_HEAD {
+                let Some(data) = reader.read().await? else { break };
+                stats.received(data.len());
+                buf.extend_from_slice(&data);
+                end = head_end(&buf);
+            }
+            let Some((end, (head, interim))) = end.and_then(|end| Some((end, response_head(&buf[..end])?))) else {
+                return respond(&mut client_tx, "502 Bad Gateway").await;
+            };
+            client_tx.write_all(head.as_bytes()).await?;
+            buf.drain(..end);
+            if !interim {
+                break;
+            }
+        }
+        client_tx.write_all(&buf).await?;
+        while let Some(data) = reader.read().await? {
+            stats.received(data.len());
+            client_tx.write_all(&data).await?;
+        }
+        client_tx.shutdown().await?;
+        Ok(())
+    };
+    tokio::select! {
+        result = upload => result,
+        result = download => result,
+    }
+}
+
+/// The request in origin form, without the proxy's own headers. The upstream connection
+/// closes after the response.
+fn request_head(request: &Request<'_>, path: &str) -> String {
+    let mut head = format!("{} {} {}\r\n", request.method, path, request.version);
+    for (name, value) in &request.headers {
+        if !HOP_HEADERS.iter().any(|h| name.eq_ignore_ascii_case(h)) {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+    }
+    head.push_str("Connection: close\r\n\r\n");
+    head
+}
+
+/// The response head for the client, telling it the connection closes after this response.
+/// Returns whether it's an interim 1xx response, which goes through unchanged.
+fn response_head(head: &[u8]) -> Option<(String, bool)> {
+    let head = std::str::from_utf8(head).ok()?;
+    let mut lines = head.split("\r\n").filter(|line| !line.is_empty());
+    let status_line = lines.next()?;
+    let status = status_line.split(' ').nth(1)?;
+    if status.len() != 3 || !status.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    if status.starts_with('1') && status != "101" {
+        return Some((head.to_string(), true));
+    }
+    let mut rewritten = format!("{}\r\n", status_line);
+    for line in lines {
+        let (name, _) = line.split_once(':')?;
+        if !HOP_HEADERS.iter().any(|h| name.trim().eq_ignore_ascii_case(h)) {
+            rewritten.push_str(line);
+            rewritten.push_str("\r\n");
+        }
+    }
+    rewritten.push_str("Connection: close\r\n\r\n");
+    Some((rewritten, false))
+}
+
+/// Length of a head up to and including the blank line, if it's all there.
+fn head_end(buf: &[u8]) -> Option<usize> {
+    buf.windows(4).position(|w| w == b"\r\n\r\n").map(|i| i + 4)
+}
+
+/// Reads up to the blank line after the headers. Returns the head and whatever of the
+/// body already arrived, or `None` if the client sent something too long or hung up.
+async fn read_head<R: AsyncRead + Unpin>(client: &mut R) -> Result<Option<(String, Vec<u8>)>, WireShadeError> {
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 1024];
+    loop {
+        if let Some(end) = head_end(&buf) {
+            let body = buf.split_off(end);
+            return Ok(String::from_utf8(buf).ok().map(|head| (head, body)));
+        }
+        if buf.len() > MAX_HEAD {
+            return Ok(None);
+        }
+        let n = client.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(None);
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+}
+
+async fn respond<W: AsyncWrite + Unpin>(client: &mut W, status: &str) -> Result<(), WireShadeError> {
+    let response = format!("HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status);
+    client.write_all(response.as_bytes()).await?;
+    Ok(())
+}
+
+struct Request<'a> {
+    method: &'a str,
+    target: &'a str,
+    version: &'a str,
+    headers: Vec<(&'a str, &'a str)>,
+}
+
+impl<'a> Request<'a> {
+    fn parse(head: &'a str) -> Option<Self> {
+        let mut lines = head.split("\r\n").filter(|line| !line.is_empty());
+        let mut request_line = lines.next()?.split(' ');
+        let (method, target, version) = (request_line.next()?, request_line.next()?, request_line.next()?);
+        let headers = lines
+            .map(|line| line.split_once(':').map(|(name, value)| (name.trim(), value.trim())))
+            .collect::<Option<_>>()?;
+        Some(Self { method, target, version, headers })
+    }
+
+    /// Host, port and, for a plain request, the path in origin form. `None` for
+    /// origin-form requests, which are for a web server, and for https:// URLs, which go
+    /// through CONNECT.
+    fn destination(&self) -> Option<(&'a str, u16, Option<&'a str>)> {
+        if self.method.eq_ignore_ascii_case("CONNECT") {
+            let (host, port) = split_authority(self.target, 443)?;
+            return Some((host, port, None));
+        }
+        let rest = self.target.strip_prefix("http://")?;
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        let (host, port) = split_authority(authority, 80)?;
+        Some((host, port, Some(path)))
+    }
+
+    fn header(&self, name: &str) -> Option<&'a str> {
+        self.headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, value)| *value)
+    }
+
+    /// Length of the body, `None` if it's chunked or the length doesn't parse.
+    fn content_length(&self) -> Option<usize> {
+        if self.header("transfer-encoding").is_some() {
+            return None;
+        }
+        self.header("content-length").map_or(Some(0), |value| value.parse().ok())
+    }
+
+    fn authorized(&self, auth: &ProxyAuth) -> bool {
+        let expected = general_purpose::STANDARD.encode(format!("{}:{}", auth.username, auth.password));
+        self.headers.iter().any(|(name, value)| {
+            name.eq_ignore_ascii_case("proxy-authorization")
+                && value.split_once(' ').is_some_and(|(scheme, credentials)| {
+                    scheme.eq_ignore_ascii_case("basic") && credentials == expected
+                })
+        })
+    }
+}
+
+/// "host:port" or "host" with `default_port`. IPv6 literals go in brackets: "[fd00::1]:443".
+fn split_authority(authority: &str, default_port: u16) -> Option<(&str, u16)> {
+    if let Some(rest) = authority.strip_prefix('[') {
+        let (host, port) = rest.split_once(']')?;
+        return match port {
+            "" => Some((host, default_port)),
+            port => Some((host, port.strip_prefix(':')?.parse().ok()?)),
+        };
+    }
+    match authority.rsplit_once(':') {
+        // An IPv6 literal without brackets, there's no telling where the port starts
+        Some((host, _)) if host.contains(':') => None,
+        Some((host, port)) => Some((host, port.parse().ok()?)),
+        None if !authority.is_empty() => Some((authority, default_port)),
+        None => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(head: &str) -> Request<'_> {
+        Request::parse(head).unwrap()
+    }
+
+    #[test]
+    fn parses_connect_targets() {
+        let connect = request("CONNECT example.com:8443 HTTP/1.1\r\nHost: example.com\r\n\r\n");
+        assert_eq!(connect.destination(), Some(("example.com", 8443, None)));
+        assert_eq!(request("CONNECT example.com HTTP/1.1\r\n\r\n").destination(), Some(("example.com", 443, None)));
+        assert_eq!(request("CONNECT example.com:x HTTP/1.1\r\n\r\n").destination(), None);
+        assert_eq!(request("CONNECT [fd00::1]:8443 HTTP/1.1\r\n\r\n").destination(), Some(("fd00::1", 8443, None)));
+        assert_eq!(request("CONNECT [fd00::1] HTTP/1.1\r\n\r\n").destination(), Some(("fd00::1", 443, None)));
+        assert_eq!(request("CONNECT fd00::1:443 HTTP/1.1\r\n\r\n").destination(), None);
+    }
+
+    #[test]
+    fn parses_absolute_form_targets() {
+        let get = request("GET http://10.0.0.1:8080/a/b?c HTTP/1.1\r\n\r\n");
+        assert_eq!(get.destination(), Some(("10.0.0.1", 8080, Some("/a/b?c"))));
+        assert_eq!(request("GET http://example.com HTTP/1.0\r\n\r\n").destination(), Some(("example.com", 80, Some("/"))));
+        assert_eq!(request("GET http://[fd00::1]/x HTTP/1.1\r\n\r\n").destination(), Some(("fd00::1", 80, Some("/x"))));
+        // Not for a proxy, or meant to go through CONNECT
+        assert_eq!(request("GET /a HTTP/1.1\r\n\r\n").destination(), None);
+        assert_eq!(request("GET https://example.com/ HTTP/1.1\r\n\r\n").destination(), None);
+    }
+
+    #[test]
+    fn rejects_malformed_heads() {
+        assert!(Request::parse("GET http://example.com/\r\n\r\n").is_none());
+        assert!(Request::parse("GET http://example.com/ HTTP/1.1\r\nno colon\r\n\r\n").is_none());
+        assert!(Request::parse("").is_none());
+    }
+
+    #[test]
+    fn rewrites_the_request_head() {
+        let get = request("GET http://example.com/x HTTP/1.1\r\nHost: example.com\r\nProxy-Authorization: Basic bWU6c2VjcmV0\r\nProxy-Connection: keep-alive\r\nConnection: keep-alive\r\nKeep-Alive: timeout=5\r\nAccept: */*\r\n\r\n");
+        assert_eq!(request_head(&get, "/x"), "GET /x HTTP/1.1\r\nHost: example.com\r\nAccept: */*\r\nConnection: close\r\n\r\n");
+    }
+
+    #[test]
+    fn rewrites_the_response_head() {
+        let (head, interim) = response_head(b"HTTP/1.1 404 Not Found\r\nConnection: keep-alive\r\nKeep-Alive: timeout=5\r\nContent-Length: 3\r\n\r\n").unwrap();
+        assert_eq!(head, "HTTP/1.1 404 Not Found\r\nContent-Length: 3\r\nConnection: close\r\n\r\n");
+        assert!(!interim);
+        let continue_head = "HTTP/1.1 100 Continue\r\n\r\n";
+        assert_eq!(response_head(continue_head.as_bytes()), Some((continue_head.to_string(), true)));
+        assert_eq!(response_head(b"SSH-2.0-OpenSSH\r\n\r\n"), None);
+    }
+
+    #[test]
+    fn finds_the_body_length() {
+        assert_eq!(request("POST http://a/ HTTP/1.1\r\nContent-Length: 12\r\n\r\n").content_length(), Some(12));
+        assert_eq!(request("GET http://a/ HTTP/1.1\r\n\r\n").content_length(), Some(0));
+        assert_eq!(request("POST http://a/ HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n").content_length(), None);
+        assert_eq!(request("POST http://a/ HTTP/1.1\r\nContent-Length: -1\r\n\r\n").content_length(), None);
+    }
+
+    #[test]
+    fn checks_basic_credentials() {
+        let auth = ProxyAuth { username: "me".to_string(), password: "secret".to_string() };
+        assert!(request("GET http://a/ HTTP/1.1\r\nproxy-authorization: basic bWU6c2VjcmV0\r\n\r\n").authorized(&auth));
+        assert!(!request("GET http://a/ HTTP/1.1\r\nProxy-Authorization: Basic bWU6d3Jvbmc=\r\n\r\n").authorized(&auth));
+        assert!(!request("GET http://a/ HTTP/1.1\r\nProxy-Authorization: Bearer bWU6c2VjcmV0\r\n\r\n").authorized(&auth));
+        // Credentials for the origin server don't count
+        assert!(!request("GET http://a/ HTTP/1.1\r\nAuthorization: Basic bWU6c2VjcmV0\r\n\r\n").authorized(&auth));
+    }
+
+    #[tokio::test]
+    async fn reads_the_head_and_keeps_the_body() {
+        let mut input = &b"POST http://a/ HTTP/1.1\r\nContent-Length: 4\r\n\r\nbody"[..];
+        let (head, body) = read_head(&mut input).await.unwrap().unwrap();
+        assert_eq!(head, "POST http://a/ HTTP/1.1\r\nContent-Length: 4\r\n\r\n");
+        assert_eq!(body, b"body");
+    }
+
+    #[tokio::test]
+    async fn truncated_or_oversized_heads_are_not_read() {
+        for input in [&b""[..], b"GET http://a/ HTTP/1.1\r\n", b"GET http://a/ HTTP/1.1\r\nHost: a\r\n\r"] {
+            assert_eq!(read_head(&mut &input[..]).await.unwrap(), None, "{:?}", input);
+        }
+        let endless = vec![b'a'; 2 * MAX_HEAD];
+        assert_eq!(read_head(&mut &endless[..]).await.unwrap(), None);
+    }
+}
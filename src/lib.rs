@@ -1,68 +1,297 @@
 #![deny(clippy::all)]
 
+//! A userspace WireGuard tunnel with its own TCP/IP stack. The engine is plain Rust
+//! ([`Tunnel`]); the Node.js addon is a thin layer on top, behind the `napi` feature.
+
+#[cfg(feature = "napi")]
 #[macro_use]
 extern crate napi_derive;
 extern crate log;
 
-use napi::bindgen_prelude::*;
-use napi::threadsafe_function::{ThreadsafeFunction, ThreadsafeFunctionCallMode};
-use std::collections::HashMap;
-use std::net::ToSocketAddrs;
-use std::str::FromStr;
+#[cfg(feature = "napi")]
+mod bindings;
+mod capture;
+mod error;
+mod exit;
+#[cfg(feature = "fuzzing")]
+#[doc(hidden)]
+pub mod fuzz;
+mod http_proxy;
+#[cfg(feature = "napi")]
+mod logging;
+mod net;
+mod packet;
+mod peer;
+mod ping;
+mod pool;
+mod proxy;
+mod socks5;
+mod stream;
+mod reconnect;
+mod simulator;
+mod supervisor;
+mod transport;
+mod tunnel;
+
+pub use capture::CaptureConfig;
+pub use error::WireShadeError;
+pub use http_proxy::HttpProxyConfig;
+pub use net::{WgTcpListener, WgTcpStream};
+pub use packet::PacketFilter;
+pub use peer::{decode_key, PeerSpec};
+pub use ping::{PingConfig, PingResult};
+pub use proxy::{Access, ProxyAuth, ProxyServer, Target, TrafficStats};
+pub use reconnect::ReconnectPolicy;
+pub use simulator::{NetworkConditions, SimulatedTransport};
+pub use stream::{StreamReader, StreamWriter, TunnelListener, TunnelStream};
+pub use transport::{CallbackTransport, Inbound, Transport, UdpTransport};
+pub use tunnel::{Tunnel, TunnelConfig};
+/// Re-exported for the address types in the API.
+pub use smoltcp::wire;
+
+use bytes::{Buf, Bytes, BytesMut};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::time::Duration;
 use tokio::sync::{mpsc, oneshot};
-use tokio::net::UdpSocket;
-use smoltcp::iface::{Interface, SocketSet, Config, SocketStorage, Route};
-use smoltcp::socket::tcp;
-use smoltcp::wire::{IpAddress, Ipv4Address, IpCidr, IpProtocol, Ipv4Packet};
+use smoltcp::iface::{Interface, SocketSet, Config, SocketStorage};
+use smoltcp::socket::{dns, tcp};
+use smoltcp::wire::DnsQueryType;
+use smoltcp::wire::{IpAddress, IpEndpoint, Ipv4Address};
 use smoltcp::time::Instant;
 use smoltcp::phy::{Device, Medium, RxToken, TxToken};
-use boringtun::noise::{Tunn, TunnResult};
-use base64::{Engine as _, engine::general_purpose};
-use std::io::Write;
+use boringtun::noise::TunnResult;
+use capture::Capture;
+use exit::{Exit, ExitListener};
+use packet::{PacketCallback, PacketHooks};
+use peer::{Lookup, PeerTable};
+use ping::Pings;
+use pool::PacketPool;
+use simulator::PacketPaths;
+use stream::{Accepted, StreamEvent};
+use reconnect::{Action, LinkState};
+use std::sync::Arc;
+use supervisor::TaskState;
 
 // --- Command Enum ---
 enum NetworkCommand {
     Connect {
-        dest_ip: Ipv4Address,
+        dest_ip: IpAddress,
         dest_port: u16,
-        on_data: ThreadsafeFunction<Buffer>,
-        on_close: ThreadsafeFunction<()>,
-        resp: oneshot::Sender<Result<u32>>,
+        context: ConnectionContext,
+        resp: oneshot::Sender<std::result::Result<u32, WireShadeError>>,
     },
     SendData {
         connection_id: u32,
-        data: Vec<u8>,
+        data: Bytes,
+        /// Fired once all of `data` is in the TCP send buffer.
+        done: Option<oneshot::Sender<()>>,
+    },
+    Resolve {
+        name: String,
+        resp: oneshot::Sender<std::result::Result<Ipv4Address, WireShadeError>>,
     },
     Close {
         connection_id: u32,
     },
+    /// Reset a connection right away, dropping whatever is still queued.
+    Abort {
+        connection_id: u32,
+    },
+    SendPacket {
+        packet: Vec<u8>,
+    },
+    Ping {
+        dest_ip: Ipv4Address,
+        config: PingConfig,
+        resp: oneshot::Sender<std::result::Result<PingResult, WireShadeError>>,
+    },
+    OnPacket {
+        id: u32,
+        filter: PacketFilter,
+        callback: PacketCallback,
+    },
+    OffPacket {
+        id: u32,
+    },
+    OnEndpointChange {
+        callback: Box<dyn Fn(EndpointChange) + Send>,
+    },
+    OnStateChange {
+        callback: Box<dyn Fn(StateChange) + Send>,
+    },
+    Rebind {
+        listen_port: Option<u16>,
+        resp: oneshot::Sender<std::result::Result<SocketAddr, WireShadeError>>,
+    },
     Listen {
         port: u16,
-        on_connection: ThreadsafeFunction<(u32, String, u16)>, // Returns (conn_id, remote_ip, remote_port)
-        // We reuse the same on_data/on_close logic, but we need to store these callbacks for the listener
-        // so we can attach them to new server connections.
-        on_data: ThreadsafeFunction<(u32, Buffer)>, // (conn_id, data) - Note we need conn_id here to mux!
-        on_close: ThreadsafeFunction<u32>, // (conn_id)
-        resp: oneshot::Sender<Result<()>>,
+        /// Where accepted connections go, for a `stream::TunnelListener`
+        accepted: mpsc::Sender<Accepted>,
+        /// The local endpoint it listens on
+        resp: oneshot::Sender<std::result::Result<IpEndpoint, WireShadeError>>,
     },
+    Unlisten {
+        port: u16,
+    },
+    /// Exit-node mode: accept TCP to foreign addresses. Responds with our address and port 0.
+    StartExit {
+        listener: ExitListener,
+        resp: oneshot::Sender<std::result::Result<IpEndpoint, WireShadeError>>,
+    },
+    StopExit,
 }
 
-// Struct to store listener callback info
-struct ListenerInfo {
-    port: u16,
-    on_connection: ThreadsafeFunction<(u32, String, u16)>,
-    on_data: ThreadsafeFunction<(u32, Buffer)>,
-    on_close: ThreadsafeFunction<u32>,
+/// Data received on a callback-driven connection, with the connection id.
+pub(crate) type DataCallback = Arc<dyn Fn(u32, Bytes) + Send + Sync>;
+/// A callback-driven connection closed, with the reason if it wasn't orderly.
+pub(crate) type CloseCallback = Arc<dyn Fn(u32, Option<WireShadeError>) + Send + Sync>;
+
+/// Callbacks for tunnel-level events, registered after construction.
+#[derive(Default)]
+struct Events {
+    endpoint_change: Option<Box<dyn Fn(EndpointChange) + Send>>,
+    state_change: Option<Box<dyn Fn(StateChange) + Send>>,
+    packets: PacketHooks,
+}
+
+impl Events {
+    fn state_changed(&self, peer: &peer::Peer) {
+        log::info!("Peer {} is {} (attempt {})", peer::encode_key(&peer.public_key), peer.link.state.as_str(), peer.link.attempt);
+        if let Some(callback) = &self.state_change {
+            callback(StateChange {
+                public_key: peer::encode_key(&peer.public_key),
+                state: peer.link.state.as_str().to_string(),
+                attempt: peer.link.attempt,
+            });
+        }
+    }
+
+    fn endpoint_changed(&self, peer: &peer::Peer, previous: Option<SocketAddr>) {
+        if let (Some(callback), Some(endpoint)) = (&self.endpoint_change, peer.endpoint) {
+            callback(EndpointChange {
+                public_key: peer::encode_key(&peer.public_key),
+                endpoint: endpoint.to_string(),
+                previous: previous.map(|p| p.to_string()),
+            });
+        }
+    }
 }
 
 enum ConnectionContext {
-    Client {
-        on_data: ThreadsafeFunction<Buffer>,
-        on_close: ThreadsafeFunction<()>,
+    /// Driven from JS (`connect` with callbacks) through its `Connection`.
+    #[cfg_attr(not(feature = "napi"), allow(dead_code))]
+    Callbacks {
+        on_data: DataCallback,
+        on_close: CloseCallback,
+    },
+    /// Driven from Rust (proxies, forwards) through a `stream::TunnelStream`.
+    Stream {
+        events: mpsc::Sender<StreamEvent>,
+        eof_sent: bool,
     },
-    Server {
-        on_data: ThreadsafeFunction<(u32, Buffer)>,
-        on_close: ThreadsafeFunction<u32>,
+}
+
+impl ConnectionContext {
+    /// False while a native reader is behind; the data then waits in the TCP window.
+    fn wants_data(&self) -> bool {
+        match self {
+            // Keep a slot free for Eof/Closed
+            ConnectionContext::Stream { events, .. } => events.capacity() > 1,
+            _ => true,
+        }
+    }
+
+    /// The native reader went away before the end of the data, nobody will read from this
+    /// connection again. Past EOF the writer decides when it closes.
+    fn is_abandoned(&self) -> bool {
+        matches!(self, ConnectionContext::Stream { events, eof_sent } if events.is_closed() && !eof_sent)
+    }
+
+    fn data(&self, id: u32, data: Bytes) {
+        match self {
+            ConnectionContext::Callbacks { on_data, .. } => on_data(id, data),
+            ConnectionContext::Stream { events, .. } => {
+                let _ = events.try_send(StreamEvent::Data(data));
+            }
+        }
+    }
+
+    fn connected(&self) {
+        if let ConnectionContext::Stream { events, .. } = self {
+            let _ = events.try_send(StreamEvent::Connected);
+        }
+    }
+
+    /// The remote end finished sending. Only native streams hear about it before the close.
+    fn eof(&mut self) {
+        if let ConnectionContext::Stream { events, eof_sent } = self {
+            if !*eof_sent {
+                *eof_sent = true;
+                let _ = events.try_send(StreamEvent::Eof);
+            }
+        }
+    }
+
+    fn closed(&self, id: u32, reason: Option<WireShadeError>) {
+        match self {
+            ConnectionContext::Callbacks { on_close, .. } => on_close(id, reason),
+            ConnectionContext::Stream { events, .. } => {
+                let _ = events.try_send(StreamEvent::Closed(reason));
+            }
+        }
+    }
+}
+
+/// Data accepted for a connection that didn't fit its TCP send buffer yet.
+struct PendingWrite {
+    data: Bytes,
+    done: Option<oneshot::Sender<()>>,
+}
+
+/// Minimum time between automatic rebinds, so a persistently failing socket doesn't spin.
+const REBIND_BACKOFF: Duration = Duration::from_secs(5);
+/// Wall-clock gap between 5s heartbeats that we take as a suspend/resume.
+const SLEEP_DETECT_GAP: Duration = Duration::from_secs(30);
+/// Size of the smoltcp socket set; listeners and connections share it.
+const MAX_SOCKETS: usize = 32;
+/// How long a connect may stay unanswered before it fails with ETIMEDOUT.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+// --- Outer Socket ---
+
+/// The transport carrying WireGuard datagrams, recorded by the capture when one runs.
+pub(crate) struct OuterSocket {
+    transport: Box<dyn Transport>,
+    local_addr: SocketAddr,
+    capture: Arc<Capture>,
+}
+
+impl OuterSocket {
+    async fn new(transport: Box<dyn Transport>, capture: Arc<Capture>) -> std::io::Result<Self> {
+        // A fresh socket isn't known to be writable yet, and try_send_to would fail the first handshake
+        transport::send_ready(&*transport).await?;
+        let local_addr = transport.local_addr()?;
+        Ok(Self { transport, local_addr, capture })
+    }
+
+    pub fn try_send_to(&self, datagram: &[u8], to: SocketAddr) -> std::io::Result<usize> {
+        self.capture.outer(self.local_addr, to, datagram);
+        self.transport.try_send_to(datagram, to)
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> std::io::Result<(usize, SocketAddr)> {
+        let (len, from) = transport::recv_from(&*self.transport, buf).await?;
+        self.capture.outer(from, self.local_addr, &buf[..len]);
+        Ok((len, from))
+    }
+
+    /// Moves to a fresh socket bound to `port`.
+    async fn rebind(&mut self, port: u16) -> std::io::Result<()> {
+        self.transport.rebind(port)?;
+        transport::send_ready(&*self.transport).await?;
+        self.local_addr = self.transport.local_addr()?;
+        Ok(())
     }
 }
 
@@ -71,26 +300,56 @@ struct VirtualDevice {
     rx_queue: std::collections::VecDeque<Vec<u8>>,
     tx_queue: std::collections::VecDeque<Vec<u8>>,
     mtu: usize,
+    /// Packets pass through here between the queues and smoltcp, if set.
+    simulated: Option<PacketPaths>,
+    /// Where the queued packets' buffers come from and go back to.
+    pool: PacketPool,
 }
 
 impl VirtualDevice {
-    fn new(mtu: usize) -> Self {
+    fn new(mtu: usize, conditions: Option<NetworkConditions>) -> Self {
         Self {
             rx_queue: std::collections::VecDeque::new(),
             tx_queue: std::collections::VecDeque::new(),
             mtu,
+            simulated: conditions.map(PacketPaths::new),
+            pool: PacketPool::default(),
+        }
+    }
+
+    /// The next packet from the peers for smoltcp.
+    fn next_rx(&mut self) -> Option<Vec<u8>> {
+        match &mut self.simulated {
+            Some(paths) => {
+                let now = std::time::Instant::now();
+                paths.inbound.push_all(now, &mut self.rx_queue);
+                paths.inbound.pop_due(now)
+            }
+            None => self.rx_queue.pop_front(),
+        }
+    }
+
+    /// The next packet to encrypt and send.
+    fn next_tx(&mut self) -> Option<Vec<u8>> {
+        match &mut self.simulated {
+            Some(paths) => {
+                let now = std::time::Instant::now();
+                paths.outbound.push_all(now, &mut self.tx_queue);
+                paths.outbound.pop_due(now)
+            }
+            None => self.tx_queue.pop_front(),
         }
     }
 }
 
 impl Device for VirtualDevice {
-    type RxToken<'a> = RxTokenVec;
+    type RxToken<'a> = RxTokenVec<'a>;
     type TxToken<'a> = TxTokenVec<'a>;
 
     fn receive(&mut self, _timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
-        if let Some(buffer) = self.rx_queue.pop_front() {
-            let rx = RxTokenVec { buffer };
-            let tx = TxTokenVec { queue: &mut self.tx_queue };
+        if let Some(buffer) = self.next_rx() {
+            let rx = RxTokenVec { buffer, pool: &self.pool };
+            let tx = TxTokenVec { queue: &mut self.tx_queue, pool: &self.pool };
             Some((rx, tx))
         } else {
             None
@@ -98,41 +357,45 @@ impl Device for VirtualDevice {
     }
 
     fn transmit(&mut self, _timestamp: Instant) -> Option<Self::TxToken<'_>> {
-        Some(TxTokenVec { queue: &mut self.tx_queue })
+        Some(TxTokenVec { queue: &mut self.tx_queue, pool: &self.pool })
     }
 
     fn capabilities(&self) -> smoltcp::phy::DeviceCapabilities {
         let mut caps = smoltcp::phy::DeviceCapabilities::default();
-        caps.medium = Medium::Ip; 
-        caps.max_transmission_unit = self.mtu; 
-        
+        caps.medium = Medium::Ip;
+        caps.max_transmission_unit = self.mtu;
+
         // Revert to Both: This worked for Handshake!
         // It seems the server accepts our packets even without calculated checksums (or 0),
         // but rejects them if we try to calculate them (maybe incorrectly?).
         // Or maybe Checksum::Rx capability logic in smoltcp is different than assumed.
         caps.checksum.ipv4 = smoltcp::phy::Checksum::Both;
         caps.checksum.tcp = smoltcp::phy::Checksum::Both;
-        
+
         caps
     }
 }
 
-struct RxTokenVec {
+struct RxTokenVec<'a> {
     buffer: Vec<u8>,
+    pool: &'a PacketPool,
 }
 
-impl RxToken for RxTokenVec {
+impl<'a> RxToken for RxTokenVec<'a> {
     fn consume<R, F>(self, f: F) -> R
     where
         F: FnOnce(&[u8]) -> R,
     {
         // Simple IP packet passthrough
-        f(&self.buffer)
+        let result = f(&self.buffer);
+        self.pool.put(self.buffer);
+        result
     }
 }
 
 struct TxTokenVec<'a> {
     queue: &'a mut std::collections::VecDeque<Vec<u8>>,
+    pool: &'a PacketPool,
 }
 
 impl<'a> TxToken for TxTokenVec<'a> {
@@ -140,7 +403,7 @@ impl<'a> TxToken for TxTokenVec<'a> {
     where
         F: FnOnce(&mut [u8]) -> R,
     {
-        let mut buffer = vec![0u8; len];
+        let mut buffer = self.pool.zeroed(len);
         let result = f(&mut buffer);
         // Simple IP packet passthrough
         self.queue.push_back(buffer);
@@ -148,694 +411,873 @@ impl<'a> TxToken for TxTokenVec<'a> {
     }
 }
 
-// --- WireShade ---
-
-#[napi]
-pub struct WireShade {
-    cmd_tx: mpsc::Sender<NetworkCommand>,
+/// Passed to `onStateChange` when a peer's tunnel goes up or down.
+#[cfg_attr(feature = "napi", napi(object))]
+pub struct StateChange {
+    pub public_key: String,
+    /// "connecting", "connected", "reconnecting" or "failed"
+    pub state: String,
+    /// Retry number while reconnecting, 0 otherwise.
+    pub attempt: u32,
 }
 
-#[napi]
-impl WireShade {
-    #[napi(constructor)]
-    pub fn new(
-        private_key: String,
-        peer_public_key: String,
-        preshared_key: Option<String>,
-        endpoint: String,
-        source_ip: String,
-    ) -> Result<Self> {
-        let (cmd_tx, mut cmd_rx) = mpsc::channel(32);
-
-        let private_key_bytes = decode_key(&private_key).map_err(|e| Error::from_reason(format!("Invalid private key: {}", e)))?;
-        let peer_key_bytes = decode_key(&peer_public_key).map_err(|e| Error::from_reason(format!("Invalid peer key: {}", e)))?;
-        let psk_bytes = if let Some(psk) = preshared_key {
-            Some(decode_key(&psk).map_err(|e| Error::from_reason(format!("Invalid psk: {}", e)))?)
-        } else {
-            None
-        };
-
-        let source_ip_addr = Ipv4Address::from_str(&source_ip).map_err(|_| Error::from_reason("Invalid source IP"))?;
-        
-        eprintln!("Resolving endpoint: {}", endpoint);
-        let endpoint_addr = endpoint.to_socket_addrs().map_err(|e| Error::from_reason(format!("Invalid endpoint: {}", e)))?
-            .next().ok_or_else(|| Error::from_reason("Endpoint did not resolve"))?;
-        eprintln!("Resolved to: {}", endpoint_addr);
-
-        tokio::spawn(async move {
-            let mut tunn = Tunn::new(
-                private_key_bytes.into(),
-                peer_key_bytes.into(),
-                psk_bytes,
-                None, 
-                0, 
-                None 
-            ).expect("Failed to create Tunn");
-
-            let udp_socket = UdpSocket::bind("0.0.0.0:0").await.expect("Failed to bind UDP");
-            let local_addr = udp_socket.local_addr().expect("Failed to get local addr");
-            udp_socket.connect(endpoint_addr).await.expect("Failed to connect UDP");
-            eprintln!("UDP bound to {} and connected to {}", local_addr, endpoint_addr);
-
-            let mut device = VirtualDevice::new(1420); 
-            
-            let mut socket_set_entries: [SocketStorage; 32] = Default::default();
-            let mut socket_set = SocketSet::new(&mut socket_set_entries[..]);
-            
-            // Configure interface for IP medium - exactly like river
-            let mut config = Config::new(smoltcp::wire::HardwareAddress::Ip);
-            // Randomize seed for ISN generation
-            use std::time::{SystemTime, UNIX_EPOCH};
-            config.random_seed = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_micros() as u64;
-            
-            // Use /32 with .into() exactly like river does
-            let mut iface = Interface::new(config, &mut device, Instant::now());
-            iface.update_ip_addrs(|ip_addrs| {
-                ip_addrs.push(smoltcp::wire::Ipv4Cidr::new(source_ip_addr, 32).into()).ok();
-            });
+/// Passed to `onEndpointChange` whenever a peer's outer address changes, either because
+/// it roamed or because its hostname now resolves elsewhere.
+#[cfg_attr(feature = "napi", napi(object))]
+pub struct EndpointChange {
+    pub public_key: String,
+    pub endpoint: String,
+    pub previous: Option<String>,
+}
 
-            // NO routes - exactly like river
-            eprintln!("Interface configured: {}/32 (exactly like river)", source_ip_addr);
-            let _ = std::io::stderr().flush();
-
-            let mut connections: HashMap<u32, (smoltcp::iface::SocketHandle, ConnectionContext)> = HashMap::new();
-            let mut listeners: HashMap<u16, ListenerInfo> = HashMap::new();
-            let mut listening_sockets: HashMap<u16, smoltcp::iface::SocketHandle> = HashMap::new();
-            // Buffer for pending data when socket can't send yet (e.g., during TCP handshake)
-            let mut pending_data: HashMap<u32, Vec<Vec<u8>>> = HashMap::new();
-            let mut next_conn_id = 1u32;
-            
-            // Randomize start port
-            let start = SystemTime::now();
-            let since_the_epoch = start.duration_since(UNIX_EPOCH).unwrap();
-            let mut next_local_port = ((since_the_epoch.as_millis() % 16383) + 49152) as u16; 
-            eprintln!("[INIT] Starting with ephemeral port: {}", next_local_port);
-
-            let mut buf = [0u8; 65535]; 
-            let mut dst_buf = [0u8; 65535]; 
-            
-            // CRITICAL: Initiate WireGuard handshake IMMEDIATELY
-            eprintln!("[WG] Initiating handshake...");
-            let _ = std::io::stderr().flush();
-            match tunn.format_handshake_initiation(&mut dst_buf, false) {
-                TunnResult::WriteToNetwork(b) => {
-                    let res = udp_socket.send(b).await;
-                    eprintln!("[WG] Handshake initiation sent ({} bytes). Result: {:?}", b.len(), res);
-                }
-                other => {
-                    eprintln!("[WG] Unexpected handshake init result: {:?}", other);
-                }
+/// Sets up the tunnel and starts its loop. Anything that can fail up front (keys, the UDP
+/// port) fails here, synchronously; later failures go to `Tunnel::on_fatal_error`.
+fn spawn(config: TunnelConfig) -> Result<Tunnel, WireShadeError> {
+    let (cmd_tx, mut cmd_rx) = mpsc::channel(32);
+    let source_ip_addr = config.source_ip;
+    let source_ip6 = config.source_ip6;
+    let resolve_interval = config.resolve_interval;
+    let listen_port = config.listen_port.unwrap_or(0);
+    let reconnect_policy = config.reconnect;
+    let dns_servers = config.dns_servers;
+    let inner_conditions = config.inner_conditions;
+
+    let mut peers = PeerTable::new(config.private_key, config.peers)
+        .map_err(|e| WireShadeError::InvalidKey { kind: "key pair", reason: e.to_string() })?;
+    let transport = match config.transport {
+        Some(transport) => transport,
+        None => Box::new(UdpTransport::bind(listen_port)?),
+    };
+
+    let state = Arc::new(TaskState::default());
+    let capture = Arc::new(Capture::default());
+    let task_capture = capture.clone();
+    tokio::spawn(supervisor::supervise(async move {
+        let mut udp_socket = OuterSocket::new(transport, task_capture).await?;
+        log::info!("UDP bound to {}", udp_socket.local_addr);
+
+        let mut device = VirtualDevice::new(1420, inner_conditions);
+
+        let mut socket_set_entries: [SocketStorage; MAX_SOCKETS] = Default::default();
+        let mut socket_set = SocketSet::new(&mut socket_set_entries[..]);
+
+        // Configure interface for IP medium - exactly like river
+        let mut config = Config::new(smoltcp::wire::HardwareAddress::Ip);
+        // Randomize seed for ISN generation
+        use std::time::{SystemTime, UNIX_EPOCH};
+        config.random_seed = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_micros() as u64;
+
+        // Use /32 with .into() exactly like river does
+        let mut iface = Interface::new(config, &mut device, Instant::now());
+        iface.update_ip_addrs(|ip_addrs| {
+            ip_addrs.push(smoltcp::wire::Ipv4Cidr::new(source_ip_addr, 32).into()).ok();
+            if let Some(ip6) = source_ip6 {
+                ip_addrs.push(smoltcp::wire::Ipv6Cidr::new(ip6, 128).into()).ok();
             }
-            let _ = std::io::stderr().flush();
-            
-            // Wait for handshake response and complete the handshake
-            let mut handshake_complete = false;
-            let handshake_timeout = tokio::time::Instant::now() + tokio::time::Duration::from_secs(10);
-            
-            while !handshake_complete && tokio::time::Instant::now() < handshake_timeout {
-                tokio::select! {
-                    res = udp_socket.recv(&mut buf) => {
-                        if let Ok(len) = res {
-                            eprintln!("[WG] Handshake: Received {} bytes", len);
-                            match tunn.decapsulate(None, &buf[..len], &mut dst_buf) {
-                                TunnResult::WriteToNetwork(b) => {
-                                    let res = udp_socket.send(b).await;
-                                    eprintln!("[WG] Handshake: Sent reply ({} bytes). Result: {:?}", b.len(), res);
-                                    
-                                    // After sending, check if more packets need to go out
-                                    loop {
-                                        let mut extra_buf = [0u8; 65535];
-                                        match tunn.decapsulate(None, &[], &mut extra_buf) {
-                                            TunnResult::WriteToNetwork(b2) => {
-                                                let res2 = udp_socket.send(b2).await;
-                                                eprintln!("[WG] Handshake: Follow-up ({} bytes). Result: {:?}", b2.len(), res2);
-                                            }
-                                            TunnResult::Done => {
-                                                eprintln!("[WG] *** HANDSHAKE COMPLETE! ***");
-                                                handshake_complete = true;
-                                                break;
-                                            }
-                                            _ => break,
-                                        }
-                                    }
-                                }
-                                TunnResult::Done => {
-                                    // Check if we can now send data
-                                    eprintln!("[WG] Handshake: Done received, testing if session active...");
-                                    // Try to encapsulate a small packet to see if session is active
-                                    let test_packet = [0u8; 20]; // minimal IP header
-                                    match tunn.encapsulate(&test_packet, &mut dst_buf) {
-                                        TunnResult::WriteToNetwork(_) => {
-                                            eprintln!("[WG] *** SESSION ACTIVE! ***");
-                                            handshake_complete = true;
-                                        }
-                                        _ => {
-                                            eprintln!("[WG] Session not yet active, continuing handshake...");
-                                        }
-                                    }
-                                }
-                                other => {
-                                    eprintln!("[WG] Handshake: Other result: {:?}", other);
-                                }
-                            }
-                        }
+        });
+
+        // NO routes - exactly like river
+        log::debug!("Interface configured: {}/32", source_ip_addr);
+
+        let mut connections: HashMap<u32, (smoltcp::iface::SocketHandle, ConnectionContext)> = HashMap::new();
+        let mut listeners: HashMap<u16, mpsc::Sender<Accepted>> = HashMap::new();
+        // One per port and tunnel address
+        let mut listening_sockets: HashMap<IpEndpoint, smoltcp::iface::SocketHandle> = HashMap::new();
+        // Sockets that left the listening state with a SYN, as (port, handle), until established
+        let mut handshakes: Vec<(u16, smoltcp::iface::SocketHandle)> = Vec::new();
+        let mut exit: Option<Exit> = None;
+        // Buffer for pending data when socket can't send yet (e.g., during TCP handshake)
+        let mut pending_data: HashMap<u32, VecDeque<PendingWrite>> = HashMap::new();
+        // Connections to close once their pending data is sent
+        let mut closing: HashSet<u32> = HashSet::new();
+        // Last TCP state seen per connection and when it was entered, to tell why it closed
+        let mut conn_states: HashMap<u32, (tcp::State, std::time::Instant)> = HashMap::new();
+        let mut next_conn_id = 1u32;
+        let mut pings = Pings::default();
+        // Lookups with the tunnel's DNS servers, on a socket that only exists if there are any
+        let dns_socket = (!dns_servers.is_empty()).then(|| socket_set.add(dns::Socket::new(&dns_servers, vec![])));
+        // (query, name, resp) per lookup in flight
+        let mut dns_queries = Vec::new();
+        let mut events = Events::default();
+        // DNS lookups run off the loop and report back here as (peer index, result)
+        let (resolve_tx, mut resolve_rx) = mpsc::channel::<(usize, std::io::Result<SocketAddr>)>(8);
+
+        // Randomize start port
+        let start = SystemTime::now();
+        let since_the_epoch = start.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let mut next_local_port = ((since_the_epoch.as_millis() % 16383) + 49152) as u16;
+        log::debug!(target: "wireshade::tcp", "Starting with ephemeral port: {}", next_local_port);
+
+        let mut buf = [0u8; 65535];
+        let mut dst_buf = [0u8; 65535];
+
+        // CRITICAL: Initiate WireGuard handshake IMMEDIATELY
+        initiate_handshakes(&mut peers, &udp_socket, &mut dst_buf);
+
+        // Set when the outer socket looks broken (recv errors, the machine slept);
+        // the loop then rebinds on its own, at most once per REBIND_BACKOFF.
+        let mut rebind_needed = false;
+        let mut last_rebind = std::time::Instant::now();
+        let mut last_heartbeat = SystemTime::now();
+
+        let mut heartbeat_timer = tokio::time::interval(tokio::time::Duration::from_secs(5));
+        let mut rate_limit_timer = tokio::time::interval(tokio::time::Duration::from_secs(1));
+        let resolve_period = resolve_interval.unwrap_or(Duration::from_secs(3600));
+        let mut resolve_timer = tokio::time::interval_at(tokio::time::Instant::now() + resolve_period, resolve_period);
+
+        loop {
+            let timestamp = Instant::now();
+            iface.poll(timestamp, &mut device, &mut socket_set);
+
+            tokio::select! {
+                _ = heartbeat_timer.tick() => {
+                    // Monotonic time stands still while the machine is suspended, wall time
+                    // does not: a large gap means we slept and NAT mappings are likely gone.
+                    let now = SystemTime::now();
+                    if now.duration_since(last_heartbeat).unwrap_or_default() > SLEEP_DETECT_GAP {
+                        log::info!("Clock jumped, assuming the machine slept");
+                        rebind_needed = true;
                     }
-                    _ = tokio::time::sleep(tokio::time::Duration::from_millis(100)) => {
-                        // Check timers
-                        match tunn.update_timers(&mut dst_buf) {
-                            TunnResult::WriteToNetwork(b) => {
-                                let res = udp_socket.send(b).await;
-                                eprintln!("[WG] Handshake: Timer packet ({} bytes). Result: {:?}", b.len(), res);
-                            }
-                            _ => {}
+                    last_heartbeat = now;
+                    udp_socket.capture.flush();
+
+                    // FORCE HANDSHAKE by sending a keepalive if no activity: without a session
+                    // boringtun queues it and starts a new handshake instead.
+                    for peer in peers.iter_mut() {
+                        if peer.endpoint.is_none() {
+                            continue;
+                        }
+                        if let TunnResult::WriteToNetwork(b) = peer.tunn.encapsulate(&[], &mut dst_buf) {
+                            peer.send(&udp_socket, b);
                         }
                     }
                 }
-            }
-            
-            if !handshake_complete {
-                eprintln!("[WG] WARNING: Handshake may not be complete after timeout!");
-            }
-
-            let mut heartbeat_timer = tokio::time::interval(tokio::time::Duration::from_secs(5));
-
-            loop {
-                let timestamp = Instant::now();
-                iface.poll(timestamp, &mut device, &mut socket_set);
-
-                tokio::select! {
-                    _ = heartbeat_timer.tick() => {
-                        // FORCE HANDSHAKE by sending a dummy packet if no activity
-                        // We send a dummy UDP packet to 10.245.1.1:9 (Discard)
-                        let mut ip_bytes = vec![0u8; 20 + 8 + 4];
-                        let mut ip_packet = Ipv4Packet::new_unchecked(&mut ip_bytes);
-                        ip_packet.set_version(4);
-                        ip_packet.set_header_len(20);
-                        ip_packet.set_total_len(32);
-                        ip_packet.set_next_header(IpProtocol::Udp);
-                        ip_packet.set_src_addr(source_ip_addr);
-                        ip_packet.set_dst_addr(Ipv4Address::new(10, 245, 1, 1));
-                        ip_packet.set_hop_limit(64);
-                        ip_packet.fill_checksum();
-
-                        match tunn.encapsulate(ip_packet.into_inner(), &mut dst_buf) {
-                            TunnResult::WriteToNetwork(b) => {
-                                let res = udp_socket.send(b).await;
-                                eprintln!("[WG] Periodic heartbeat/handshake trigger sent ({} bytes). UDP Send result: {:?}", b.len(), res);
-                                let _ = std::io::stderr().flush();
+                _ = rate_limit_timer.tick() => {
+                    peers.reset_rate_limiter();
+                }
+                _ = resolve_timer.tick(), if resolve_interval.is_some() => {
+                    for index in 0..peers.len() {
+                        let peer = peers.get_mut(index);
+                        spawn_resolve(peer, index, resolve_tx.clone());
+                    }
+                }
+                Some((index, res)) = resolve_rx.recv() => {
+                    let peer = peers.get_mut(index);
+                    peer.resolving = false;
+                    match res {
+                        Ok(addr) if peer.endpoint != Some(addr) => {
+                            log::info!("Endpoint {} now resolves to {}", peer.endpoint_host.as_deref().unwrap_or_default(), addr);
+                            let previous = peer.endpoint.replace(addr);
+                            events.endpoint_changed(peer, previous);
+                            // The old session lives on the old host; start over at the new one.
+                            if let TunnResult::WriteToNetwork(b) = peer.tunn.format_handshake_initiation(&mut dst_buf, true) {
+                                peer.send(&udp_socket, b);
                             }
-                            _ => {}
                         }
+                        Ok(_) => {}
+                        Err(e) => log::warn!("Re-resolving {} failed: {}", peer.endpoint_host.as_deref().unwrap_or_default(), e),
                     }
-                    cmd_res = cmd_rx.recv() => {
-                        if let Some(cmd) = cmd_res {
-                             match cmd {
-                                NetworkCommand::Connect { dest_ip, dest_port, on_data, on_close, resp } => {
-                                    eprintln!("Command Connect to {}:{}", dest_ip, dest_port);
-
-                                    // Debug Routing
-                                    eprintln!(" -- Routing Info --");
-                                    for c in iface.ip_addrs() {
-                                        eprintln!("  IF IP: {:?}", c);
+                }
+                cmd_res = cmd_rx.recv() => {
+                    if let Some(cmd) = cmd_res {
+                         match cmd {
+                            NetworkCommand::Connect { dest_ip, dest_port, context, resp } => {
+                                log::debug!(target: "wireshade::tcp", "Connecting to {}:{}", dest_ip, dest_port);
+
+                                let local_ip = match dest_ip {
+                                    IpAddress::Ipv4(_) => IpAddress::Ipv4(source_ip_addr),
+                                    IpAddress::Ipv6(_) => match source_ip6 {
+                                        Some(ip6) => IpAddress::Ipv6(ip6),
+                                        None => {
+                                            let no_address = std::io::Error::new(std::io::ErrorKind::AddrNotAvailable, "no IPv6 address in the tunnel");
+                                            let _ = resp.send(Err(no_address.into()));
+                                            continue;
+                                        }
+                                    },
+                                };
+                                match peers.route_mut(dest_ip) {
+                                    None => {
+                                        let _ = resp.send(Err(WireShadeError::NoRoute(dest_ip)));
+                                        continue;
                                     }
-                                    let _ = std::io::stderr().flush();
+                                    Some(peer) if peer.link.state == LinkState::Failed => {
+                                        let _ = resp.send(Err(WireShadeError::HandshakeTimeout));
+                                        continue;
+                                    }
+                                    Some(_) => {}
+                                }
+                                if socket_set.iter().count() >= MAX_SOCKETS {
+                                    let _ = resp.send(Err(WireShadeError::SocketLimit));
+                                    continue;
+                                }
 
-                                    let rx_buffer = tcp::SocketBuffer::new(vec![0; 65535]);
-                                    let tx_buffer = tcp::SocketBuffer::new(vec![0; 65535]);
-                                    let mut socket = tcp::Socket::new(rx_buffer, tx_buffer);
+                                let rx_buffer = tcp::SocketBuffer::new(vec![0; 65535]);
+                                let tx_buffer = tcp::SocketBuffer::new(vec![0; 65535]);
+                                let mut socket = tcp::Socket::new(rx_buffer, tx_buffer);
+                                // Cleared once established; until then smoltcp aborts the SYN retries after this.
+                                socket.set_timeout(Some(CONNECT_TIMEOUT.into()));
 
-                                    eprintln!("[CONNECT] Attempting {}:{}", dest_ip, dest_port);
-                                    let remote_endpoint = (IpAddress::Ipv4(dest_ip), dest_port);
+                                let remote_endpoint = (dest_ip, dest_port);
 
-                                    // Use a real ephemeral port - smoltcp REQUIRES non-zero port!
-                                    let local_port = next_local_port;
-                                    next_local_port = next_local_port.wrapping_add(1);
-                                    if next_local_port < 49152 { next_local_port = 49152; }
+                                // Use a real ephemeral port - smoltcp REQUIRES non-zero port!
+                                let local_port = next_local_port;
+                                next_local_port = next_local_port.wrapping_add(1);
+                                if next_local_port < 49152 { next_local_port = 49152; }
 
-                                    let local_endpoint = (IpAddress::Ipv4(source_ip_addr), local_port);
+                                let local_endpoint = (local_ip, local_port);
 
-                                    eprintln!("[CONNECT] remote={:?}, local={:?}", remote_endpoint, local_endpoint);
-                                    let _ = std::io::stderr().flush();
+                                log::trace!(target: "wireshade::tcp", "remote={:?}, local={:?}", remote_endpoint, local_endpoint);
 
-                                    match socket.connect(iface.context(), remote_endpoint, local_endpoint) {
-                                        Ok(_) => {
-                                            eprintln!("Connect initiated! Socket state: {:?}", socket.state());
+                                match socket.connect(iface.context(), remote_endpoint, local_endpoint) {
+                                    Ok(_) => {
+                                        // Add the connected socket to the socket_set
+                                        let handle = socket_set.add(socket);
 
-                                            // Add the connected socket to the socket_set
-                                            let handle = socket_set.add(socket);
+                                        let id = next_conn_id;
+                                        next_conn_id += 1;
+                                        connections.insert(id, (handle, context));
+                                        conn_states.insert(id, (tcp::State::SynSent, std::time::Instant::now()));
 
-                                            let id = next_conn_id;
-                                            next_conn_id += 1;
-                                            connections.insert(id, (handle, ConnectionContext::Client { on_data, on_close }));
+                                        // CRITICAL: Resolve immediately!
+                                        // JavaScript can start queueing data, and we'll send it when socket is ready
+                                        log::trace!(target: "wireshade::tcp", "Resolving promise immediately for connection {}", id);
+                                        let _ = resp.send(Ok(id));
 
-                                            // CRITICAL: Resolve immediately! 
-                                            // JavaScript can start queueing data, and we'll send it when socket is ready
-                                            eprintln!("[CONNECT] Resolving promise immediately for connection {}", id);
-                                            let _ = resp.send(Ok(id));
+                                        iface.poll(Instant::now(), &mut device, &mut socket_set);
+                                    }
+                                    Err(e) => {
+                                        log::warn!(target: "wireshade::tcp", "Connect to {}:{} failed: {:?}", dest_ip, dest_port, e);
+                                        // Socket is not in socket_set yet, so no need to remove
+                                        let _ = resp.send(Err(WireShadeError::InvalidAddress(format!("{}:{}", dest_ip, dest_port))));
+                                    }
+                                }
+                            },
+                            NetworkCommand::SendData { connection_id, data, done } => {
+                                log::trace!(target: "wireshade::tcp", "Sending {} bytes to connection {}", data.len(), connection_id);
+                                if connections.contains_key(&connection_id) {
+                                    // Queued behind earlier data; flush_pending moves it into the socket in order
+                                    pending_data.entry(connection_id).or_default().push_back(PendingWrite { data, done });
+                                    if flush_pending(&connections, &mut pending_data, &mut closing, &mut socket_set) {
+                                        // CRITICAL: Poll to generate the TCP packet
+                                        iface.poll(Instant::now(), &mut device, &mut socket_set);
 
-                                            iface.poll(Instant::now(), &mut device, &mut socket_set);
-                                        }
-                                        Err(e) => {
-                                            eprintln!("Connect failed: {:?}", e);
-                                            // Socket is not in socket_set yet, so no need to remove
-                                            let _ = resp.send(Err(Error::from_reason(format!("Connect error: {:?}", e))));
-                                        }
+                                        // Send any generated packets through WireGuard
+                                        flush_device(&mut device, &mut peers, &udp_socket, &mut dst_buf);
                                     }
-                                },
-                                NetworkCommand::SendData { connection_id, data } => {
-                                    eprintln!("[SEND] Sending {} bytes to connection {}", data.len(), connection_id);
-                                    if let Some((handle, _)) = connections.get(&connection_id) {
-                                        let socket = socket_set.get_mut::<tcp::Socket>(*handle);
-                                        eprintln!("[SEND] Socket state: {:?}, can_send: {}", socket.state(), socket.can_send());
-                                        if socket.can_send() {
-                                            match socket.send_slice(&data) {
-                                                Ok(sent) => {
-                                                    eprintln!("[SEND] Queued {} bytes in TCP socket", sent);
-                                                }
-                                                Err(e) => {
-                                                    eprintln!("[SEND] Error: {:?}", e);
-                                                }
-                                            }
-                                            // CRITICAL: Poll to generate the TCP packet
-                                            iface.poll(Instant::now(), &mut device, &mut socket_set);
-
-                                            // Send any generated packets through WireGuard
-                                            while let Some(packet) = device.tx_queue.pop_front() {
-                                                match tunn.encapsulate(&packet, &mut dst_buf) {
-                                                    TunnResult::WriteToNetwork(b) => {
-                                                        let _ = udp_socket.try_send(b);
-                                                        eprintln!("[SEND] Sent {} bytes through WireGuard", b.len());
-                                                    }
-                                                    _ => {}
-                                                }
-                                            }
-                                        } else {
-                                            eprintln!("[SEND] Socket cannot send right now - state: {:?}. Buffering data.", socket.state());
-                                            // Buffer the data for later
-                                            pending_data.entry(connection_id).or_default().push(data);
-                                        }
-                                    } else {
-                                        eprintln!("[SEND] Connection {} not found", connection_id);
+                                } else {
+                                    log::debug!(target: "wireshade::tcp", "Connection {} not found", connection_id);
+                                }
+                            },
+                            NetworkCommand::Resolve { name, resp } => {
+                                let Some(handle) = dns_socket else {
+                                    let _ = resp.send(Err(WireShadeError::NoDnsServer));
+                                    continue;
+                                };
+                                let socket = socket_set.get_mut::<dns::Socket>(handle);
+                                match socket.start_query(iface.context(), &name, DnsQueryType::A) {
+                                    Ok(query) => dns_queries.push((query, name, resp)),
+                                    Err(_) => {
+                                        let _ = resp.send(Err(WireShadeError::EndpointNotFound(name)));
                                     }
-                                },
-                                NetworkCommand::Close { connection_id } => {
-                                     if let Some((handle, _)) = connections.get(&connection_id) {
-                                        let socket = socket_set.get_mut::<tcp::Socket>(*handle);
-                                        socket.close();
-                                     }
                                 }
-                                NetworkCommand::Listen { port, on_connection, on_data, on_close, resp } => {
-                                    eprintln!("[LISTEN] Request on port {}", port);
-                                    
-                                    // Store listener info for spawning future sockets
-                                    listeners.insert(port, ListenerInfo { 
-                                        port, 
-                                        on_connection, 
-                                        on_data: on_data, // clone needed? TF is cloneable
-                                        on_close: on_close 
-                                    });
-
-                                    // Create first listening socket
-                                    let rx_buffer = tcp::SocketBuffer::new(vec![0; 65535]);
-                                    let tx_buffer = tcp::SocketBuffer::new(vec![0; 65535]);
-                                    let mut socket = tcp::Socket::new(rx_buffer, tx_buffer);
-
-                                    let local_endpoint = (IpAddress::Ipv4(source_ip_addr), port);
-                                    match socket.listen(local_endpoint) {
-                                        Ok(_) => {
-                                            let handle = socket_set.add(socket);
-                                            listening_sockets.insert(port, handle);
-                                            let _ = resp.send(Ok(()));
-                                            eprintln!("[LISTEN] Socket listening on port {}", port);
-                                        },
-                                        Err(e) => {
-                                            eprintln!("[LISTEN] Failed to listen: {:?}", e);
-                                            let _ = resp.send(Err(Error::from_reason(format!("Listen failed: {:?}", e))));
-                                        }
+                            }
+                            NetworkCommand::Ping { dest_ip, config, resp } => {
+                                match peers.route_mut(IpAddress::Ipv4(dest_ip)) {
+                                    None => {
+                                        let _ = resp.send(Err(WireShadeError::NoRoute(IpAddress::Ipv4(dest_ip))));
+                                        continue;
+                                    }
+                                    Some(peer) if peer.link.state == LinkState::Failed => {
+                                        let _ = resp.send(Err(WireShadeError::HandshakeTimeout));
+                                        continue;
                                     }
+                                    Some(_) => {}
                                 }
-                             }
-                        }
-                    }
-                    res = udp_socket.recv(&mut buf) => {
-                         match res {
-                            Ok(len) => {
-                                 // WireGuard packet types: 1=Initiation, 2=Response, 3=CookieReply, 4=Data
-                                 let pkt_type = if len >= 4 { buf[0] } else { 0 };
-                                 let type_name = match pkt_type {
-                                     1 => "Initiation",
-                                     2 => "Response",
-                                     3 => "CookieReply",
-                                     4 => "Data",
-                                     _ => "Unknown"
-                                 };
-                                 // eprintln!("[WG] Received {} bytes from UDP (type={} {})", len, pkt_type, type_name);
-                                 let _ = std::io::stderr().flush();
-
-                                 // First decapsulate with the received data
-                                 match tunn.decapsulate(None, &buf[..len], &mut dst_buf) {
-                                    TunnResult::WriteToNetwork(b) => {
-                                         let res = udp_socket.send(b).await;
-                                         eprintln!("[WG] Decap triggered reply ({} bytes). Send result: {:?}", b.len(), res);
-                                         let _ = std::io::stderr().flush();
-
-                                         // CRITICAL: After WriteToNetwork, boringtun may have more packets!
-                                         // We need to loop with empty input to drain pending handshake packets.
-                                         loop {
-                                             let mut extra_buf = [0u8; 65535];
-                                             match tunn.decapsulate(None, &[], &mut extra_buf) {
-                                                 TunnResult::WriteToNetwork(b2) => {
-                                                     let res2 = udp_socket.send(b2).await;
-                                                     eprintln!("[WG] Decap follow-up packet ({} bytes). Send result: {:?}", b2.len(), res2);
-                                                 }
-                                                 TunnResult::Done => {
-                                                     eprintln!("[WG] Handshake sequence complete!");
-                                                     break;
-                                                 }
-                                                 _ => break,
-                                             }
-                                         }
+                                if socket_set.iter().count() >= MAX_SOCKETS {
+                                    let _ = resp.send(Err(WireShadeError::SocketLimit));
+                                    continue;
+                                }
+                                log::debug!("Pinging {} ({} probes)", dest_ip, config.count);
+                                pings.start(&mut socket_set, dest_ip, config, resp);
+                            }
+                            NetworkCommand::SendPacket { packet } => {
+                                device.tx_queue.push_back(packet);
+                                flush_device(&mut device, &mut peers, &udp_socket, &mut dst_buf);
+                            }
+                            NetworkCommand::OnPacket { id, filter, callback } => {
+                                events.packets.add(id, filter, callback);
+                            }
+                            NetworkCommand::OffPacket { id } => {
+                                events.packets.remove(id);
+                            }
+                            NetworkCommand::OnEndpointChange { callback } => {
+                                events.endpoint_change = Some(callback);
+                            }
+                            NetworkCommand::OnStateChange { callback } => {
+                                events.state_change = Some(callback);
+                            }
+                            NetworkCommand::Rebind { listen_port: port, resp } => {
+                                let res = rebind(&mut udp_socket, port.unwrap_or(listen_port), &mut peers, &mut dst_buf).await;
+                                last_rebind = std::time::Instant::now();
+                                rebind_needed = false;
+                                for index in 0..peers.len() {
+                                    spawn_resolve(peers.get_mut(index), index, resolve_tx.clone());
+                                }
+                                let _ = resp.send(res.map_err(WireShadeError::from));
+                            }
+                            NetworkCommand::Close { connection_id } => {
+                                 if let Some((handle, _)) = connections.get(&connection_id) {
+                                    // FIN goes out after whatever is still pending
+                                    if pending_data.get(&connection_id).is_some_and(|p| !p.is_empty()) {
+                                        closing.insert(connection_id);
+                                    } else {
+                                        socket_set.get_mut::<tcp::Socket>(*handle).close();
                                     }
-                                    TunnResult::WriteToTunnelV4(b, _) => {
-                                        // Simple manual inspection of TCP flags to debug handshake
-                                        if b.len() > 20 && b[9] == 6 { // IPv4 & TCP
-                                            let ihl = (b[0] & 0x0F) * 4;
-                                            if b.len() >= (ihl as usize + 14) {
-                                                let tcp_flags = b[ihl as usize + 13];
-                                                let is_syn = tcp_flags & 0x02 != 0;
-                                                let is_ack = tcp_flags & 0x10 != 0;
-                                                let is_rst = tcp_flags & 0x04 != 0;
-                                                let is_fin = tcp_flags & 0x01 != 0;
-                                                let is_psh = tcp_flags & 0x08 != 0;
-                                                /*
-                                                eprintln!("[WG] Decapped IPv4 TCP ({} bytes). Flags: [{} {} {} {} {}]",
-                                                    b.len(),
-                                                    if is_syn { "SYN" } else { "-" },
-                                                    if is_ack { "ACK" } else { "-" },
-                                                    if is_rst { "RST" } else { "-" },
-                                                    if is_fin { "FIN" } else { "-" },
-                                                    if is_psh { "PSH" } else { "-" }
-                                                );
-                                                */
-                                            }
-                                        } else {
-                                            // eprintln!("[WG] Decapped IPv4 DATA ({} bytes)", b.len());
-                                        }
-
-                                        device.rx_queue.push_back(b.to_vec());
-
-                                        // CRITICAL: Immediately poll so smoltcp processes the packet
-                                        iface.poll(Instant::now(), &mut device, &mut socket_set);
+                                 }
+                            }
+                            NetworkCommand::Abort { connection_id } => {
+                                if let Some((handle, _)) = connections.get(&connection_id) {
+                                    socket_set.get_mut::<tcp::Socket>(*handle).abort();
+                                    pending_data.remove(&connection_id);
+                                }
+                            }
+                            NetworkCommand::Listen { port, accepted, resp } => {
+                                log::debug!(target: "wireshade::tcp", "Listen request on port {}", port);
+                                if listeners.contains_key(&port) {
+                                    let _ = resp.send(Err(WireShadeError::AddressInUse(port)));
+                                    continue;
+                                }
+                                if socket_set.iter().count() >= MAX_SOCKETS {
+                                    let _ = resp.send(Err(WireShadeError::SocketLimit));
+                                    continue;
+                                }
 
-                                        // Check if any connections can now send buffered data
-                                        for (id, (handle, _)) in connections.iter() {
-                                            let socket = socket_set.get_mut::<tcp::Socket>(*handle);
-                                            // eprintln!("[DEBUG] Socket {} state after poll: {:?}, can_send: {}", id, socket.state(), socket.can_send());
-                                            
-                                            if socket.can_send() {
-                                                if let Some(buffers) = pending_data.get_mut(id) {
-                                                    if !buffers.is_empty() {
-                                                        // eprintln!("[SEND] Flushing {} buffered chunks for connection {}", buffers.len(), id);
-                                                        for data in buffers.drain(..) {
-                                                            match socket.send_slice(&data) {
-                                                                Ok(sent) => {
-                                                                    // eprintln!("[SEND] Flushed {} bytes to TCP socket", sent);
-                                                                }
-                                                                Err(e) => {
-                                                                    eprintln!("[SEND] Flush error: {:?}", e);
-                                                                }
-                                                            }
-                                                        }
-                                                        // Poll again to generate packets
-                                                        iface.poll(Instant::now(), &mut device, &mut socket_set);
-                                                        
-                                                        // Send generated packets through WireGuard
-                                                        while let Some(packet) = device.tx_queue.pop_front() {
-                                                            match tunn.encapsulate(&packet, &mut dst_buf) {
-                                                                TunnResult::WriteToNetwork(b2) => {
-                                                                    let _ = udp_socket.try_send(b2);
-                                                                    // eprintln!("[SEND] Flushed {} bytes through WireGuard", b2.len());
-                                                                }
-                                                                _ => {}
-                                                            }
-                                                        }
-                                                    }
-                                                }
+                                // Create first listening socket
+                                let rx_buffer = tcp::SocketBuffer::new(vec![0; 65535]);
+                                let tx_buffer = tcp::SocketBuffer::new(vec![0; 65535]);
+                                let mut socket = tcp::Socket::new(rx_buffer, tx_buffer);
+
+                                let local_endpoint = IpEndpoint::new(IpAddress::Ipv4(source_ip_addr), port);
+                                match socket.listen(local_endpoint) {
+                                    Ok(_) => {
+                                        let handle = socket_set.add(socket);
+                                        listening_sockets.insert(local_endpoint, handle);
+                                        if let Some(ip6) = source_ip6.filter(|_| socket_set.iter().count() < MAX_SOCKETS) {
+                                            let endpoint = IpEndpoint::new(IpAddress::Ipv6(ip6), port);
+                                            let mut socket = tcp::Socket::new(tcp::SocketBuffer::new(vec![0; 65535]), tcp::SocketBuffer::new(vec![0; 65535]));
+                                            if socket.listen(endpoint).is_ok() {
+                                                listening_sockets.insert(endpoint, socket_set.add(socket));
                                             }
                                         }
+                                        // Accepted connections go here
+                                        listeners.insert(port, accepted);
+                                        let _ = resp.send(Ok(local_endpoint));
+                                        log::info!(target: "wireshade::tcp", "Listening on port {}", port);
+                                    },
+                                    Err(e) => {
+                                        log::warn!(target: "wireshade::tcp", "Listen on port {} failed: {:?}", port, e);
+                                        let _ = resp.send(Err(WireShadeError::InvalidAddress(format!("{}:{}", source_ip_addr, port))));
                                     }
-                                    TunnResult::Done => {
-                                         eprintln!("[WG] Decap: Done (no action needed)");
-                                    }
-                                    TunnResult::Err(e) => {
-                                         eprintln!("[WG] Decap error: {:?}", e);
-                                    }
-                                    _ => {
-                                         eprintln!("[WG] Decap result: Other (WriteToTunnelV6?)");
-                                    }
-                                 }
+                                }
+                            }
+                            NetworkCommand::StartExit { listener, resp } => {
+                                if exit.is_some() {
+                                    let _ = resp.send(Err(WireShadeError::InvalidArgument("exit node, one is already running".to_string())));
+                                    continue;
+                                }
+                                // Accept packets for any address routed "via" our own
+                                iface.set_any_ip(true);
+                                let _ = iface.routes_mut().add_default_ipv4_route(source_ip_addr);
+                                exit = Some(Exit::new(listener));
+                                let _ = resp.send(Ok(IpEndpoint::new(IpAddress::Ipv4(source_ip_addr), 0)));
+                                log::info!(target: "wireshade::tcp", "Exit node enabled");
+                            }
+                            NetworkCommand::StopExit => stop_exit(&mut exit, &mut iface, &mut socket_set),
+                            NetworkCommand::Unlisten { port } => {
+                                unlisten(port, &mut listeners, &mut listening_sockets, &mut handshakes, &mut socket_set);
                             }
-                             Err(e) => {
-                                 eprintln!("UDP Recv Error: {:?}", e);
-                             }
                          }
+                    } else {
+                        // Every handle was dropped: nobody can talk to this tunnel anymore.
+                        log::info!("All handles dropped, stopping tunnel");
+                        return Ok(());
                     }
-                    _ = tokio::time::sleep(tokio::time::Duration::from_millis(10)) => {}
                 }
-                // Process Device Tx -> WireGuard
-                while let Some(packet) = device.tx_queue.pop_front() {
-                     match tunn.encapsulate(&packet, &mut dst_buf) {
-                        TunnResult::WriteToNetwork(b) => {
-                             let res = udp_socket.send(b).await;
-                             // eprintln!("[WG] Encap sent packet ({} bytes). Send result: {:?}", b.len(), res);
+                res = udp_socket.recv_from(&mut buf) => {
+                     match res {
+                        Ok((len, from)) => {
+                            if handle_datagram(&buf[..len], from, &mut peers, &udp_socket, &mut device, &mut dst_buf, &events) {
+                                if let Some(exit) = exit.as_mut() {
+                                    exit.admit(&mut device.rx_queue, &mut socket_set, source_ip_addr, MAX_SOCKETS);
+                                }
+                                // CRITICAL: Immediately poll so smoltcp processes the packet
+                                iface.poll(Instant::now(), &mut device, &mut socket_set);
+
+                                // Check if any connections can now send buffered data
+                                if flush_pending(&connections, &mut pending_data, &mut closing, &mut socket_set) {
+                                    // Poll again to generate packets
+                                    iface.poll(Instant::now(), &mut device, &mut socket_set);
+                                    flush_device(&mut device, &mut peers, &udp_socket, &mut dst_buf);
+                                }
+                            }
                         }
-                        _ => {}
+                         Err(e) => {
+                             log::warn!("UDP receive failed: {}", e);
+                             rebind_needed = true;
+                         }
                      }
                 }
-                
-                match tunn.update_timers(&mut dst_buf) {
-                     TunnResult::WriteToNetwork(b) => {
-                          let res = udp_socket.send(b).await;
-                          eprintln!("[WG] Timer handshake/keepalive ({} bytes). Send result: {:?}", b.len(), res);
-                     }
-                     _ => {}
+                _ = tokio::time::sleep(tokio::time::Duration::from_millis(10)) => {}
+            }
+
+            if rebind_needed && last_rebind.elapsed() >= REBIND_BACKOFF {
+                rebind_needed = false;
+                last_rebind = std::time::Instant::now();
+                if let Err(e) = rebind(&mut udp_socket, listen_port, &mut peers, &mut dst_buf).await {
+                    log::warn!("Automatic rebind failed: {}", e);
+                }
+                for index in 0..peers.len() {
+                    spawn_resolve(peers.get_mut(index), index, resolve_tx.clone());
+                }
+            }
+            // Process Device Tx -> WireGuard
+            flush_device(&mut device, &mut peers, &udp_socket, &mut dst_buf);
+
+            for index in 0..peers.len() {
+                let peer = peers.get_mut(index);
+                if let TunnResult::WriteToNetwork(b) = peer.tunn.update_timers(&mut dst_buf) {
+                    log::trace!("Timer handshake/keepalive ({} bytes)", b.len());
+                    peer.send(&udp_socket, b);
+                }
+                // Handshakes keep going unanswered: the hostname may point somewhere else now.
+                if peer.needs_resolve() {
+                    spawn_resolve(peer, index, resolve_tx.clone());
                 }
 
-                let mut to_remove = Vec::new();
-                for (id, (handle, ctx)) in connections.iter() {
-                     let socket = socket_set.get_mut::<tcp::Socket>(*handle);
-                     if socket.can_recv() {
-                         let recv_len = socket.recv_queue(); // Avoid potential issues with empty queue alloc
-                         if recv_len > 0 {
-                             let mut data = vec![0; recv_len];
-                             if let Ok(len) = socket.recv_slice(&mut data) {
-                                 if len > 0 {
-                                     let buffer = Buffer::from(data[..len].to_vec());
-                                     match ctx {
-                                         ConnectionContext::Client { on_data, .. } => {
-                                             on_data.call(Ok(buffer), ThreadsafeFunctionCallMode::NonBlocking);
-                                         },
-                                         ConnectionContext::Server { on_data, .. } => {
-                                             on_data.call(Ok((*id, buffer)), ThreadsafeFunctionCallMode::NonBlocking);
-                                         }
-                                     }
-                                 }
-                             }
-                        }
+                if !peer.initiator {
+                    continue;
+                }
+                let before = (peer.link.state, peer.link.attempt);
+                let action = peer.link.poll(std::time::Instant::now(), peer.handshake_attempts, &reconnect_policy);
+                if before != (peer.link.state, peer.link.attempt) {
+                    events.state_changed(peer);
+                }
+                if let Action::Retry = action {
+                    if let Err(e) = peers.reset_session(index) {
+                        log::error!("Failed to reset session: {}", e);
+                        continue;
+                    }
+                    let peer = peers.get_mut(index);
+                    if let TunnResult::WriteToNetwork(b) = peer.tunn.format_handshake_initiation(&mut dst_buf, true) {
+                        peer.send(&udp_socket, b);
+                    }
+                    spawn_resolve(peer, index, resolve_tx.clone());
+                    // Nobody answers at all: more likely our side of the network changed.
+                    if !peers.iter_mut().any(|p| p.link.state == LinkState::Connected) {
+                        rebind_needed = true;
+                    }
+                }
+            }
+
+            let mut to_remove = Vec::new();
+            for (id, (handle, ctx)) in connections.iter_mut() {
+                 let socket = socket_set.get_mut::<tcp::Socket>(*handle);
+                 if ctx.is_abandoned() {
+                     // Like closing a socket: reset if received data was left unread, else FIN after what is queued
+                     if socket.recv_queue() > 0 {
+                         socket.abort();
+                     } else if pending_data.get(id).is_some_and(|p| !p.is_empty()) {
+                         closing.insert(*id);
+                     } else {
+                         socket.close();
                      }
-                     if socket.state() == tcp::State::Closed {
-                         match ctx {
-                             ConnectionContext::Client { on_close, .. } => {
-                                 on_close.call(Ok(()), ThreadsafeFunctionCallMode::NonBlocking); 
-                             },
-                             ConnectionContext::Server { on_close, .. } => {
-                                 on_close.call(Ok(*id), ThreadsafeFunctionCallMode::NonBlocking);
-                             }
+                 }
+                 if socket.can_recv() && ctx.wants_data() {
+                     let recv_len = socket.recv_queue(); // Avoid potential issues with empty queue alloc
+                     if recv_len > 0 {
+                         // Straight out of the receive ring, twice if it wraps; handed on without another copy
+                         let mut data = BytesMut::with_capacity(recv_len);
+                         while let Ok(len @ 1..) = socket.recv(|chunk| {
+                             data.extend_from_slice(chunk);
+                             (chunk.len(), chunk.len())
+                         }) {
+                             log::trace!(target: "wireshade::tcp", "Received {} bytes on connection {}", len, id);
+                         }
+                         if !data.is_empty() {
+                             ctx.data(*id, data.freeze());
                          }
-                         to_remove.push(*id);
-                     }
-                }
-                for id in to_remove {
-                    if let Some((handle, _)) = connections.remove(&id) {
-                         socket_set.remove(handle);
-                         pending_data.remove(&id); // Clean up pending data for closed connections
                     }
+                 }
+                 let state = socket.state();
+                 let (previous, since) = conn_states.get(id).copied().unwrap_or((tcp::State::Established, std::time::Instant::now()));
+                 if state != previous {
+                     if previous == tcp::State::SynSent {
+                         socket.set_timeout(None);
+                         if state == tcp::State::Established {
+                             ctx.connected();
+                         }
+                     }
+                     conn_states.insert(*id, (state, std::time::Instant::now()));
+                 }
+                 // The remote end sent FIN and everything before it has been read
+                 if matches!(state, tcp::State::CloseWait | tcp::State::LastAck | tcp::State::Closing | tcp::State::TimeWait)
+                     && socket.recv_queue() == 0 {
+                     ctx.eof();
+                 }
+                 if state == tcp::State::Closed {
+                     ctx.closed(*id, close_reason(previous, since));
+                     to_remove.push(*id);
+                 }
+            }
+            if !to_remove.is_empty() {
+                // Aborted sockets send their RST when polled, so not before they go
+                iface.poll(Instant::now(), &mut device, &mut socket_set);
+            }
+            for id in to_remove {
+                if let Some((handle, _)) = connections.remove(&id) {
+                     socket_set.remove(handle);
+                     pending_data.remove(&id); // Clean up pending data for closed connections
+                     closing.remove(&id);
+                     conn_states.remove(&id);
                 }
+            }
 
-                // Check if any connections can now send pending buffered data
-                for (id, (handle, _)) in connections.iter() {
-                    let socket = socket_set.get_mut::<tcp::Socket>(*handle);
-                    if socket.can_send() {
-                        if let Some(buffers) = pending_data.get_mut(id) {
-                            if !buffers.is_empty() {
-                                eprintln!("[FLUSH] Flushing {} buffered chunks for connection {}", buffers.len(), id);
-                                for data in buffers.drain(..) {
-                                    match socket.send_slice(&data) {
-                                        Ok(sent) => {
-                                            eprintln!("[FLUSH] Sent {} bytes to TCP socket", sent);
-                                        }
-                                        Err(e) => {
-                                            eprintln!("[FLUSH] Error: {:?}", e);
-                                        }
-                                    }
-                                }
-                            }
+            // Check if any connections can now send pending buffered data
+            flush_pending(&connections, &mut pending_data, &mut closing, &mut socket_set);
+
+            if let Some(handle) = dns_socket {
+                let socket = socket_set.get_mut::<dns::Socket>(handle);
+                let mut i = 0;
+                while i < dns_queries.len() {
+                    let result = match socket.get_query_result(dns_queries[i].0) {
+                        Err(dns::GetQueryResultError::Pending) => {
+                            i += 1;
+                            continue;
                         }
+                        Ok(addrs) => addrs.iter().find_map(|addr| match addr {
+                            IpAddress::Ipv4(v4) => Some(*v4),
+                            _ => None,
+                        }),
+                        Err(dns::GetQueryResultError::Failed) => None,
+                    };
+                    let (_, name, resp) = dns_queries.swap_remove(i);
+                    log::debug!("{} resolved to {:?} in the tunnel", name, result);
+                    let _ = resp.send(result.ok_or(WireShadeError::EndpointNotFound(name)));
+                }
+            }
+
+            // A listener dropped while the command queue was full couldn't say so
+            let abandoned: Vec<u16> = listeners.iter()
+                .filter(|(_, accepted)| accepted.is_closed())
+                .map(|(&port, _)| port)
+                .collect();
+            for port in abandoned {
+                unlisten(port, &mut listeners, &mut listening_sockets, &mut handshakes, &mut socket_set);
+            }
+            if exit.as_ref().is_some_and(Exit::is_abandoned) {
+                stop_exit(&mut exit, &mut iface, &mut socket_set);
+            }
+
+            // --- Server: Check for incoming connections ---
+            // A listening socket that got a SYN finishes the handshake on its own, while a
+            // fresh one takes the next SYN: clients connecting at once aren't refused.
+            let taken: Vec<IpEndpoint> = listening_sockets.iter()
+                .filter(|(_, &handle)| socket_set.get::<tcp::Socket>(handle).state() != tcp::State::Listen)
+                .map(|(&endpoint, _)| endpoint)
+                .collect();
+            for endpoint in taken {
+                if let Some(handle) = listening_sockets.remove(&endpoint) {
+                    handshakes.push((endpoint.port, handle));
+                }
+                if socket_set.iter().count() >= MAX_SOCKETS {
+                    log::warn!(target: "wireshade::tcp", "Socket limit reached, no longer accepting on {}", endpoint);
+                    continue;
+                }
+                let rx_buffer = tcp::SocketBuffer::new(vec![0; 65535]);
+                let tx_buffer = tcp::SocketBuffer::new(vec![0; 65535]);
+                let mut socket = tcp::Socket::new(rx_buffer, tx_buffer);
+                if socket.listen(endpoint).is_ok() {
+                    listening_sockets.insert(endpoint, socket_set.add(socket));
+                }
+            }
+
+            let mut new_connections = Vec::new();
+            let mut failed = Vec::new();
+            handshakes.retain(|&(port, handle)| {
+                let socket = socket_set.get::<tcp::Socket>(handle);
+                match (socket.state(), socket.remote_endpoint()) {
+                    (tcp::State::SynReceived, _) => return true,
+                    (tcp::State::Established, Some(remote)) => new_connections.push((port, handle, socket.local_endpoint(), remote)),
+                    // Reset or timed out during the handshake
+                    _ => failed.push(handle),
+                }
+                false
+            });
+            for handle in failed {
+                socket_set.remove(handle);
+            }
+
+            for (port, handle, local, remote) in new_connections {
+                let Some(accepted) = listeners.get(&port) else {
+                    socket_set.remove(handle);
+                    continue;
+                };
+                let id = next_conn_id;
+                next_conn_id += 1;
+
+                let local = local.unwrap_or(IpEndpoint::new(IpAddress::Ipv4(source_ip_addr), port));
+                let (events_tx, events) = mpsc::channel(stream::STREAM_EVENTS);
+                match accepted.try_send(Accepted { id, events, local, remote }) {
+                    Ok(()) => {
+                        connections.insert(id, (handle, ConnectionContext::Stream { events: events_tx, eof_sent: false }));
+                        log::debug!(target: "wireshade::tcp", "Accepted connection {} from {}", id, remote);
+                    }
+                    Err(_) => {
+                        log::debug!(target: "wireshade::tcp", "Nobody accepting on port {}, dropping connection from {}", port, remote);
+                        socket_set.remove(handle);
                     }
                 }
-                                        // --- Server: Check for incoming connections ---
-                                        let mut new_connections = Vec::new(); 
-                                        for (&port, &handle) in listening_sockets.iter() {
-                                            let socket = socket_set.get::<tcp::Socket>(handle);
-                                            // Actively established means we accepted a handshake
-                                            if socket.state() == tcp::State::Established {
-                                                if let Some(remote) = socket.remote_endpoint() {
-                                                    new_connections.push((port, handle, remote));
-                                                }
-                                            }
-                                        }
+            }
 
-                                        for (port, handle, remote) in new_connections {
-                                            if let Some(info) = listeners.get(&port) {
-                                                let id = next_conn_id;
-                                                next_conn_id += 1;
-                                                
-                                                let remote_ip = match remote.addr { IpAddress::Ipv4(ip) => ip.to_string(), _ => "unknown".to_string() };
-                                                let remote_port = remote.port;
-                                                
-                                                connections.insert(id, (handle, ConnectionContext::Server {
-                                                    on_data: info.on_data.clone(),
-                                                    on_close: info.on_close.clone()
-                                                }));
-
-                                                info.on_connection.call(Ok((id, remote_ip.clone(), remote_port)), ThreadsafeFunctionCallMode::NonBlocking);
-                                                
-                                                eprintln!("[SERVER] Accepted conn {} from {}:{}", id, remote_ip, remote_port);
-
-                                                // Create Replacement Listener Socket for this port
-                                                let rx_buffer = tcp::SocketBuffer::new(vec![0; 65535]);
-                                                let tx_buffer = tcp::SocketBuffer::new(vec![0; 65535]);
-                                                let mut socket = tcp::Socket::new(rx_buffer, tx_buffer);
-
-                                                let local_endpoint = (IpAddress::Ipv4(source_ip_addr), port);
-                                                if let Ok(_) = socket.listen(local_endpoint) {
-                                                    let new_handle = socket_set.add(socket);
-                                                    listening_sockets.insert(port, new_handle); // Replace occupied handle
-                                                }
-                                            }
-                                        }
+            if let Some(exit) = exit.as_mut() {
+                for (id, handle, context) in exit.accept(&mut socket_set, &mut next_conn_id) {
+                    connections.insert(id, (handle, context));
+                }
+            }
 
-                                // Poll to generate packets
-                                iface.poll(Instant::now(), &mut device, &mut socket_set);
-                                
-                                // Send packets through WireGuard
-                                while let Some(packet) = device.tx_queue.pop_front() {
-                                    match tunn.encapsulate(&packet, &mut dst_buf) {
-                                        TunnResult::WriteToNetwork(b) => {
-                                            let _ = udp_socket.try_send(b);
-                                            eprintln!("[FLUSH] Sent {} bytes through WireGuard", b.len());
-                                        }
-                                        _ => {}
-                                    }
-                                }
-            } // end loop
-        }); // end spawn
+            pings.poll(&mut socket_set);
+
+            // Poll to generate packets
+            iface.poll(Instant::now(), &mut device, &mut socket_set);
+
+            // Send packets through WireGuard
+            flush_device(&mut device, &mut peers, &udp_socket, &mut dst_buf);
+        } // end loop
+    }, state.clone())); // end spawn
+
+    Ok(Tunnel::new(cmd_tx, state, capture))
+}
 
-        Ok(Self { cmd_tx })
+/// Routes one outer datagram to its peer and decrypts it. Returns true if an inner
+/// packet was queued on the device and smoltcp needs to be polled.
+fn handle_datagram(
+    datagram: &[u8],
+    from: SocketAddr,
+    peers: &mut PeerTable,
+    udp_socket: &OuterSocket,
+    device: &mut VirtualDevice,
+    dst_buf: &mut [u8],
+    events: &Events,
+) -> bool {
+    let index = match peers.lookup(datagram, from, dst_buf) {
+        Lookup::Peer(index) => index,
+        Lookup::Reply(cookie) => {
+            let _ = udp_socket.try_send_to(cookie, from);
+            return false;
+        }
+        Lookup::Unknown => return false,
+    };
+
+    let peer = peers.get_mut(index);
+    let state = peer.link.state;
+    let result = peer.tunn.decapsulate(Some(from.ip()), datagram, dst_buf);
+    if let Some(previous) = peer::learn_endpoint(peer, from, datagram, &result) {
+        events.endpoint_changed(peer, previous);
+    }
+    if peer.initiator && peer.link.state != state {
+        events.state_changed(peer);
     }
 
-    #[napi]
-    pub async fn connect(&self, dest_ip: String, dest_port: u16, on_data: ThreadsafeFunction<Buffer>, on_close: ThreadsafeFunction<()>) -> Result<Connection> {
-        let dest_ip_addr = Ipv4Address::from_str(&dest_ip).map_err(|_| Error::from_reason("Invalid dest IP"))?;
-        
-        let (tx, rx) = oneshot::channel();
-        self.cmd_tx.send(NetworkCommand::Connect { 
-            dest_ip: dest_ip_addr, 
-            dest_port, 
-            on_data,
-            on_close,
-            resp: tx 
-        }).await.map_err(|_| Error::from_reason("Failed to send command"))?;
-
-        let id = match rx.await {
-            Ok(res) => res?,
-            Err(_) => return Err(Error::from_reason("Connection Task Failed")),
-        };
-        
-        Ok(Connection { id, cmd_tx: self.cmd_tx.clone() })
+    match result {
+        TunnResult::WriteToNetwork(b) => {
+            peer.send(udp_socket, b);
+            // CRITICAL: After WriteToNetwork, boringtun may have more packets!
+            // We need to loop with empty input to drain pending handshake packets.
+            peer.drain(udp_socket, dst_buf);
+            false
+        }
+        TunnResult::WriteToTunnelV4(b, src) => {
+            if !peer.allows(src.into()) {
+                log::debug!("Dropping packet from {} not in peer's allowed IPs", src);
+                return false;
+            }
+            udp_socket.capture.inner(b);
+            if events.packets.dispatch(b) {
+                return false;
+            }
+            device.rx_queue.push_back(device.pool.copy(b));
+            true
+        }
+        TunnResult::Done => false,
+        TunnResult::Err(e) => {
+            log::debug!("Decapsulate error from {}: {:?}", from, e);
+            false
+        }
+        TunnResult::WriteToTunnelV6(b, src) => {
+            if !peer.allows(src.into()) {
+                log::debug!("Dropping packet from {} not in peer's allowed IPs", src);
+                return false;
+            }
+            udp_socket.capture.inner(b);
+            if events.packets.dispatch(b) {
+                return false;
+            }
+            device.rx_queue.push_back(device.pool.copy(b));
+            true
+        }
     }
+}
 
-    #[napi]
-    pub async fn listen(&self, port: u16, on_connection: ThreadsafeFunction<(u32, String, u16)>, on_data: ThreadsafeFunction<(u32, Buffer)>, on_close: ThreadsafeFunction<u32>) -> Result<()> {
-        let (tx, rx) = oneshot::channel();
-        self.cmd_tx.send(NetworkCommand::Listen {
-            port,
-            on_connection,
-            on_data,
-            on_close,
-            resp: tx,
-        }).await.map_err(|_| Error::from_reason("Failed to send Listen command"))?;
-
-        match rx.await {
-            Ok(res) => res,
-            Err(_) => Err(Error::from_reason("Listen Task Failed")),
+/// Sends a handshake initiation to every peer we know how to reach. Peers without an
+/// endpoint initiate towards us; the main loop answers them.
+fn initiate_handshakes(peers: &mut PeerTable, udp_socket: &OuterSocket, dst_buf: &mut [u8]) {
+    for peer in peers.iter_mut() {
+        if peer.endpoint.is_none() {
+            continue;
+        }
+        log::debug!("Initiating handshake with {}", peer::encode_key(&peer.public_key));
+        if let TunnResult::WriteToNetwork(b) = peer.tunn.format_handshake_initiation(dst_buf, false) {
+            peer.send(udp_socket, b);
         }
     }
+}
 
-    /// Send data to a connection by ID (works for both client and server connections)
-    #[napi]
-    pub async fn send_to(&self, connection_id: u32, data: Buffer) -> Result<()> {
-        let vec_data: Vec<u8> = data.into();
-        self.cmd_tx.send(NetworkCommand::SendData {
-            connection_id,
-            data: vec_data
-        }).await.map_err(|_| Error::from_reason("Failed to send data"))?;
-        Ok(())
+/// Swaps the outer socket for a freshly bound one and starts new sessions over it.
+async fn rebind(udp_socket: &mut OuterSocket, port: u16, peers: &mut PeerTable, dst_buf: &mut [u8]) -> std::io::Result<SocketAddr> {
+    udp_socket.rebind(port).await?;
+    let local_addr = udp_socket.local_addr;
+    log::info!("Rebound UDP to {}", local_addr);
+
+    peers.reset_sessions().map_err(std::io::Error::other)?;
+    initiate_handshakes(peers, udp_socket, dst_buf);
+    Ok(local_addr)
+}
+
+/// Looks up the peer's endpoint hostname in the background; the result comes back
+/// through `tx` and is applied by the loop.
+fn spawn_resolve(peer: &mut peer::Peer, index: usize, tx: mpsc::Sender<(usize, std::io::Result<SocketAddr>)>) {
+    if peer.resolving {
+        return;
     }
+    let Some(host) = peer.endpoint_host.clone() else { return };
+    peer.resolving = true;
+    peer.last_resolve = Some(std::time::Instant::now());
+    tokio::spawn(async move {
+        let res = match tokio::net::lookup_host(&host).await {
+            Ok(mut addrs) => addrs.next().ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "Endpoint did not resolve")),
+            Err(e) => Err(e),
+        };
+        let _ = tx.send((index, res)).await;
+    });
+}
 
-    /// Close a connection by ID (works for both client and server connections)
-    #[napi]
-    pub async fn close_connection(&self, connection_id: u32) -> Result<()> {
-        self.cmd_tx.send(NetworkCommand::Close {
-            connection_id
-        }).await.map_err(|_| Error::from_reason("Failed to close connection"))?;
-        Ok(())
+/// Encrypts everything smoltcp (or `sendPacket`) queued on the device and sends it to the owning peer.
+fn flush_device(device: &mut VirtualDevice, peers: &mut PeerTable, udp_socket: &OuterSocket, dst_buf: &mut [u8]) {
+    while let Some(packet) = device.next_tx() {
+        udp_socket.capture.inner(&packet);
+        let peer = packet::destination(&packet).and_then(|dst| {
+            let peer = peers.route_mut(dst);
+            if peer.is_none() {
+                log::debug!("No peer for {}", dst);
+            }
+            peer
+        });
+        if let Some(peer) = peer {
+            if let TunnResult::WriteToNetwork(b) = peer.tunn.encapsulate(&packet, dst_buf) {
+                peer.send(udp_socket, b);
+                if packet::expects_answer(&packet) {
+                    peer.link.on_data_sent(std::time::Instant::now());
+                }
+            }
+        }
+        device.pool.put(packet);
     }
 }
 
-#[napi]
-pub struct Connection {
-    id: u32,
-    cmd_tx: mpsc::Sender<NetworkCommand>,
+/// Why a connection that just reached `Closed` from `previous` (entered at `since`) ended.
+/// `None` for an orderly close.
+fn close_reason(previous: tcp::State, since: std::time::Instant) -> Option<WireShadeError> {
+    match previous {
+        // smoltcp gives up on the SYN after CONNECT_TIMEOUT, anything earlier was a RST
+        tcp::State::SynSent if since.elapsed() >= CONNECT_TIMEOUT => Some(WireShadeError::Timeout),
+        tcp::State::SynSent => Some(WireShadeError::ConnectionRefused),
+        tcp::State::LastAck | tcp::State::TimeWait | tcp::State::Closing | tcp::State::Closed => None,
+        _ => Some(WireShadeError::ConnectionReset),
+    }
 }
 
-#[napi]
-impl Connection {
-    #[napi]
-    pub async fn send(&self, data: Buffer) -> Result<()> {
-        let vec_data: Vec<u8> = data.into();
-        self.cmd_tx.send(NetworkCommand::SendData {
-            connection_id: self.id,
-            data: vec_data
-        }).await.map_err(|_| Error::from_reason("Failed to send data"))?;
-        Ok(())
+/// Stops listening on `port`. Accepted connections stay open.
+fn unlisten(
+    port: u16,
+    listeners: &mut HashMap<u16, mpsc::Sender<Accepted>>,
+    listening_sockets: &mut HashMap<IpEndpoint, smoltcp::iface::SocketHandle>,
+    handshakes: &mut Vec<(u16, smoltcp::iface::SocketHandle)>,
+    socket_set: &mut SocketSet,
+) {
+    if listeners.remove(&port).is_some() {
+        log::info!(target: "wireshade::tcp", "Stopped listening on port {}", port);
     }
+    listening_sockets.retain(|endpoint, &mut handle| {
+        if endpoint.port == port {
+            socket_set.remove(handle);
+        }
+        endpoint.port != port
+    });
+    handshakes.retain(|&(p, handle)| {
+        if p == port {
+            socket_set.remove(handle);
+        }
+        p != port
+    });
+}
 
-    #[napi]
-    pub async fn close(&self) -> Result<()> {
-        self.cmd_tx.send(NetworkCommand::Close {
-            connection_id: self.id
-        }).await.map_err(|_| Error::from_reason("Failed to send close"))?;
-        Ok(())
+/// Leaves exit-node mode if it is on. Accepted connections stay open.
+fn stop_exit(exit: &mut Option<Exit>, iface: &mut Interface, socket_set: &mut SocketSet) {
+    if let Some(stopped) = exit.take() {
+        stopped.close(socket_set);
+        iface.set_any_ip(false);
+        iface.routes_mut().remove_default_ipv4_route();
+        log::info!(target: "wireshade::tcp", "Exit node disabled");
     }
 }
 
-fn decode_key(key: &str) -> std::result::Result<[u8; 32], String> {
-    let bytes = general_purpose::STANDARD.decode(key).map_err(|e| e.to_string())?;
-    if bytes.len() != 32 {
-        return Err("Key must be 32 bytes".to_string());
+/// Moves data that arrived before a connection could send into its TCP socket.
+/// Returns true if anything was written.
+fn flush_pending(
+    connections: &HashMap<u32, (smoltcp::iface::SocketHandle, ConnectionContext)>,
+    pending_data: &mut HashMap<u32, VecDeque<PendingWrite>>,
+    closing: &mut HashSet<u32>,
+    socket_set: &mut SocketSet,
+) -> bool {
+    let mut flushed = false;
+    for (id, (handle, _)) in connections.iter() {
+        let socket = socket_set.get_mut::<tcp::Socket>(*handle);
+        let Some(queue) = pending_data.get_mut(id) else { continue };
+        while socket.can_send() {
+            let Some(write) = queue.front_mut() else { break };
+            match socket.send_slice(&write.data) {
+                Ok(sent) => {
+                    log::trace!(target: "wireshade::tcp", "Queued {} bytes for connection {}", sent, id);
+                    flushed |= sent > 0;
+                    write.data.advance(sent);
+                }
+                Err(e) => {
+                    log::warn!(target: "wireshade::tcp", "Send on connection {} failed: {:?}", id, e);
+                    break;
+                }
+            }
+            if !write.data.is_empty() {
+                // Send buffer is full, the rest waits for ACKs
+                break;
+            }
+            if let Some(done) = queue.pop_front().and_then(|write| write.done) {
+                let _ = done.send(());
+            }
+        }
+        if queue.is_empty() && closing.remove(id) {
+            socket.close();
+            flushed = true;
+        }
     }
-    let mut arr = [0u8; 32];
-    arr.copy_from_slice(&bytes);
-    Ok(arr)
+    flushed
 }
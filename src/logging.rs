@@ -0,0 +1,79 @@
+use log::{LevelFilter, Log, Metadata, Record};
+use napi::threadsafe_function::{ThreadsafeFunction, ThreadsafeFunctionCallMode};
+use std::sync::{Once, OnceLock, RwLock};
+
+/// One native log line, as passed to the `setLogger` callback.
+#[napi(object)]
+pub struct LogRecord {
+    /// "error", "warn", "info", "debug" or "trace"
+    pub level: String,
+    /// Module the line comes from, e.g. "wireshade::peer" or "wireshade::tcp".
+    pub target: String,
+    pub message: String,
+}
+
+struct Sink {
+    level: LevelFilter,
+    /// Without a callback, lines at `level` go to stderr.
+    callback: Option<ThreadsafeFunction<LogRecord>>,
+}
+
+/// Sends `log` records to the sink configured with `setLogger`. Until there is one,
+/// logs go to stderr filtered by `RUST_LOG` (warnings and errors by default).
+struct JsLogger {
+    stderr: env_logger::Logger,
+    sink: RwLock<Option<Sink>>,
+}
+
+static LOGGER: OnceLock<JsLogger> = OnceLock::new();
+
+/// Installs the logger; cheap to call again.
+pub(crate) fn init() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+        let logger = LOGGER.get_or_init(|| JsLogger {
+            stderr: env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).build(),
+            sink: RwLock::new(None),
+        });
+        if log::set_logger(logger).is_ok() {
+            log::set_max_level(logger.stderr.filter());
+        }
+    });
+}
+
+pub(crate) fn set_sink(level: LevelFilter, callback: Option<ThreadsafeFunction<LogRecord>>) {
+    init();
+    let Some(logger) = LOGGER.get() else { return };
+    *logger.sink.write().unwrap_or_else(|e| e.into_inner()) = Some(Sink { level, callback });
+    log::set_max_level(level);
+}
+
+impl Log for JsLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        match &*self.sink.read().unwrap_or_else(|e| e.into_inner()) {
+            Some(sink) => metadata.level() <= sink.level,
+            None => self.stderr.enabled(metadata),
+        }
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        match &*self.sink.read().unwrap_or_else(|e| e.into_inner()) {
+            Some(Sink { callback: Some(callback), .. }) => {
+                callback.call(Ok(LogRecord {
+                    level: record.level().as_str().to_lowercase(),
+                    target: record.target().to_string(),
+                    message: record.args().to_string(),
+                }), ThreadsafeFunctionCallMode::NonBlocking);
+            }
+            Some(Sink { callback: None, .. }) => eprintln!("[{} {}] {}", record.level(), record.target(), record.args()),
+            None => self.stderr.log(record),
+        }
+    }
+
+    fn flush(&self) {
+        self.stderr.flush();
+    }
+}
@@ -0,0 +1,132 @@
+use crate::error::WireShadeError;
+use crate::proxy::socket_addr;
+use crate::stream::{StreamReader, StreamWriter, TunnelListener, TunnelStream};
+use crate::tunnel::Tunnel;
+use bytes::{Buf, Bytes};
+use std::future::Future;
+use std::io;
+use std::net::{SocketAddr, SocketAddrV4};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Most bytes one `poll_write` takes, so a huge buffer doesn't sit in the loop's queue at once.
+const MAX_WRITE: usize = 64 * 1024;
+
+type Op = Pin<Box<dyn Future<Output = Result<(), WireShadeError>> + Send>>;
+
+/// A TCP connection through the tunnel with the `tokio::io` traits, so hyper, tonic,
+/// tokio-rustls and friends run over it unchanged.
+///
+/// A write is accepted as soon as the tunnel has it queued; the next write (or `flush`)
+/// waits until it is in the TCP send buffer. Dropping the stream closes it like a socket:
+/// with a FIN after what was written, or a reset if received data was left unread.
+pub struct WgTcpStream {
+    reader: StreamReader,
+    writer: StreamWriter,
+    peer: SocketAddr,
+    /// Rest of the last chunk received that didn't fit the caller's buffer.
+    unread: Bytes,
+    /// Write or shutdown still on its way into the send buffer.
+    pending: Option<Op>,
+    shut_down: bool,
+}
+
+impl WgTcpStream {
+    /// Opens a connection to `addr` inside the tunnel.
+    pub async fn connect(tunnel: &Tunnel, addr: SocketAddrV4) -> Result<Self, WireShadeError> {
+        let stream = tunnel.connect(*addr.ip(), addr.port()).await?;
+        Ok(Self::new(stream, SocketAddr::V4(addr)))
+    }
+
+    fn new(stream: TunnelStream, peer: SocketAddr) -> Self {
+        let (reader, writer) = stream.into_split();
+        Self { reader, writer, peer, unread: Bytes::new(), pending: None, shut_down: false }
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer
+    }
+
+    /// Drives the write or shutdown in flight, if any, to completion.
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), WireShadeError>> {
+        if let Some(op) = &mut self.pending {
+            let res = ready!(op.as_mut().poll(cx));
+            self.pending = None;
+            res?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncRead for WgTcpStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.unread.is_empty() {
+            match ready!(this.reader.poll_read(cx))? {
+                Some(data) => this.unread = data,
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+        let n = buf.remaining().min(this.unread.len());
+        buf.put_slice(&this.unread[..n]);
+        this.unread.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for WgTcpStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending(cx))?;
+        if this.shut_down {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        let n = buf.len().min(MAX_WRITE);
+        this.pending = Some(Box::pin(this.writer.write(Bytes::copy_from_slice(&buf[..n]))));
+        // Get it onto the command channel now, not on the next call
+        if let Poll::Ready(Err(e)) = this.poll_pending(cx) {
+            return Poll::Ready(Err(e.into()));
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().poll_pending(cx).map_err(Into::into)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending(cx))?;
+        if !this.shut_down {
+            this.shut_down = true;
+            this.pending = Some(Box::pin(this.writer.shutdown()));
+            ready!(this.poll_pending(cx))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Accepts TCP connections on a tunnel port, like `tokio::net::TcpListener`. Dropping it
+/// stops listening; accepted connections stay open.
+pub struct WgTcpListener {
+    inner: TunnelListener,
+}
+
+impl WgTcpListener {
+    /// Listens on `port` at the tunnel address.
+    pub async fn bind(tunnel: &Tunnel, port: u16) -> Result<Self, WireShadeError> {
+        Ok(Self { inner: tunnel.listen(port).await? })
+    }
+
+    /// Waits for the next connection and returns it with the peer's address.
+    pub async fn accept(&mut self) -> Result<(WgTcpStream, SocketAddr), WireShadeError> {
+        let (stream, _, remote) = self.inner.accept().await?;
+        let peer = socket_addr(remote)?;
+        Ok((WgTcpStream::new(stream, peer), peer))
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, WireShadeError> {
+        socket_addr(self.inner.local_addr())
+    }
+}
@@ -0,0 +1,240 @@
+use smoltcp::wire::{IpAddress, IpProtocol, Ipv4Packet, Ipv6Packet, TcpPacket};
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// Called with each decrypted packet a hook matches.
+pub type PacketCallback = Box<dyn Fn(&[u8]) + Send>;
+
+/// Destination of a raw IPv4 or IPv6 packet, `None` if it doesn't parse.
+pub(crate) fn destination(packet: &[u8]) -> Option<IpAddress> {
+    match packet.first()? >> 4 {
+        4 => Some(Ipv4Packet::new_checked(packet).ok()?.dst_addr().into()),
+        6 => Some(Ipv6Packet::new_checked(packet).ok()?.dst_addr().into()),
+        _ => None,
+    }
+}
+
+/// IP protocol and what follows the IP header, `None` for non-first fragments.
+fn transport(packet: &[u8]) -> Option<(IpProtocol, Option<&[u8]>)> {
+    match packet.first()? >> 4 {
+        4 => {
+            let ip = Ipv4Packet::new_checked(packet).ok()?;
+            Some((ip.next_header(), (ip.frag_offset() == 0).then(|| ip.payload())))
+        }
+        6 => {
+            let ip = Ipv6Packet::new_checked(packet).ok()?;
+            Some((ip.next_header(), Some(ip.payload())))
+        }
+        _ => None,
+    }
+}
+
+/// Whether a raw packet carries something the other end has to answer: a payload, or a
+/// TCP SYN or FIN. Bare ACKs aren't answered.
+pub(crate) fn expects_answer(packet: &[u8]) -> bool {
+    match transport(packet) {
+        Some((IpProtocol::Tcp, Some(payload))) => TcpPacket::new_checked(payload).is_ok_and(|tcp| tcp.syn() || tcp.fin() || !tcp.payload().is_empty()),
+        Some((IpProtocol::Udp, Some(payload))) => payload.len() > 8,
+        Some((_, Some(payload))) => !payload.is_empty(),
+        // The rest of a fragmented packet
+        Some((_, None)) => true,
+        None => false,
+    }
+}
+
+/// IP protocol and, for TCP and UDP, the source and destination ports.
+/// Extension headers and non-first fragments are not looked into.
+fn classify(packet: &[u8]) -> Option<(u8, Option<(u16, u16)>)> {
+    let (protocol, payload) = transport(packet)?;
+    let ports = match (protocol, payload) {
+        (IpProtocol::Tcp | IpProtocol::Udp, Some(payload)) if payload.len() >= 4 => Some((
+            u16::from_be_bytes([payload[0], payload[1]]),
+            u16::from_be_bytes([payload[2], payload[3]]),
+        )),
+        _ => None,
+    };
+    Some((protocol.into(), ports))
+}
+
+/// Which decrypted packets a hook sees. Empty matches everything.
+#[derive(Default)]
+pub struct PacketFilter {
+    pub protocol: Option<u8>,
+    /// Matches either the source or destination port, like tcpdump's `port`.
+    pub port: Option<u16>,
+    /// Keep matching packets from smoltcp instead of passing them on.
+    pub intercept: bool,
+}
+
+impl PacketFilter {
+    fn matches(&self, protocol: u8, ports: Option<(u16, u16)>) -> bool {
+        self.protocol.is_none_or(|p| p == protocol)
+            && self.port.is_none_or(|port| ports.is_some_and(|(src, dst)| src == port || dst == port))
+    }
+}
+
+/// Callbacks registered with `onPacket`, fed every packet that comes out of the tunnel.
+#[derive(Default)]
+pub(crate) struct PacketHooks {
+    hooks: Vec<(u32, PacketFilter, PacketCallback)>,
+}
+
+impl PacketHooks {
+    /// A hook id no other hook of any tunnel has.
+    pub fn next_id() -> u32 {
+        static NEXT_ID: AtomicU32 = AtomicU32::new(1);
+        NEXT_ID.fetch_add(1, Ordering::Relaxed)
+    }
+
+    pub fn add(&mut self, id: u32, filter: PacketFilter, callback: PacketCallback) {
+        self.hooks.push((id, filter, callback));
+    }
+
+    pub fn remove(&mut self, id: u32) {
+        self.hooks.retain(|(hook_id, ..)| *hook_id != id);
+    }
+
+    /// Hands `packet` to every matching hook. Returns true if one of them intercepts it.
+    pub fn dispatch(&self, packet: &[u8]) -> bool {
+        if self.hooks.is_empty() {
+            return false;
+        }
+        let Some((protocol, ports)) = classify(packet) else { return false };
+        let mut intercepted = false;
+        for (_, filter, callback) in &self.hooks {
+            if filter.matches(protocol, ports) {
+                callback(packet);
+                intercepted |= filter.intercept;
+            }
+        }
+        intercepted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use smoltcp::wire::{Ipv4Address, Ipv6Address};
+
+    /// An IPv4 packet around `payload`.
+    fn ipv4(protocol: IpProtocol, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0; 20 + payload.len()];
+        let mut ip = Ipv4Packet::new_unchecked(&mut packet[..]);
+        ip.set_version(4);
+        ip.set_header_len(20);
+        ip.set_total_len((20 + payload.len()) as u16);
+        ip.set_hop_limit(64);
+        ip.set_next_header(protocol);
+        ip.set_src_addr(Ipv4Address::new(10, 0, 0, 2));
+        ip.set_dst_addr(Ipv4Address::new(10, 0, 0, 1));
+        ip.payload_mut().copy_from_slice(payload);
+        packet
+    }
+
+    /// An IPv6 packet around `payload`.
+    fn ipv6(protocol: IpProtocol, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0; 40 + payload.len()];
+        let mut ip = Ipv6Packet::new_unchecked(&mut packet[..]);
+        ip.set_version(6);
+        ip.set_payload_len(payload.len() as u16);
+        ip.set_next_header(protocol);
+        ip.set_hop_limit(64);
+        ip.set_src_addr(Ipv6Address::new(0xfd00, 0, 0, 0, 0, 0, 0, 2));
+        ip.set_dst_addr(Ipv6Address::new(0xfd00, 0, 0, 0, 0, 0, 0, 1));
+        ip.payload_mut().copy_from_slice(payload);
+        packet
+    }
+
+    /// Start of a TCP or UDP header from port 40000 to 443, and some more.
+    const PORTS: [u8; 8] = [0x9c, 0x40, 0x01, 0xbb, 0, 0, 0, 0];
+
+    #[test]
+    fn classifies_ipv4() {
+        assert_eq!(classify(&ipv4(IpProtocol::Tcp, &PORTS)), Some((6, Some((40000, 443)))));
+        assert_eq!(classify(&ipv4(IpProtocol::Udp, &PORTS)), Some((17, Some((40000, 443)))));
+        assert_eq!(classify(&ipv4(IpProtocol::Icmp, &[8, 0, 0, 0])), Some((1, None)));
+    }
+
+    #[test]
+    fn classifies_ipv6() {
+        assert_eq!(classify(&ipv6(IpProtocol::Tcp, &PORTS)), Some((6, Some((40000, 443)))));
+        assert_eq!(classify(&ipv6(IpProtocol::Udp, &PORTS)), Some((17, Some((40000, 443)))));
+        assert_eq!(classify(&ipv6(IpProtocol::Icmpv6, &[128, 0, 0, 0])), Some((58, None)));
+    }
+
+    #[test]
+    fn skips_ports_of_later_fragments() {
+        let mut packet = ipv4(IpProtocol::Udp, &PORTS);
+        Ipv4Packet::new_unchecked(&mut packet[..]).set_frag_offset(8);
+        assert_eq!(classify(&packet), Some((17, None)));
+    }
+
+    #[test]
+    fn truncated_packets_are_not_classified() {
+        for packet in [ipv4(IpProtocol::Tcp, &PORTS), ipv6(IpProtocol::Udp, &PORTS)] {
+            let header_len = if packet[0] >> 4 == 4 { 20 } else { 40 };
+            for len in 0..header_len {
+                assert_eq!(classify(&packet[..len]), None, "{} bytes", len);
+                assert_eq!(destination(&packet[..len]), None, "{} bytes", len);
+            }
+            // Shorter than its length field says
+            assert_eq!(classify(&packet[..packet.len() - 1]), None);
+        }
+        assert_eq!(classify(&[0x50; 40]), None);
+    }
+
+    #[test]
+    fn truncated_transport_headers_have_no_ports() {
+        assert_eq!(classify(&ipv4(IpProtocol::Tcp, &PORTS[..3])), Some((6, None)));
+        assert_eq!(classify(&ipv6(IpProtocol::Udp, &[])), Some((17, None)));
+    }
+
+    #[test]
+    fn bare_acks_expect_no_answer() {
+        // 20 byte TCP header with data offset 5 and flags to fill in, then 4 bytes of data
+        let tcp = |flags: u8| {
+            let mut tcp = [0u8; 24];
+            tcp[..4].copy_from_slice(&PORTS[..4]);
+            tcp[12] = 5 << 4;
+            tcp[13] = flags;
+            tcp
+        };
+        let (ack, syn, fin) = (tcp(0x10), tcp(0x02), tcp(0x11));
+        assert!(!expects_answer(&ipv4(IpProtocol::Tcp, &ack[..20])));
+        assert!(!expects_answer(&ipv6(IpProtocol::Tcp, &ack[..20])));
+        assert!(expects_answer(&ipv4(IpProtocol::Tcp, &ack)));
+        assert!(expects_answer(&ipv6(IpProtocol::Tcp, &ack)));
+        assert!(expects_answer(&ipv4(IpProtocol::Tcp, &syn[..20])));
+        assert!(expects_answer(&ipv4(IpProtocol::Tcp, &fin[..20])));
+        assert!(!expects_answer(&ipv4(IpProtocol::Udp, &PORTS)));
+        assert!(expects_answer(&ipv4(IpProtocol::Udp, &[&PORTS[..], b"dns"].concat())));
+        assert!(expects_answer(&ipv4(IpProtocol::Icmp, &[8, 0, 0, 0])));
+        assert!(!expects_answer(&[0x45; 10]));
+    }
+
+    #[test]
+    fn filters_match_protocol_and_either_port() {
+        let filter = PacketFilter { protocol: Some(6), port: Some(443), intercept: false };
+        assert!(filter.matches(6, Some((40000, 443))));
+        assert!(filter.matches(6, Some((443, 40000))));
+        assert!(!filter.matches(17, Some((40000, 443))));
+        assert!(!filter.matches(6, None));
+        assert!(PacketFilter::default().matches(1, None));
+    }
+
+    #[test]
+    fn dispatch_reports_interception() {
+        let seen = std::sync::Arc::new(AtomicU32::new(0));
+        let mut hooks = PacketHooks::default();
+        let counter = seen.clone();
+        hooks.add(1, PacketFilter { protocol: Some(17), ..PacketFilter::default() }, Box::new(move |_| {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }));
+        assert!(!hooks.dispatch(&ipv4(IpProtocol::Udp, &PORTS)));
+        assert!(!hooks.dispatch(&ipv4(IpProtocol::Tcp, &PORTS)));
+        hooks.add(2, PacketFilter { port: Some(443), intercept: true, ..PacketFilter::default() }, Box::new(|_| {}));
+        assert!(hooks.dispatch(&ipv6(IpProtocol::Tcp, &PORTS)));
+        hooks.remove(2);
+        assert!(!hooks.dispatch(&ipv6(IpProtocol::Tcp, &PORTS)));
+        assert_eq!(seen.load(Ordering::Relaxed), 1);
+    }
+}
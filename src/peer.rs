@@ -0,0 +1,275 @@
+use boringtun::noise::handshake::parse_handshake_anon;
+use boringtun::noise::rate_limiter::RateLimiter;
+use boringtun::noise::{Packet, Tunn, TunnResult};
+use boringtun::x25519::{PublicKey, StaticSecret};
+use smoltcp::wire::{IpAddress, IpCidr};
+use crate::error::WireShadeError;
+use crate::reconnect::Link;
+use crate::OuterSocket;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// Same budget boringtun's own device uses before it starts demanding cookies.
+const HANDSHAKE_RATE_LIMIT: u64 = 100;
+
+/// WireGuard message types (first byte of the datagram).
+const HANDSHAKE_INIT: u8 = 1;
+const COOKIE_REPLY: u8 = 3;
+
+/// Unanswered initiations (boringtun retries every 5s) before we suspect the endpoint moved.
+const RESOLVE_AFTER_ATTEMPTS: u32 = 3;
+const MIN_RESOLVE_GAP: Duration = Duration::from_secs(15);
+
+/// Peer as configured by the caller, with keys and addresses already parsed.
+pub struct PeerSpec {
+    pub public_key: [u8; 32],
+    pub preshared_key: Option<[u8; 32]>,
+    pub endpoint: Option<SocketAddr>,
+    /// The endpoint as written by the user, kept when it is a hostname so it can be resolved again.
+    pub endpoint_host: Option<String>,
+    pub allowed_ips: Vec<IpCidr>,
+    pub persistent_keepalive: Option<u16>,
+}
+
+pub(crate) struct Peer {
+    pub public_key: [u8; 32],
+    preshared_key: Option<[u8; 32]>,
+    persistent_keepalive: Option<u16>,
+    pub tunn: Tunn,
+    /// Where to send datagrams. `None` until the peer has talked to us if it was
+    /// configured without a static endpoint.
+    pub endpoint: Option<SocketAddr>,
+    pub endpoint_host: Option<String>,
+    pub allowed_ips: Vec<IpCidr>,
+    /// Handshake initiations sent since we last heard from the peer.
+    pub handshake_attempts: u32,
+    /// A DNS lookup for `endpoint_host` is in flight.
+    pub resolving: bool,
+    pub last_resolve: Option<Instant>,
+    /// Configured with an endpoint, so it is our job to keep the tunnel to it up.
+    pub initiator: bool,
+    pub link: Link,
+}
+
+impl Peer {
+    /// Whether an inner packet from/to `addr` belongs to this peer (cryptokey routing).
+    pub fn allows(&self, addr: IpAddress) -> bool {
+        self.allowed_ips.iter().any(|cidr| cidr.contains_addr(&addr))
+    }
+
+    pub fn send(&mut self, udp_socket: &OuterSocket, datagram: &[u8]) {
+        if datagram.first() == Some(&HANDSHAKE_INIT) {
+            self.handshake_attempts += 1;
+        }
+        match self.endpoint {
+            Some(endpoint) => {
+                if let Err(e) = udp_socket.try_send_to(datagram, endpoint) {
+                    log::warn!("UDP send to {} failed: {}", endpoint, e);
+                }
+            }
+            None => log::debug!("Dropping {} bytes for peer without endpoint", datagram.len()),
+        }
+    }
+
+    /// Sends everything boringtun still has queued after a decapsulate that asked to write.
+    pub fn drain(&mut self, udp_socket: &OuterSocket, dst_buf: &mut [u8]) {
+        while let TunnResult::WriteToNetwork(b) = self.tunn.decapsulate(None, &[], dst_buf) {
+            self.send(udp_socket, b);
+        }
+    }
+
+    /// Whether the hostname should be looked up again because handshakes keep failing.
+    pub fn needs_resolve(&self) -> bool {
+        self.endpoint_host.is_some()
+            && !self.resolving
+            && self.handshake_attempts >= RESOLVE_AFTER_ATTEMPTS
+            && self.last_resolve.is_none_or(|t| t.elapsed() >= MIN_RESOLVE_GAP)
+    }
+}
+
+/// Outcome of matching an inbound datagram to a peer.
+pub(crate) enum Lookup<'a> {
+    Peer(usize),
+    /// We are under load and the sender must retry with a cookie.
+    Reply(&'a [u8]),
+    Unknown,
+}
+
+/// All peers of one interface, indexed the way boringtun assigns session indices.
+pub(crate) struct PeerTable {
+    peers: Vec<Peer>,
+    static_private: StaticSecret,
+    static_public: PublicKey,
+    rate_limiter: Arc<RateLimiter>,
+}
+
+impl PeerTable {
+    pub fn new(private_key: [u8; 32], specs: Vec<PeerSpec>) -> std::result::Result<Self, &'static str> {
+        let static_private = StaticSecret::from(private_key);
+        let static_public = PublicKey::from(&static_private);
+        let rate_limiter = Arc::new(RateLimiter::new(&static_public, HANDSHAKE_RATE_LIMIT));
+
+        let mut peers = Vec::with_capacity(specs.len());
+        for (index, spec) in specs.into_iter().enumerate() {
+            // boringtun puts the index in the upper 24 bits of every session id, which is
+            // how data packets find their way back to the right `Tunn`.
+            let tunn = Tunn::new(
+                static_private.clone(),
+                PublicKey::from(spec.public_key),
+                spec.preshared_key,
+                spec.persistent_keepalive,
+                index as u32,
+                Some(rate_limiter.clone()),
+            )?;
+            peers.push(Peer {
+                public_key: spec.public_key,
+                preshared_key: spec.preshared_key,
+                persistent_keepalive: spec.persistent_keepalive,
+                tunn,
+                endpoint: spec.endpoint,
+                endpoint_host: spec.endpoint_host,
+                allowed_ips: spec.allowed_ips,
+                handshake_attempts: 0,
+                resolving: false,
+                last_resolve: None,
+                initiator: spec.endpoint.is_some(),
+                link: Link::new(),
+            });
+        }
+
+        Ok(Self { peers, static_private, static_public, rate_limiter })
+    }
+
+    /// Throws away every session and handshake in progress, as after a network change
+    /// where nothing negotiated over the old socket can be trusted to still work.
+    pub fn reset_sessions(&mut self) -> std::result::Result<(), &'static str> {
+        for index in 0..self.peers.len() {
+            self.reset_session(index)?;
+        }
+        Ok(())
+    }
+
+    pub fn reset_session(&mut self, index: usize) -> std::result::Result<(), &'static str> {
+        let peer = &mut self.peers[index];
+        peer.tunn = Tunn::new(
+            self.static_private.clone(),
+            PublicKey::from(peer.public_key),
+            peer.preshared_key,
+            peer.persistent_keepalive,
+            index as u32,
+            Some(self.rate_limiter.clone()),
+        )?;
+        peer.handshake_attempts = 0;
+        Ok(())
+    }
+
+    /// Must be called once per second; the shared limiter is not reset by the tunnels.
+    pub fn reset_rate_limiter(&self) {
+        self.rate_limiter.reset_count();
+    }
+
+    pub fn get_mut(&mut self, index: usize) -> &mut Peer {
+        &mut self.peers[index]
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn iter_mut(&mut self) -> std::slice::IterMut<'_, Peer> {
+        self.peers.iter_mut()
+    }
+
+    /// Picks the peer whose allowed IPs contain `dst` with the longest prefix.
+    pub fn route_mut(&mut self, dst: IpAddress) -> Option<&mut Peer> {
+        self.peers
+            .iter_mut()
+            .filter_map(|peer| {
+                let prefix = peer
+                    .allowed_ips
+                    .iter()
+                    .filter(|cidr| cidr.contains_addr(&dst))
+                    .map(|cidr| cidr.prefix_len())
+                    .max()?;
+                Some((prefix, peer))
+            })
+            // `max_by_key` keeps the last maximum; reverse so the first configured peer wins ties.
+            .rev()
+            .max_by_key(|(prefix, _)| *prefix)
+            .map(|(_, peer)| peer)
+    }
+
+    /// Finds the peer a datagram is addressed to. Handshake initiations carry no index we
+    /// know about, so the initiator's static key is decrypted and matched against the table.
+    pub fn lookup<'a>(&self, datagram: &[u8], from: SocketAddr, cookie_buf: &'a mut [u8]) -> Lookup<'a> {
+        let packet = match self.rate_limiter.verify_packet(Some(from.ip()), datagram, cookie_buf) {
+            Ok(packet) => packet,
+            Err(TunnResult::WriteToNetwork(cookie)) => return Lookup::Reply(cookie),
+            Err(_) => return Lookup::Unknown,
+        };
+
+        let index = match packet {
+            Packet::HandshakeInit(ref init) => {
+                match parse_handshake_anon(&self.static_private, &self.static_public, init) {
+                    Ok(half) => match self.peers.iter().position(|p| p.public_key == half.peer_static_public) {
+                        Some(index) => index,
+                        None => {
+                            log::warn!("Handshake from unknown peer {} ({})", encode_key(&half.peer_static_public), from);
+                            return Lookup::Unknown;
+                        }
+                    },
+                    Err(_) => return Lookup::Unknown,
+                }
+            }
+            Packet::HandshakeResponse(p) => (p.receiver_idx >> 8) as usize,
+            Packet::PacketCookieReply(p) => (p.receiver_idx >> 8) as usize,
+            Packet::PacketData(p) => (p.receiver_idx >> 8) as usize,
+        };
+
+        if index < self.peers.len() {
+            Lookup::Peer(index)
+        } else {
+            Lookup::Unknown
+        }
+    }
+}
+
+/// Records the source of an authenticated `datagram` as the peer's endpoint (roaming).
+/// Cookie replies, ours to an unchecked handshake or one we received, aren't authenticated
+/// and must not move the endpoint. Returns the previous endpoint if it changed.
+pub(crate) fn learn_endpoint(peer: &mut Peer, from: SocketAddr, datagram: &[u8], result: &TunnResult) -> Option<Option<SocketAddr>> {
+    let authenticated = match result {
+        TunnResult::Err(_) => false,
+        TunnResult::WriteToNetwork(b) => b.first() != Some(&COOKIE_REPLY),
+        _ => datagram.first() != Some(&COOKIE_REPLY),
+    };
+    if !authenticated {
+        return None;
+    }
+    peer.handshake_attempts = 0;
+    peer.link.on_receive(Instant::now());
+    if peer.endpoint == Some(from) {
+        return None;
+    }
+    log::info!("Peer {} endpoint is now {}", encode_key(&peer.public_key), from);
+    Some(peer.endpoint.replace(from))
+}
+
+pub(crate) fn encode_key(key: &[u8; 32]) -> String {
+    use base64::{engine::general_purpose, Engine as _};
+    general_purpose::STANDARD.encode(key)
+}
+
+/// Decodes a base64 WireGuard key. `kind` names the key in the error, e.g. "private key".
+pub fn decode_key(key: &str, kind: &'static str) -> Result<[u8; 32], WireShadeError> {
+    use base64::{engine::general_purpose, Engine as _};
+    let bytes = general_purpose::STANDARD.decode(key)
+        .map_err(|e| WireShadeError::InvalidKey { kind, reason: e.to_string() })?;
+    if bytes.len() != 32 {
+        return Err(WireShadeError::InvalidKey { kind, reason: "Key must be 32 bytes".to_string() });
+    }
+    let mut arr = [0u8; 32];
+    arr.copy_from_slice(&bytes);
+    Ok(arr)
+}
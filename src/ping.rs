@@ -0,0 +1,172 @@
+use crate::error::WireShadeError;
+use smoltcp::iface::{SocketHandle, SocketSet};
+use smoltcp::phy::ChecksumCapabilities;
+use smoltcp::socket::icmp;
+use smoltcp::wire::{Icmpv4Packet, Icmpv4Repr, IpAddress, Ipv4Address};
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+
+/// ICMP header in front of the echo payload.
+const ECHO_HEADER_LEN: usize = 8;
+
+pub struct PingConfig {
+    pub count: u32,
+    /// Bytes of payload per probe, like ping's `-s`.
+    pub size: usize,
+    pub interval: Duration,
+    /// How long each probe waits for its reply before it counts as lost.
+    pub timeout: Duration,
+}
+
+impl Default for PingConfig {
+    fn default() -> Self {
+        Self { count: 4, size: 56, interval: Duration::from_secs(1), timeout: Duration::from_secs(1) }
+    }
+}
+
+/// Outcome of `ping`.
+#[cfg_attr(feature = "napi", napi(object))]
+pub struct PingResult {
+    pub host: String,
+    pub transmitted: u32,
+    pub received: u32,
+    /// Fraction of probes without a reply, 0 to 1.
+    pub loss: f64,
+    /// Round-trip time of each probe in ms, `null` for lost ones.
+    pub rtts: Vec<Option<f64>>,
+    pub min: Option<f64>,
+    pub avg: Option<f64>,
+    pub max: Option<f64>,
+}
+
+struct Ping {
+    handle: SocketHandle,
+    dest: Ipv4Address,
+    ident: u16,
+    config: PingConfig,
+    /// When each probe went out, indexed by sequence number.
+    sent: Vec<Instant>,
+    rtts: Vec<Option<Duration>>,
+    next_send: Instant,
+    resp: oneshot::Sender<Result<PingResult, WireShadeError>>,
+}
+
+impl Ping {
+    fn send_due(&mut self, socket: &mut icmp::Socket, now: Instant) {
+        if self.sent.len() >= self.config.count as usize || now < self.next_send || !socket.can_send() {
+            return;
+        }
+        let seq_no = self.sent.len() as u16;
+        let data: Vec<u8> = (0..self.config.size).map(|i| i as u8).collect();
+        let repr = Icmpv4Repr::EchoRequest { ident: self.ident, seq_no, data: &data };
+        let Ok(buf) = socket.send(repr.buffer_len(), IpAddress::Ipv4(self.dest)) else { return };
+        repr.emit(&mut Icmpv4Packet::new_unchecked(buf), &ChecksumCapabilities::default());
+        self.sent.push(now);
+        self.rtts.push(None);
+        self.next_send = now + self.config.interval;
+    }
+
+    fn receive(&mut self, socket: &mut icmp::Socket, now: Instant) {
+        while let Ok((payload, from)) = socket.recv() {
+            if from != IpAddress::Ipv4(self.dest) {
+                continue;
+            }
+            let Ok(packet) = Icmpv4Packet::new_checked(payload) else { continue };
+            let Ok(Icmpv4Repr::EchoReply { ident, seq_no, .. }) = Icmpv4Repr::parse(&packet, &ChecksumCapabilities::default()) else {
+                continue;
+            };
+            let Some(&sent) = self.sent.get(seq_no as usize) else { continue };
+            let rtt = now - sent;
+            // Duplicates keep the first reply; late ones stay lost
+            if ident == self.ident && rtt <= self.config.timeout && self.rtts[seq_no as usize].is_none() {
+                self.rtts[seq_no as usize] = Some(rtt);
+            }
+        }
+    }
+
+    fn is_done(&self, now: Instant) -> bool {
+        if self.sent.len() < self.config.count as usize {
+            return false;
+        }
+        self.rtts.iter().all(Option::is_some)
+            || self.sent.last().is_none_or(|&last| now >= last + self.config.timeout)
+    }
+
+    fn result(&self) -> PingResult {
+        let rtts: Vec<Option<f64>> = self.rtts.iter().map(|rtt| rtt.map(|d| d.as_secs_f64() * 1000.0)).collect();
+        let answered: Vec<f64> = rtts.iter().flatten().copied().collect();
+        let transmitted = self.sent.len() as u32;
+        let received = answered.len() as u32;
+        PingResult {
+            host: self.dest.to_string(),
+            transmitted,
+            received,
+            loss: if transmitted == 0 { 1.0 } else { 1.0 - f64::from(received) / f64::from(transmitted) },
+            min: answered.iter().copied().reduce(f64::min),
+            avg: (!answered.is_empty()).then(|| answered.iter().sum::<f64>() / answered.len() as f64),
+            max: answered.iter().copied().reduce(f64::max),
+            rtts,
+        }
+    }
+}
+
+/// Pings in flight, each on its own ICMP socket bound to a distinct identifier.
+#[derive(Default)]
+pub(crate) struct Pings {
+    active: Vec<Ping>,
+    next_ident: u16,
+}
+
+impl Pings {
+    /// Adds the ICMP socket; the caller checks that the socket set has room.
+    pub fn start(
+        &mut self,
+        sockets: &mut SocketSet<'_>,
+        dest: Ipv4Address,
+        config: PingConfig,
+        resp: oneshot::Sender<Result<PingResult, WireShadeError>>,
+    ) {
+        let ident = self.next_ident;
+        self.next_ident = self.next_ident.wrapping_add(1);
+
+        let probe_len = ECHO_HEADER_LEN + config.size;
+        let rx = icmp::PacketBuffer::new(vec![icmp::PacketMetadata::EMPTY; 8], vec![0; 8 * (probe_len + 20)]);
+        let tx = icmp::PacketBuffer::new(vec![icmp::PacketMetadata::EMPTY; 2], vec![0; 2 * probe_len]);
+        let mut socket = icmp::Socket::new(rx, tx);
+        if socket.bind(icmp::Endpoint::Ident(ident)).is_err() {
+            let _ = resp.send(Err(WireShadeError::InvalidArgument("ping identifier".to_string())));
+            return;
+        }
+        let handle = sockets.add(socket);
+        self.active.push(Ping {
+            handle,
+            dest,
+            ident,
+            config,
+            sent: Vec::new(),
+            rtts: Vec::new(),
+            next_send: Instant::now(),
+            resp,
+        });
+    }
+
+    /// Collects replies, sends the probes that are due and resolves finished pings.
+    pub fn poll(&mut self, sockets: &mut SocketSet<'_>) {
+        let now = Instant::now();
+        let mut i = 0;
+        while i < self.active.len() {
+            let ping = &mut self.active[i];
+            let socket = sockets.get_mut::<icmp::Socket>(ping.handle);
+            ping.receive(socket, now);
+            ping.send_due(socket, now);
+            if !ping.is_done(now) && !ping.resp.is_closed() {
+                i += 1;
+                continue;
+            }
+            let ping = self.active.swap_remove(i);
+            sockets.remove(ping.handle);
+            let result = ping.result();
+            let _ = ping.resp.send(Ok(result));
+        }
+    }
+}
@@ -0,0 +1,46 @@
+//! Packet buffers that go back to a free list after use, so packets moving between
+//! WireGuard and the TCP stack don't each cost an allocation.
+
+use std::cell::RefCell;
+
+/// Capacity of a new buffer: an IP packet at the tunnel MTU, with room to spare.
+const BUFFER_SIZE: usize = 2048;
+/// Free buffers kept at most; a burst beyond this is allocated and freed as before.
+const MAX_FREE: usize = 256;
+
+/// Shared by the device and its smoltcp tokens, which are handed out together, hence
+/// the `RefCell`. Only ever used from the tunnel loop.
+#[derive(Default)]
+pub(crate) struct PacketPool {
+    free: RefCell<Vec<Vec<u8>>>,
+}
+
+impl PacketPool {
+    /// A buffer holding a copy of `data`.
+    pub fn copy(&self, data: &[u8]) -> Vec<u8> {
+        let mut buffer = self.take();
+        buffer.extend_from_slice(data);
+        buffer
+    }
+
+    /// A buffer of `len` zeros.
+    pub fn zeroed(&self, len: usize) -> Vec<u8> {
+        let mut buffer = self.take();
+        buffer.resize(len, 0);
+        buffer
+    }
+
+    /// Takes `buffer` back for reuse.
+    pub fn put(&self, mut buffer: Vec<u8>) {
+        let mut free = self.free.borrow_mut();
+        // Odd sizes from `sendPacket` aren't worth keeping
+        if free.len() < MAX_FREE && buffer.capacity() >= BUFFER_SIZE && buffer.capacity() <= 4 * BUFFER_SIZE {
+            buffer.clear();
+            free.push(buffer);
+        }
+    }
+
+    fn take(&self) -> Vec<u8> {
+        self.free.borrow_mut().pop().unwrap_or_else(|| Vec::with_capacity(BUFFER_SIZE))
+    }
+}
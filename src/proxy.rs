@@ -0,0 +1,249 @@
+use crate::error::WireShadeError;
+use crate::stream::{relay, TunnelListener, TunnelStream};
+use crate::tunnel::Tunnel;
+use smoltcp::wire::{IpAddress, IpCidr, IpEndpoint, Ipv4Address};
+use std::fmt::Display;
+use std::future::Future;
+use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::{AbortHandle, JoinSet};
+
+/// Username and password clients must present.
+#[cfg_attr(feature = "napi", napi(object))]
+#[derive(Clone)]
+pub struct ProxyAuth {
+    pub username: String,
+    pub password: String,
+}
+
+/// Which destinations a proxy may connect to. An empty list allows everything.
+#[derive(Default)]
+pub struct Access {
+    pub ips: Vec<IpCidr>,
+    pub ports: Vec<u16>,
+}
+
+impl Access {
+    pub fn allows(&self, ip: IpAddress, port: u16) -> bool {
+        (self.ips.is_empty() || self.ips.iter().any(|cidr| cidr.contains_addr(&ip)))
+            && (self.ports.is_empty() || self.ports.contains(&port))
+    }
+}
+
+/// Counters shared by a server's accept loop and its connections.
+#[derive(Default)]
+pub(crate) struct Stats {
+    active: AtomicU32,
+    total: AtomicU32,
+    sent: AtomicU64,
+    received: AtomicU64,
+}
+
+impl Stats {
+    /// Bytes that went into the tunnel.
+    pub fn sent(&self, bytes: usize) {
+        self.sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Bytes that came out of the tunnel.
+    pub fn received(&self, bytes: usize) {
+        self.received.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+}
+
+/// Traffic of a proxy or forward since it started.
+#[cfg_attr(feature = "napi", napi(object))]
+pub struct TrafficStats {
+    pub active_connections: u32,
+    pub total_connections: u32,
+    /// Bytes sent into the tunnel.
+    pub bytes_sent: i64,
+    /// Bytes received from the tunnel.
+    pub bytes_received: i64,
+}
+
+/// A proxy or port forward, listening on a host port or, for `forwardRemote`, a tunnel port.
+#[cfg_attr(feature = "napi", napi)]
+pub struct ProxyServer {
+    address: SocketAddr,
+    accept: AbortHandle,
+    stats: Arc<Stats>,
+}
+
+impl ProxyServer {
+    /// Address it listens on, e.g. 127.0.0.1:1080.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.address
+    }
+
+    pub fn stats(&self) -> TrafficStats {
+        TrafficStats {
+            active_connections: self.stats.active.load(Ordering::Relaxed),
+            total_connections: self.stats.total.load(Ordering::Relaxed),
+            bytes_sent: self.stats.sent.load(Ordering::Relaxed) as i64,
+            bytes_received: self.stats.received.load(Ordering::Relaxed) as i64,
+        }
+    }
+
+    /// Stop listening and drop every connection it opened.
+    pub fn close(&self) {
+        self.accept.abort();
+    }
+}
+
+/// Binds `bind` on the host and hands each client to `handler` until the server is closed.
+/// `name` labels the server in logs.
+pub(crate) async fn start<H, Fut>(bind: &str, name: &'static str, handler: H) -> Result<ProxyServer, WireShadeError>
+where
+    H: Fn(TcpStream, Arc<Stats>) -> Fut + Send + 'static,
+    Fut: Future<Output = Result<(), WireShadeError>> + Send + 'static,
+{
+    let listener = TcpListener::bind(bind).await?;
+    let address = listener.local_addr()?;
+    let stats = Arc::new(Stats::default());
+    let accept = tokio::spawn(accept_loop(listener, name, handler, stats.clone())).abort_handle();
+    log::info!("{} listening on {}", name, address);
+    Ok(ProxyServer { address, accept, stats })
+}
+
+async fn accept_loop<H, Fut>(listener: TcpListener, name: &'static str, handler: H, stats: Arc<Stats>)
+where
+    H: Fn(TcpStream, Arc<Stats>) -> Fut,
+    Fut: Future<Output = Result<(), WireShadeError>> + Send + 'static,
+{
+    // Owning the connections here means aborting the loop ends them too
+    let mut connections = JoinSet::new();
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (client, from) = match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        log::warn!("{} accept failed: {}", name, e);
+                        continue;
+                    }
+                };
+                spawn(&mut connections, name, from, &stats, handler(client, stats.clone()));
+            }
+            Some(_) = connections.join_next() => {}
+        }
+    }
+}
+
+/// Counts a new connection and runs it until it ends or the accept loop is aborted.
+fn spawn<Fut>(connections: &mut JoinSet<()>, name: &'static str, from: impl Display + Send + 'static, stats: &Arc<Stats>, connection: Fut)
+where
+    Fut: Future<Output = Result<(), WireShadeError>> + Send + 'static,
+{
+    stats.total.fetch_add(1, Ordering::Relaxed);
+    stats.active.fetch_add(1, Ordering::Relaxed);
+    let stats = stats.clone();
+    connections.spawn(async move {
+        if let Err(e) = connection.await {
+            log::debug!("{} client {}: {}", name, from, e);
+        }
+        stats.active.fetch_sub(1, Ordering::Relaxed);
+    });
+}
+
+/// Connects a host client straight to a fixed tunnel destination (`forwardLocal`).
+pub(crate) async fn forward(client: TcpStream, tunnel: Tunnel, dest_ip: Ipv4Address, dest_port: u16, stats: Arc<Stats>) -> Result<(), WireShadeError> {
+    let stream = tunnel.connect(dest_ip, dest_port).await?;
+    relay(client, stream, &stats).await?;
+    Ok(())
+}
+
+/// Where `forwardRemote` sends the connections it accepts in the tunnel.
+pub enum Target {
+    /// "host:port", resolved on every connection.
+    Tcp(String),
+    #[cfg(unix)]
+    Unix(std::path::PathBuf),
+}
+
+/// Listens on `port` at the tunnel address and connects every tunnel client to `target` on
+/// the host (`forwardRemote`). Beyond `max_connections` open at once, new clients are reset.
+pub(crate) async fn start_remote(tunnel: Tunnel, port: u16, target: Target, max_connections: Option<u32>) -> Result<ProxyServer, WireShadeError> {
+    let listener = tunnel.listen(port).await?;
+    let target = Arc::new(target);
+    start_in_tunnel(listener, "Remote forward", max_connections, move |stream, _, stats| {
+        forward_remote(stream, target.clone(), stats)
+    })
+}
+
+/// Exit-node mode: accepts peers' TCP connections to any destination in `access` and makes
+/// them from the host instead, like a NAT gateway without the kernel.
+pub(crate) async fn start_exit(tunnel: Tunnel, access: Access, max_connections: Option<u32>) -> Result<ProxyServer, WireShadeError> {
+    let listener = tunnel.listen_any(access).await?;
+    start_in_tunnel(listener, "Exit node", max_connections, |stream, dest, stats| async move {
+        let socket = TcpStream::connect(socket_addr(dest)?).await?;
+        relay(socket, stream, &stats).await?;
+        Ok(())
+    })
+}
+
+fn start_in_tunnel<H, Fut>(listener: TunnelListener, name: &'static str, max_connections: Option<u32>, handler: H) -> Result<ProxyServer, WireShadeError>
+where
+    H: Fn(TunnelStream, IpEndpoint, Arc<Stats>) -> Fut + Send + 'static,
+    Fut: Future<Output = Result<(), WireShadeError>> + Send + 'static,
+{
+    let address = socket_addr(listener.local_addr())?;
+    let stats = Arc::new(Stats::default());
+    let accept = tokio::spawn(tunnel_accept_loop(listener, name, max_connections, handler, stats.clone())).abort_handle();
+    log::info!("{} listening on {}", name, address);
+    Ok(ProxyServer { address, accept, stats })
+}
+
+/// Like `accept_loop`, for connections accepted in the tunnel. `handler` also gets the
+/// address each one was made to.
+async fn tunnel_accept_loop<H, Fut>(mut listener: TunnelListener, name: &'static str, max_connections: Option<u32>, handler: H, stats: Arc<Stats>)
+where
+    H: Fn(TunnelStream, IpEndpoint, Arc<Stats>) -> Fut,
+    Fut: Future<Output = Result<(), WireShadeError>> + Send + 'static,
+{
+    let mut connections = JoinSet::new();
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, local, from) = match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        log::warn!("{} stopped: {}", name, e);
+                        return;
+                    }
+                };
+                if max_connections.is_some_and(|max| stats.active.load(Ordering::Relaxed) >= max) {
+                    log::debug!("{} at its connection limit, refusing {}", name, from);
+                    stream.abort();
+                    continue;
+                }
+                spawn(&mut connections, name, from, &stats, handler(stream, local, stats.clone()));
+            }
+            Some(_) = connections.join_next() => {}
+        }
+    }
+}
+
+pub(crate) fn socket_addr(endpoint: IpEndpoint) -> Result<SocketAddr, WireShadeError> {
+    match endpoint.addr {
+        IpAddress::Ipv4(ip) => Ok(SocketAddr::V4(SocketAddrV4::new(ip, endpoint.port))),
+        IpAddress::Ipv6(ip) => Ok(SocketAddr::V6(SocketAddrV6::new(ip, endpoint.port, 0, 0))),
+    }
+}
+
+async fn forward_remote(stream: TunnelStream, target: Arc<Target>, stats: Arc<Stats>) -> Result<(), WireShadeError> {
+    match target.as_ref() {
+        Target::Tcp(address) => {
+            let socket = TcpStream::connect(address.as_str()).await?;
+            relay(socket, stream, &stats).await?;
+        }
+        #[cfg(unix)]
+        Target::Unix(path) => {
+            let socket = tokio::net::UnixStream::connect(path).await?;
+            relay(socket, stream, &stats).await?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,265 @@
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Unanswered handshake initiations (one every 5s) before a peer counts as unreachable.
+const MAX_UNANSWERED_HANDSHAKES: u32 = 3;
+
+#[derive(Clone, Copy)]
+pub struct ReconnectPolicy {
+    pub enabled: bool,
+    /// How long after sending data we wait for anything authenticated from the peer.
+    pub dead_peer_timeout: Duration,
+    pub delay: Duration,
+    pub max_delay: Duration,
+    pub backoff_multiplier: f64,
+    /// Fraction of the delay randomly added or removed, so many clients don't retry in lockstep.
+    pub jitter: f64,
+    /// 0 retries forever.
+    pub max_attempts: u32,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            dead_peer_timeout: Duration::from_secs(15),
+            delay: Duration::from_millis(1000),
+            max_delay: Duration::from_millis(30000),
+            backoff_multiplier: 1.5,
+            jitter: 0.2,
+            max_attempts: 10,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    fn backoff(&self, attempt: u32, rng: &mut u64) -> Duration {
+        let base = self.delay.as_secs_f64() * self.backoff_multiplier.powi(attempt.saturating_sub(1) as i32);
+        let base = base.min(self.max_delay.as_secs_f64());
+        let spread = (next_random(rng) * 2.0 - 1.0) * self.jitter;
+        Duration::from_secs_f64((base * (1.0 + spread)).max(0.0))
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum LinkState {
+    Connecting,
+    Connected,
+    Reconnecting,
+    /// No more retries of our own after `max_attempts`, and new connections to the peer are
+    /// refused. boringtun's timers still start handshakes while there is traffic for it, as
+    /// does `rebind()`; anything authenticated from the peer brings it back.
+    Failed,
+}
+
+impl LinkState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LinkState::Connecting => "connecting",
+            LinkState::Connected => "connected",
+            LinkState::Reconnecting => "reconnecting",
+            LinkState::Failed => "failed",
+        }
+    }
+}
+
+pub(crate) enum Action {
+    Idle,
+    /// Start a fresh handshake with the peer now.
+    Retry,
+}
+
+/// Liveness of one peer, driven by the tunnel loop.
+pub(crate) struct Link {
+    pub state: LinkState,
+    pub attempt: u32,
+    next_retry: Option<Instant>,
+    last_rx: Option<Instant>,
+    last_data_tx: Option<Instant>,
+    rng: u64,
+}
+
+impl Link {
+    pub fn new() -> Self {
+        let seed = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(0);
+        Self {
+            state: LinkState::Connecting,
+            attempt: 0,
+            next_retry: None,
+            last_rx: None,
+            last_data_tx: None,
+            // xorshift must not start at zero
+            rng: seed | 1,
+        }
+    }
+
+    /// Anything authenticated arrived from the peer.
+    pub fn on_receive(&mut self, now: Instant) {
+        self.last_rx = Some(now);
+        self.state = LinkState::Connected;
+        self.attempt = 0;
+        self.next_retry = None;
+    }
+
+    /// An inner packet the peer has to answer was sent to it: one with payload, or a TCP
+    /// SYN or FIN. Bare ACKs and keepalives don't count.
+    pub fn on_data_sent(&mut self, now: Instant) {
+        // Keep the oldest unanswered send: that's the one the timeout counts from.
+        if self.last_data_tx.is_none_or(|tx| self.last_rx.is_some_and(|rx| rx >= tx)) {
+            self.last_data_tx = Some(now);
+        }
+    }
+
+    fn is_dead(&self, now: Instant, handshake_attempts: u32, policy: &ReconnectPolicy) -> bool {
+        if handshake_attempts >= MAX_UNANSWERED_HANDSHAKES {
+            return true;
+        }
+        match self.last_data_tx {
+            Some(tx) => self.last_rx.is_none_or(|rx| rx < tx) && now.duration_since(tx) >= policy.dead_peer_timeout,
+            None => false,
+        }
+    }
+
+    pub fn poll(&mut self, now: Instant, handshake_attempts: u32, policy: &ReconnectPolicy) -> Action {
+        if !policy.enabled {
+            return Action::Idle;
+        }
+        match self.state {
+            LinkState::Connecting | LinkState::Connected => {
+                if self.is_dead(now, handshake_attempts, policy) {
+                    self.state = LinkState::Reconnecting;
+                    self.attempt = 0;
+                    self.last_data_tx = None;
+                    self.next_retry = Some(now);
+                }
+                Action::Idle
+            }
+            LinkState::Reconnecting => match self.next_retry {
+                Some(at) if now >= at => {
+                    self.attempt += 1;
+                    if policy.max_attempts > 0 && self.attempt > policy.max_attempts {
+                        self.state = LinkState::Failed;
+                        self.next_retry = None;
+                        return Action::Idle;
+                    }
+                    self.next_retry = Some(now + policy.backoff(self.attempt, &mut self.rng));
+                    Action::Retry
+                }
+                _ => Action::Idle,
+            },
+            LinkState::Failed => Action::Idle,
+        }
+    }
+}
+
+/// xorshift64, mapped to [0, 1). For jitter and simulated networks, not for anything secret.
+pub(crate) fn next_random(state: &mut u64) -> f64 {
+    let mut x = *state;
+    x ^= x << 13;
+    x ^= x >> 7;
+    x ^= x << 17;
+    *state = x;
+    (x >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> ReconnectPolicy {
+        ReconnectPolicy {
+            delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(1000),
+            backoff_multiplier: 2.0,
+            jitter: 0.0,
+            max_attempts: 3,
+            ..ReconnectPolicy::default()
+        }
+    }
+
+    #[test]
+    fn backoff_grows_up_to_the_cap() {
+        let (policy, mut rng) = (policy(), 1);
+        let delays: Vec<_> = (1..=6).map(|attempt| policy.backoff(attempt, &mut rng).as_millis()).collect();
+        assert_eq!(delays, [100, 200, 400, 800, 1000, 1000]);
+    }
+
+    #[test]
+    fn jitter_stays_within_its_fraction() {
+        let policy = ReconnectPolicy { jitter: 0.2, ..policy() };
+        let mut rng = 42;
+        let delays: Vec<_> = (0..1000).map(|_| policy.backoff(2, &mut rng)).collect();
+        assert!(delays.iter().all(|d| *d >= Duration::from_millis(160) && *d <= Duration::from_millis(240)));
+        // Spread both ways, not stuck at one end
+        assert!(delays.iter().any(|d| *d < Duration::from_millis(180)));
+        assert!(delays.iter().any(|d| *d > Duration::from_millis(220)));
+    }
+
+    #[test]
+    fn random_numbers_stay_in_the_unit_interval() {
+        let mut state = 1;
+        assert!((0..10_000).map(|_| next_random(&mut state)).all(|x| (0.0..1.0).contains(&x)));
+    }
+
+    #[test]
+    fn goes_stale_reconnects_and_comes_back() {
+        let (policy, start) = (policy(), Instant::now());
+        let mut link = Link::new();
+        link.on_receive(start);
+        assert_eq!(link.state, LinkState::Connected);
+
+        // Sent data goes unanswered: fine until the dead peer timeout runs out
+        let sent = start + Duration::from_secs(1);
+        link.on_data_sent(sent);
+        assert!(matches!(link.poll(sent + Duration::from_secs(14), 0, &policy), Action::Idle));
+        assert_eq!(link.state, LinkState::Connected);
+        let stale = sent + policy.dead_peer_timeout;
+        assert!(matches!(link.poll(stale, 0, &policy), Action::Idle));
+        assert_eq!(link.state, LinkState::Reconnecting);
+
+        assert!(matches!(link.poll(stale, 0, &policy), Action::Retry));
+        assert_eq!(link.attempt, 1);
+        // Nothing more until the backoff is over
+        assert!(matches!(link.poll(stale + Duration::from_millis(50), 0, &policy), Action::Idle));
+        assert!(matches!(link.poll(stale + Duration::from_millis(100), 0, &policy), Action::Retry));
+        assert_eq!(link.attempt, 2);
+
+        link.on_receive(stale + Duration::from_millis(150));
+        assert_eq!((link.state, link.attempt), (LinkState::Connected, 0));
+        assert!(matches!(link.poll(stale + Duration::from_secs(60), 0, &policy), Action::Idle));
+    }
+
+    #[test]
+    fn unanswered_handshakes_make_a_peer_stale() {
+        let mut link = Link::new();
+        link.on_receive(Instant::now());
+        link.poll(Instant::now(), MAX_UNANSWERED_HANDSHAKES, &policy());
+        assert_eq!(link.state, LinkState::Reconnecting);
+    }
+
+    #[test]
+    fn stops_after_max_attempts() {
+        let (policy, mut now) = (policy(), Instant::now());
+        let mut link = Link::new();
+        link.poll(now, MAX_UNANSWERED_HANDSHAKES, &policy);
+        let mut retries = 0;
+        for _ in 0..10 {
+            if matches!(link.poll(now, 0, &policy), Action::Retry) {
+                retries += 1;
+            }
+            now += policy.max_delay;
+        }
+        assert_eq!(retries, policy.max_attempts);
+        assert_eq!(link.state, LinkState::Failed);
+        // Anything authenticated from the peer brings it back, not only a reply to our retries
+        link.on_receive(now);
+        assert_eq!(link.state, LinkState::Connected);
+    }
+
+    #[test]
+    fn disabled_policy_never_retries() {
+        let policy = ReconnectPolicy { enabled: false, ..policy() };
+        let mut link = Link::new();
+        assert!(matches!(link.poll(Instant::now(), MAX_UNANSWERED_HANDSHAKES, &policy), Action::Idle));
+        assert_eq!(link.state, LinkState::Connecting);
+    }
+}
@@ -0,0 +1,242 @@
+//! A bad network on demand: loss, latency, jitter, duplication, reordering and bandwidth
+//! caps, decided by a seeded generator so a failing run can be repeated.
+
+use crate::reconnect::next_random;
+use crate::transport::Transport;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, VecDeque};
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::time::Sleep;
+
+/// Extra time a reordered packet is held back, for the packets behind it to overtake it.
+const REORDER_DELAY: Duration = Duration::from_millis(10);
+/// Packets waiting longer than this for a capped link are dropped, like a router's full buffer.
+const MAX_BACKLOG: Duration = Duration::from_millis(500);
+
+/// How a simulated network treats each packet, in each direction on its own.
+#[derive(Debug, Clone, Default)]
+pub struct NetworkConditions {
+    /// Fraction of packets dropped, 0 to 1.
+    pub loss: f64,
+    /// Latency added to every packet.
+    pub delay: Duration,
+    /// Random extra latency, up to this much. Packets may overtake each other.
+    pub jitter: Duration,
+    /// Fraction of packets delivered twice.
+    pub duplicate: f64,
+    /// Fraction of packets held back so that the following ones arrive first.
+    pub reorder: f64,
+    /// Bytes per second; faster traffic queues up, and is dropped once the backlog is too long.
+    pub bandwidth: Option<u64>,
+    /// The same seed makes the same decisions for the same sequence of packets.
+    pub seed: u64,
+}
+
+/// One direction through the simulated network.
+pub(crate) struct Path<T> {
+    conditions: NetworkConditions,
+    rng: u64,
+    /// When the link is done sending what it already took, with a bandwidth cap.
+    free_at: Instant,
+    queue: BinaryHeap<Reverse<Queued<T>>>,
+    /// Keeps packets due at the same instant in order.
+    sequence: u64,
+}
+
+struct Queued<T> {
+    due: Instant,
+    sequence: u64,
+    item: T,
+}
+
+impl<T> PartialEq for Queued<T> {
+    fn eq(&self, other: &Self) -> bool {
+        (self.due, self.sequence) == (other.due, other.sequence)
+    }
+}
+
+impl<T> Eq for Queued<T> {}
+
+impl<T> PartialOrd for Queued<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Queued<T> {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (self.due, self.sequence).cmp(&(other.due, other.sequence))
+    }
+}
+
+impl<T: Clone> Path<T> {
+    /// `salt` tells the directions apart, so they don't lose the same packets.
+    pub fn new(conditions: NetworkConditions, salt: u64) -> Self {
+        // xorshift must not start at zero
+        let rng = (conditions.seed ^ salt).wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1;
+        Self { conditions, rng, free_at: Instant::now(), queue: BinaryHeap::new(), sequence: 0 }
+    }
+
+    /// Hands a packet of `len` bytes to the network, which may drop, delay or copy it.
+    pub fn push(&mut self, now: Instant, item: T, len: usize) {
+        if next_random(&mut self.rng) < self.conditions.loss {
+            return;
+        }
+        let mut sent = now;
+        if let Some(bandwidth) = self.conditions.bandwidth.filter(|&b| b > 0) {
+            let start = self.free_at.max(now);
+            if start - now > MAX_BACKLOG {
+                return;
+            }
+            self.free_at = start + Duration::from_secs_f64(len as f64 / bandwidth as f64);
+            sent = self.free_at;
+        }
+        let copies = if next_random(&mut self.rng) < self.conditions.duplicate { 2 } else { 1 };
+        for _ in 0..copies {
+            let mut due = sent + self.conditions.delay + self.conditions.jitter.mul_f64(next_random(&mut self.rng));
+            if next_random(&mut self.rng) < self.conditions.reorder {
+                due += REORDER_DELAY;
+            }
+            self.sequence += 1;
+            self.queue.push(Reverse(Queued { due, sequence: self.sequence, item: item.clone() }));
+        }
+    }
+
+    /// The next packet whose time has come.
+    pub fn pop_due(&mut self, now: Instant) -> Option<T> {
+        if self.queue.peek()?.0.due > now {
+            return None;
+        }
+        self.queue.pop().map(|Reverse(queued)| queued.item)
+    }
+
+    pub fn next_due(&self) -> Option<Instant> {
+        self.queue.peek().map(|Reverse(queued)| queued.due)
+    }
+
+    /// Moves everything waiting in `queue` onto the path.
+    pub fn push_all(&mut self, now: Instant, queue: &mut VecDeque<T>)
+    where
+        T: AsRef<[u8]>,
+    {
+        for item in queue.drain(..) {
+            let len = item.as_ref().len();
+            self.push(now, item, len);
+        }
+    }
+}
+
+/// The simulated network on decrypted packets, between WireGuard and the TCP stack.
+pub(crate) struct PacketPaths {
+    pub inbound: Path<Vec<u8>>,
+    pub outbound: Path<Vec<u8>>,
+}
+
+impl PacketPaths {
+    pub fn new(conditions: NetworkConditions) -> Self {
+        Self { inbound: Path::new(conditions.clone(), 2), outbound: Path::new(conditions, 1) }
+    }
+}
+
+type Datagram = (Vec<u8>, SocketAddr);
+
+struct Paths {
+    outbound: Path<Datagram>,
+    inbound: Path<Datagram>,
+    /// Wakes the receiving task when the next held datagram is due.
+    timer: Option<Pin<Box<Sleep>>>,
+}
+
+impl Paths {
+    fn next_due(&self) -> Option<Instant> {
+        match (self.outbound.next_due(), self.inbound.next_due()) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+}
+
+/// Wraps another transport and puts the WireGuard datagrams through a simulated network,
+/// both ways. For tests and for reproducing trouble seen in the field.
+pub struct SimulatedTransport<T> {
+    inner: T,
+    paths: Mutex<Paths>,
+}
+
+impl<T: Transport> SimulatedTransport<T> {
+    pub fn new(inner: T, conditions: NetworkConditions) -> Self {
+        let paths = Paths {
+            outbound: Path::new(conditions.clone(), 1),
+            inbound: Path::new(conditions, 2),
+            timer: None,
+        };
+        Self { inner, paths: Mutex::new(paths) }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Paths> {
+        self.paths.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn send_due(&self, outbound: &mut Path<Datagram>, now: Instant) {
+        while let Some((datagram, to)) = outbound.pop_due(now) {
+            // Failures are just more loss
+            let _ = self.inner.try_send_to(&datagram, to);
+        }
+    }
+}
+
+impl<T: Transport> Transport for SimulatedTransport<T> {
+    fn try_send_to(&self, datagram: &[u8], to: SocketAddr) -> io::Result<usize> {
+        let now = Instant::now();
+        let mut paths = self.lock();
+        paths.outbound.push(now, (datagram.to_vec(), to), datagram.len());
+        self.send_due(&mut paths.outbound, now);
+        Ok(datagram.len())
+    }
+
+    // Delayed datagrams also leave from here: the tunnel always waits to receive.
+    fn poll_recv_from(&self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<(usize, SocketAddr)>> {
+        let mut paths = self.lock();
+        loop {
+            let now = Instant::now();
+            self.send_due(&mut paths.outbound, now);
+            if let Some((datagram, from)) = paths.inbound.pop_due(now) {
+                let len = datagram.len().min(buf.len());
+                buf[..len].copy_from_slice(&datagram[..len]);
+                return Poll::Ready(Ok((len, from)));
+            }
+            match self.inner.poll_recv_from(cx, buf) {
+                Poll::Ready(Ok((len, from))) => {
+                    paths.inbound.push(now, (buf[..len].to_vec(), from), len);
+                    continue;
+                }
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => {}
+            }
+            let Some(due) = paths.next_due() else { return Poll::Pending };
+            let timer = paths.timer.get_or_insert_with(|| Box::pin(tokio::time::sleep_until(due.into())));
+            timer.as_mut().reset(due.into());
+            if timer.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+        }
+    }
+
+    fn poll_send_ready(&self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner.poll_send_ready(cx)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn rebind(&mut self, port: u16) -> io::Result<()> {
+        self.inner.rebind(port)
+    }
+}
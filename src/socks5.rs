@@ -0,0 +1,270 @@
+use crate::error::WireShadeError;
+use crate::proxy::{ProxyAuth, Stats};
+use crate::stream::relay;
+use crate::tunnel::Tunnel;
+use smoltcp::wire::{IpAddress, Ipv4Address, Ipv6Address};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const VERSION: u8 = 5;
+const NO_AUTH: u8 = 0x00;
+const USER_PASS: u8 = 0x02;
+const NO_ACCEPTABLE_METHOD: u8 = 0xFF;
+
+const CMD_CONNECT: u8 = 1;
+
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+// Reply codes from RFC 1928
+const SUCCEEDED: u8 = 0x00;
+const GENERAL_FAILURE: u8 = 0x01;
+const NETWORK_UNREACHABLE: u8 = 0x03;
+const HOST_UNREACHABLE: u8 = 0x04;
+const CONNECTION_REFUSED: u8 = 0x05;
+const COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const ADDRESS_TYPE_NOT_SUPPORTED: u8 = 0x08;
+
+/// Serves one SOCKS5 client, tunneling its CONNECT request.
+pub(crate) async fn handle(mut client: TcpStream, tunnel: Tunnel, auth: Arc<Option<ProxyAuth>>, stats: Arc<Stats>) -> Result<(), WireShadeError> {
+    if !negotiate(&mut client, auth.as_ref().as_ref()).await? {
+        return Ok(());
+    }
+    let Some((command, address, port)) = read_request(&mut client).await? else {
+        return Ok(());
+    };
+    // BIND and UDP ASSOCIATE need a listening or UDP socket in the tunnel
+    if command != CMD_CONNECT {
+        return reply(&mut client, COMMAND_NOT_SUPPORTED).await;
+    }
+    let dest = match address {
+        Address::Ip(ip) => ip,
+        Address::Domain(name) => match tunnel.resolve(&name).await {
+            Ok(ip) => IpAddress::Ipv4(ip),
+            Err(WireShadeError::NoDnsServer) => {
+                log::warn!("SOCKS5: can't resolve names without a DNS server for the tunnel");
+                reply(&mut client, GENERAL_FAILURE).await?;
+                return Err(WireShadeError::NoDnsServer);
+            }
+            Err(_) => return reply(&mut client, HOST_UNREACHABLE).await,
+        },
+    };
+
+    let stream = match tunnel.connect_ip(dest, port).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            let code = match e {
+                WireShadeError::ConnectionRefused => CONNECTION_REFUSED,
+                // An IPv6 destination, but the tunnel has no IPv6 address
+                WireShadeError::Io(ref e) if e.kind() == std::io::ErrorKind::AddrNotAvailable => ADDRESS_TYPE_NOT_SUPPORTED,
+                WireShadeError::NoRoute(_) => NETWORK_UNREACHABLE,
+                WireShadeError::Timeout | WireShadeError::HandshakeTimeout => HOST_UNREACHABLE,
+                _ => GENERAL_FAILURE,
+            };
+            reply(&mut client, code).await?;
+            return Err(e);
+        }
+    };
+    log::debug!(target: "wireshade::tcp", "SOCKS5 CONNECT to {}:{}", dest, port);
+    reply(&mut client, SUCCEEDED).await?;
+    relay(client, stream, &stats).await?;
+    Ok(())
+}
+
+enum Address {
+    Ip(IpAddress),
+    /// A name to resolve in the tunnel.
+    Domain(String),
+}
+
+/// Greeting and, if configured, authentication. Returns false if the client offers no
+/// method we accept, after telling it so.
+async fn negotiate<S>(client: &mut S, auth: Option<&ProxyAuth>) -> Result<bool, WireShadeError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // Version, then the authentication methods the client offers
+    let [version, count] = read_array(client).await?;
+    if version != VERSION {
+        return Err(WireShadeError::InvalidArgument(format!("SOCKS version {}", version)));
+    }
+    let mut methods = vec![0; count as usize];
+    client.read_exact(&mut methods).await?;
+    let method = if auth.is_some() { USER_PASS } else { NO_AUTH };
+    if !methods.contains(&method) {
+        client.write_all(&[VERSION, NO_ACCEPTABLE_METHOD]).await?;
+        return Ok(false);
+    }
+    client.write_all(&[VERSION, method]).await?;
+    if let Some(auth) = auth {
+        authenticate(client, auth).await?;
+    }
+    Ok(true)
+}
+
+/// Command, destination and port of the request. `None` for an address type we don't
+/// know, which has been answered already.
+async fn read_request<S>(client: &mut S) -> Result<Option<(u8, Address, u16)>, WireShadeError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let [_, command, _, address_type] = read_array(client).await?;
+    let address = match address_type {
+        ATYP_IPV4 => Address::Ip(IpAddress::Ipv4(Ipv4Address::from_octets(read_array(client).await?))),
+        ATYP_IPV6 => Address::Ip(IpAddress::Ipv6(Ipv6Address::from_octets(read_array(client).await?))),
+        ATYP_DOMAIN => {
+            let [len] = read_array(client).await?;
+            let mut name = vec![0; len as usize];
+            client.read_exact(&mut name).await?;
+            let name = String::from_utf8_lossy(&name).into_owned();
+            match name.parse() {
+                Ok(ip) => Address::Ip(ip),
+                Err(_) => Address::Domain(name),
+            }
+        }
+        _ => {
+            reply(client, ADDRESS_TYPE_NOT_SUPPORTED).await?;
+            return Ok(None);
+        }
+    };
+    let port = u16::from_be_bytes(read_array(client).await?);
+    Ok(Some((command, address, port)))
+}
+
+/// Username/password sub-negotiation (RFC 1929).
+async fn authenticate<S>(client: &mut S, auth: &ProxyAuth) -> Result<(), WireShadeError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let [_, len] = read_array(client).await?;
+    let mut username = vec![0; len as usize];
+    client.read_exact(&mut username).await?;
+    let [len] = read_array(client).await?;
+    let mut password = vec![0; len as usize];
+    client.read_exact(&mut password).await?;
+
+    if username != auth.username.as_bytes() || password != auth.password.as_bytes() {
+        client.write_all(&[1, 1]).await?;
+        return Err(WireShadeError::InvalidArgument("SOCKS5 credentials".to_string()));
+    }
+    client.write_all(&[1, 0]).await?;
+    Ok(())
+}
+
+/// Replies with an unspecified bound address; clients only look at the code.
+async fn reply<W: AsyncWrite + Unpin>(client: &mut W, code: u8) -> Result<(), WireShadeError> {
+    client.write_all(&[VERSION, code, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0]).await?;
+    Ok(())
+}
+
+async fn read_array<R: AsyncRead + Unpin, const N: usize>(client: &mut R) -> std::io::Result<[u8; N]> {
+    let mut buf = [0; N];
+    client.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{duplex, DuplexStream};
+
+    /// The proxy's end of a connection the client already sent `input` on and closed.
+    async fn client_sent(input: &[u8]) -> (DuplexStream, DuplexStream) {
+        let (mut client, proxy) = duplex(1024);
+        client.write_all(input).await.unwrap();
+        client.shutdown().await.unwrap();
+        (client, proxy)
+    }
+
+    async fn replies(mut client: DuplexStream) -> Vec<u8> {
+        let mut replies = Vec::new();
+        client.read_to_end(&mut replies).await.unwrap();
+        replies
+    }
+
+    fn auth() -> ProxyAuth {
+        ProxyAuth { username: "me".to_string(), password: "secret".to_string() }
+    }
+
+    #[tokio::test]
+    async fn greeting_picks_a_method() {
+        let (client, mut proxy) = client_sent(&[5, 2, 0, 2]).await;
+        assert!(negotiate(&mut proxy, None).await.unwrap());
+        drop(proxy);
+        assert_eq!(replies(client).await, [5, 0]);
+
+        let (client, mut proxy) = client_sent(&[5, 1, 0]).await;
+        assert!(!negotiate(&mut proxy, Some(&auth())).await.unwrap());
+        drop(proxy);
+        assert_eq!(replies(client).await, [5, 0xff]);
+
+        let (_client, mut proxy) = client_sent(&[4, 1, 0]).await;
+        assert!(negotiate(&mut proxy, None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn checks_credentials() {
+        let (client, mut proxy) = client_sent(b"\x05\x01\x02\x01\x02me\x06secret").await;
+        assert!(negotiate(&mut proxy, Some(&auth())).await.unwrap());
+        drop(proxy);
+        assert_eq!(replies(client).await, [5, 2, 1, 0]);
+
+        let (client, mut proxy) = client_sent(b"\x05\x01\x02\x01\x02me\x05wrong").await;
+        assert!(negotiate(&mut proxy, Some(&auth())).await.is_err());
+        drop(proxy);
+        assert_eq!(replies(client).await, [5, 2, 1, 1]);
+    }
+
+    #[tokio::test]
+    async fn reads_connect_for_each_address_type() {
+        let (_client, mut proxy) = client_sent(&[5, 1, 0, 1, 10, 0, 0, 1, 0, 80]).await;
+        let request = read_request(&mut proxy).await.unwrap();
+        let expected = IpAddress::Ipv4(Ipv4Address::new(10, 0, 0, 1));
+        assert!(matches!(request, Some((CMD_CONNECT, Address::Ip(ip), 80)) if ip == expected));
+
+        let mut ipv6 = vec![5, 1, 0, 4, 0xfd, 0];
+        ipv6.extend_from_slice(&[0; 13]);
+        ipv6.extend_from_slice(&[1, 1, 0xbb]);
+        let (_client, mut proxy) = client_sent(&ipv6).await;
+        let request = read_request(&mut proxy).await.unwrap();
+        let expected = IpAddress::Ipv6(Ipv6Address::new(0xfd00, 0, 0, 0, 0, 0, 0, 1));
+        assert!(matches!(request, Some((CMD_CONNECT, Address::Ip(ip), 443)) if ip == expected));
+
+        let (_client, mut proxy) = client_sent(b"\x05\x01\x00\x03\x0bexample.com\x00\x50").await;
+        let request = read_request(&mut proxy).await.unwrap();
+        assert!(matches!(request, Some((CMD_CONNECT, Address::Domain(name), 80)) if name == "example.com"));
+
+        // Addresses sent as names don't need a lookup
+        let (_client, mut proxy) = client_sent(b"\x05\x01\x00\x03\x0810.0.0.1\x00\x50").await;
+        let request = read_request(&mut proxy).await.unwrap();
+        assert!(matches!(request, Some((_, Address::Ip(IpAddress::Ipv4(_)), 80))));
+    }
+
+    #[tokio::test]
+    async fn refuses_unknown_address_types() {
+        let (client, mut proxy) = client_sent(&[5, 1, 0, 9]).await;
+        assert!(read_request(&mut proxy).await.unwrap().is_none());
+        drop(proxy);
+        assert_eq!(replies(client).await[..2], [5, ADDRESS_TYPE_NOT_SUPPORTED]);
+    }
+
+    #[tokio::test]
+    async fn truncated_input_is_an_error() {
+        for input in [&[5][..], &[5, 2, 0]] {
+            let (_client, mut proxy) = client_sent(input).await;
+            assert!(negotiate(&mut proxy, None).await.is_err(), "{:?}", input);
+        }
+        let (_client, mut proxy) = client_sent(b"\x05\x01\x02\x01\x02me\x06sec").await;
+        assert!(negotiate(&mut proxy, Some(&auth())).await.is_err());
+
+        let request = [5, 1, 0, 1, 10, 0, 0, 1, 0, 80];
+        for len in 0..request.len() {
+            let (_client, mut proxy) = client_sent(&request[..len]).await;
+            assert!(read_request(&mut proxy).await.is_err(), "{} bytes", len);
+        }
+        let (_client, mut proxy) = client_sent(b"\x05\x01\x00\x03\x0bexample").await;
+        assert!(read_request(&mut proxy).await.is_err());
+    }
+}
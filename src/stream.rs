@@ -0,0 +1,225 @@
+use crate::error::WireShadeError;
+use crate::proxy::Stats;
+use crate::tunnel::Tunnel;
+use crate::NetworkCommand;
+use bytes::{Bytes, BytesMut};
+use smoltcp::wire::IpEndpoint;
+use std::future::{poll_fn, Future};
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+
+/// Events buffered per native connection. The loop stops reading a socket while its
+/// channel is (almost) full, so a slow consumer backs up into the TCP window.
+pub(crate) const STREAM_EVENTS: usize = 16;
+/// Accepted connections queued per listener before new ones are refused.
+pub(crate) const ACCEPT_BACKLOG: usize = 16;
+/// Bytes read from a host socket per tunnel write.
+const RELAY_CHUNK: usize = 16 * 1024;
+
+/// What the tunnel loop reports to a connection driven from Rust.
+pub(crate) enum StreamEvent {
+    Connected,
+    Data(Bytes),
+    /// The remote end sent FIN; writing is still possible.
+    Eof,
+    Closed(Option<WireShadeError>),
+}
+
+/// A connection the tunnel loop accepted for a `TunnelListener`.
+pub(crate) struct Accepted {
+    pub id: u32,
+    pub events: mpsc::Receiver<StreamEvent>,
+    pub local: IpEndpoint,
+    pub remote: IpEndpoint,
+}
+
+/// Connections arriving on a tunnel port. Dropping it stops listening; accepted
+/// connections stay open.
+pub struct TunnelListener {
+    local: IpEndpoint,
+    accepted: mpsc::Receiver<Accepted>,
+    tunnel: Tunnel,
+}
+
+impl TunnelListener {
+    pub(crate) fn new(local: IpEndpoint, accepted: mpsc::Receiver<Accepted>, tunnel: Tunnel) -> Self {
+        Self { local, accepted, tunnel }
+    }
+
+    /// Tunnel address and port it listens on.
+    pub fn local_addr(&self) -> IpEndpoint {
+        self.local
+    }
+
+    /// Waits for the next connection. Returns it with the address it was made to (the
+    /// destination, in exit-node mode) and the peer's address.
+    pub async fn accept(&mut self) -> Result<(TunnelStream, IpEndpoint, IpEndpoint), WireShadeError> {
+        let Some(Accepted { id, events, local, remote }) = self.accepted.recv().await else {
+            return Err(self.tunnel.state.closed_error());
+        };
+        Ok((TunnelStream::new(id, events, self.tunnel.clone()), local, remote))
+    }
+}
+
+impl Drop for TunnelListener {
+    fn drop(&mut self) {
+        let cmd = match self.local.port {
+            0 => NetworkCommand::StopExit,
+            port => NetworkCommand::Unlisten { port },
+        };
+        // If the queue is full the loop still notices the closed accept channel
+        let _ = self.tunnel.cmd_tx.try_send(cmd);
+    }
+}
+
+/// A tunnel TCP connection. Split it to read and write from different tasks.
+pub struct TunnelStream {
+    reader: StreamReader,
+    writer: StreamWriter,
+}
+
+impl TunnelStream {
+    pub(crate) fn new(id: u32, events: mpsc::Receiver<StreamEvent>, tunnel: Tunnel) -> Self {
+        Self {
+            reader: StreamReader { events, tunnel: tunnel.clone(), done: false },
+            writer: StreamWriter { id, tunnel, shut_down: false },
+        }
+    }
+
+    /// Waits for the handshake of a connection we opened.
+    pub(crate) async fn connected(mut self) -> Result<Self, WireShadeError> {
+        match self.reader.events.recv().await {
+            Some(StreamEvent::Connected) => Ok(self),
+            Some(StreamEvent::Closed(reason)) => Err(reason.unwrap_or(WireShadeError::ConnectionReset)),
+            // Nothing else is sent before the handshake completes
+            Some(_) => Ok(self),
+            None => Err(self.reader.tunnel.state.closed_error()),
+        }
+    }
+
+    pub fn into_split(self) -> (StreamReader, StreamWriter) {
+        (self.reader, self.writer)
+    }
+
+    pub fn write(&self, data: impl Into<Bytes>) -> impl Future<Output = Result<(), WireShadeError>> + Send + 'static {
+        self.writer.write(data)
+    }
+
+    /// The connection id and the receiving half, for callers that write by id. Unlike
+    /// dropping a `StreamWriter`, this doesn't close the sending side.
+    #[cfg(feature = "napi")]
+    pub(crate) fn into_reader(self) -> (u32, StreamReader) {
+        let TunnelStream { reader, mut writer } = self;
+        writer.shut_down = true;
+        (writer.id, reader)
+    }
+
+    /// Resets the connection right away, dropping anything not sent yet.
+    pub fn abort(self) {
+        let _ = self.writer.tunnel.cmd_tx.try_send(NetworkCommand::Abort { connection_id: self.writer.id });
+    }
+}
+
+/// Receiving half. Dropping it before the end of the data closes the connection like
+/// closing a socket: with a reset if received data is left unread, else with a FIN once
+/// everything written is out.
+pub struct StreamReader {
+    events: mpsc::Receiver<StreamEvent>,
+    tunnel: Tunnel,
+    /// The remote end is done; later reads return `None` without asking the channel.
+    done: bool,
+}
+
+impl StreamReader {
+    /// Next chunk of data, `None` once the remote end is done sending.
+    pub async fn read(&mut self) -> Result<Option<Bytes>, WireShadeError> {
+        poll_fn(|cx| self.poll_read(cx)).await
+    }
+
+    /// Polling form of `read`.
+    pub(crate) fn poll_read(&mut self, cx: &mut Context<'_>) -> Poll<Result<Option<Bytes>, WireShadeError>> {
+        if self.done {
+            return Poll::Ready(Ok(None));
+        }
+        loop {
+            let event = match ready!(self.events.poll_recv(cx)) {
+                Some(StreamEvent::Data(data)) => Ok(Some(data)),
+                Some(StreamEvent::Connected) => continue,
+                Some(StreamEvent::Eof) | Some(StreamEvent::Closed(None)) => Ok(None),
+                Some(StreamEvent::Closed(Some(reason))) => Err(reason),
+                None => Err(self.tunnel.state.closed_error()),
+            };
+            self.done = !matches!(event, Ok(Some(_)));
+            return Poll::Ready(event);
+        }
+    }
+}
+
+/// Sending half. Dropping it without `shutdown` closes the sending side all the same.
+pub struct StreamWriter {
+    id: u32,
+    tunnel: Tunnel,
+    shut_down: bool,
+}
+
+impl StreamWriter {
+    /// Resolves once `data` is in the TCP send buffer, so writes can't run ahead of the tunnel.
+    /// The future doesn't borrow the writer and can be kept to poll later.
+    pub fn write(&self, data: impl Into<Bytes>) -> impl Future<Output = Result<(), WireShadeError>> + Send + 'static {
+        let (tunnel, connection_id, data) = (self.tunnel.clone(), self.id, data.into());
+        async move { tunnel.write_data(connection_id, data).await }
+    }
+
+    /// Sends FIN after everything written so far; the connection can still receive.
+    pub fn shutdown(&mut self) -> impl Future<Output = Result<(), WireShadeError>> + Send + 'static {
+        self.shut_down = true;
+        let (tunnel, connection_id) = (self.tunnel.clone(), self.id);
+        async move { tunnel.command(NetworkCommand::Close { connection_id }).await }
+    }
+}
+
+impl Drop for StreamWriter {
+    fn drop(&mut self) {
+        if !self.shut_down {
+            self.tunnel.command_on_drop(NetworkCommand::Close { connection_id: self.id });
+        }
+    }
+}
+
+/// Copies between a host socket and a tunnel connection until both directions are done,
+/// passing half-closes through. Returns the bytes sent into and received from the tunnel.
+pub(crate) async fn relay<S>(socket: S, stream: TunnelStream, stats: &Stats) -> Result<(u64, u64), WireShadeError>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    let (mut host_rx, mut host_tx) = tokio::io::split(socket);
+    let (mut reader, mut writer) = stream.into_split();
+
+    let upload = async {
+        let mut sent = 0u64;
+        let mut buf = BytesMut::with_capacity(RELAY_CHUNK);
+        loop {
+            buf.reserve(RELAY_CHUNK);
+            let n = host_rx.read_buf(&mut buf).await?;
+            if n == 0 {
+                writer.shutdown().await?;
+                return Ok::<_, WireShadeError>(sent);
+            }
+            writer.write(buf.split().freeze()).await?;
+            stats.sent(n);
+            sent += n as u64;
+        }
+    };
+    let download = async {
+        let mut received = 0u64;
+        while let Some(data) = reader.read().await? {
+            host_tx.write_all(&data).await?;
+            stats.received(data.len());
+            received += data.len() as u64;
+        }
+        host_tx.shutdown().await?;
+        Ok::<_, WireShadeError>(received)
+    };
+    tokio::try_join!(upload, download)
+}
@@ -0,0 +1,83 @@
+use crate::error::WireShadeError;
+use std::future::{poll_fn, Future};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::pin::pin;
+use std::sync::Mutex;
+use std::task::Poll;
+
+/// Called once with `TunnelFailed` if the tunnel task dies.
+pub type FatalCallback = Box<dyn Fn(WireShadeError) + Send>;
+
+/// Shared between the tunnel task and its handles: why the task died, and who to tell.
+#[derive(Default)]
+pub(crate) struct TaskState {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    cause: Option<String>,
+    on_fatal: Option<FatalCallback>,
+}
+
+impl TaskState {
+    /// The error for a call that found the task gone.
+    pub fn closed_error(&self) -> WireShadeError {
+        match &self.lock().cause {
+            Some(cause) => WireShadeError::TunnelFailed(cause.clone()),
+            None => WireShadeError::TunnelClosed,
+        }
+    }
+
+    /// Called right away if the task has already died.
+    pub fn set_fatal_callback(&self, callback: FatalCallback) {
+        let mut inner = self.lock();
+        if let Some(cause) = &inner.cause {
+            callback(WireShadeError::TunnelFailed(cause.clone()));
+        }
+        inner.on_fatal = Some(callback);
+    }
+
+    fn fail(&self, cause: String) {
+        log::error!("Tunnel task failed: {}", cause);
+        let mut inner = self.lock();
+        if let Some(callback) = &inner.on_fatal {
+            callback(WireShadeError::TunnelFailed(cause.clone()));
+        }
+        inner.cause = Some(cause);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        // Nothing panics while holding the lock, but don't let a poisoned one take us down either.
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Runs the tunnel loop, turning an error or a panic into the instance's fatal error.
+/// The cause is recorded before the loop's state (and with it every pending response
+/// channel) is dropped, so waiting callers are rejected with the real reason.
+pub(crate) async fn supervise<F>(fut: F, state: std::sync::Arc<TaskState>)
+where
+    F: Future<Output = Result<(), WireShadeError>>,
+{
+    let mut fut = pin!(fut);
+    let outcome = poll_fn(|cx| match catch_unwind(AssertUnwindSafe(|| fut.as_mut().poll(cx))) {
+        Ok(Poll::Pending) => Poll::Pending,
+        Ok(Poll::Ready(res)) => Poll::Ready(res.map_err(|e| e.to_string())),
+        Err(panic) => Poll::Ready(Err(panic_message(panic.as_ref()))),
+    })
+    .await;
+
+    if let Err(cause) = outcome {
+        state.fail(cause);
+    }
+}
+
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    let message = payload
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_string());
+    format!("panicked: {}", message)
+}
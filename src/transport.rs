@@ -0,0 +1,178 @@
+//! Carriers for the encrypted WireGuard datagrams: real UDP by default, or anything that
+//! can move datagrams between two addresses.
+
+use socket2::{Domain, Protocol, Socket, Type};
+use std::future::poll_fn;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+
+/// Datagrams a `CallbackTransport` holds for the tunnel before dropping more, like a
+/// socket's receive buffer.
+const INBOUND_QUEUE: usize = 1024;
+/// Kernel buffer size asked for on UDP sockets. Defaults are a few hundred KiB, and the
+/// bursts of several busy connections overflow them while the loop is on other work.
+const UDP_BUFFER: usize = 4 << 20;
+
+/// Moves WireGuard datagrams between the tunnel and its peers. Peers are told apart by
+/// the address datagrams come from; it needn't be a real one.
+pub trait Transport: Send + Sync + 'static {
+    /// Sends a datagram without waiting. It may be lost on the way, like over UDP.
+    fn try_send_to(&self, datagram: &[u8], to: SocketAddr) -> io::Result<usize>;
+
+    /// Receives the next datagram into `buf`, with the address it came from.
+    fn poll_recv_from(&self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<(usize, SocketAddr)>>;
+
+    /// Ready once `try_send_to` can be expected to succeed.
+    fn poll_send_ready(&self, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+
+    /// Starts over on a fresh socket bound to `port` (0 for any), after a network change
+    /// or when receiving fails. Carriers without sockets have nothing to redo.
+    fn rebind(&mut self, _port: u16) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Waits for the next datagram on `transport`.
+pub(crate) async fn recv_from(transport: &dyn Transport, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+    poll_fn(|cx| transport.poll_recv_from(cx, buf)).await
+}
+
+pub(crate) async fn send_ready(transport: &dyn Transport) -> io::Result<()> {
+    poll_fn(|cx| transport.poll_send_ready(cx)).await
+}
+
+/// WireGuard over UDP, on all interfaces.
+pub struct UdpTransport {
+    socket: UdpSocket,
+}
+
+impl UdpTransport {
+    /// Binds `port` (0 for an ephemeral one). Must run inside a Tokio runtime.
+    pub fn bind(port: u16) -> io::Result<Self> {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        // The system may cap these; smaller buffers only mean more loss under load
+        let _ = socket.set_recv_buffer_size(UDP_BUFFER);
+        let _ = socket.set_send_buffer_size(UDP_BUFFER);
+        socket.bind(&SocketAddr::from(([0, 0, 0, 0], port)).into())?;
+        socket.set_nonblocking(true)?;
+        Ok(Self { socket: UdpSocket::from_std(socket.into())? })
+    }
+}
+
+impl Transport for UdpTransport {
+    fn try_send_to(&self, datagram: &[u8], to: SocketAddr) -> io::Result<usize> {
+        self.socket.try_send_to(datagram, to)
+    }
+
+    fn poll_recv_from(&self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<(usize, SocketAddr)>> {
+        let mut buf = tokio::io::ReadBuf::new(buf);
+        let from = std::task::ready!(self.socket.poll_recv_from(cx, &mut buf))?;
+        Poll::Ready(Ok((buf.filled().len(), from)))
+    }
+
+    fn poll_send_ready(&self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.socket.poll_send_ready(cx)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Keeps the socket if it's already on the fixed `port`: a second one can't bind there
+    /// while it's open, and releasing it first could lose the port to someone else.
+    fn rebind(&mut self, port: u16) -> io::Result<()> {
+        if port != 0 && self.socket.local_addr()?.port() == port {
+            return Ok(());
+        }
+        // The old socket stays if this fails
+        *self = Self::bind(port)?;
+        Ok(())
+    }
+}
+
+type SendFn = Box<dyn Fn(&[u8], SocketAddr) -> io::Result<()> + Send + Sync>;
+
+/// Datagrams go to a function; received ones are handed in through its `Inbound`. For
+/// tunneling WireGuard over a carrier of your own.
+pub struct CallbackTransport {
+    local: SocketAddr,
+    send: SendFn,
+    inbound: Mutex<mpsc::Receiver<(Vec<u8>, SocketAddr)>>,
+}
+
+/// Hands received datagrams to a `CallbackTransport`. Clones feed the same transport.
+#[derive(Clone)]
+pub struct Inbound {
+    tx: mpsc::Sender<(Vec<u8>, SocketAddr)>,
+}
+
+impl Inbound {
+    /// Delivers `datagram` as coming from `from`. Returns false if it was dropped because
+    /// the tunnel is behind or gone.
+    pub fn deliver(&self, datagram: Vec<u8>, from: SocketAddr) -> bool {
+        self.tx.try_send((datagram, from)).is_ok()
+    }
+}
+
+impl CallbackTransport {
+    /// A transport reporting `local` as its address and sending with `send`.
+    pub fn new<F>(local: SocketAddr, send: F) -> (Self, Inbound)
+    where
+        F: Fn(&[u8], SocketAddr) -> io::Result<()> + Send + Sync + 'static,
+    {
+        let (tx, rx) = mpsc::channel(INBOUND_QUEUE);
+        (Self { local, send: Box::new(send), inbound: Mutex::new(rx) }, Inbound { tx })
+    }
+
+    /// Two transports wired to each other, at addresses `a` and `b`, to run two tunnels
+    /// back to back in one process. Datagrams to any other address are lost.
+    pub fn pair(a: SocketAddr, b: SocketAddr) -> (Self, Self) {
+        let (to_a_tx, to_a) = mpsc::channel(INBOUND_QUEUE);
+        let (to_b_tx, to_b) = mpsc::channel(INBOUND_QUEUE);
+        let (inbound_a, inbound_b) = (Inbound { tx: to_a_tx }, Inbound { tx: to_b_tx });
+        let side = |local, inbound, peer, outbound: Inbound| Self {
+            local,
+            send: Box::new(move |datagram: &[u8], to| {
+                if to == peer {
+                    outbound.deliver(datagram.to_vec(), local);
+                }
+                Ok(())
+            }),
+            inbound: Mutex::new(inbound),
+        };
+        (side(a, to_a, b, inbound_b), side(b, to_b, a, inbound_a))
+    }
+}
+
+impl Transport for CallbackTransport {
+    fn try_send_to(&self, datagram: &[u8], to: SocketAddr) -> io::Result<usize> {
+        (self.send)(datagram, to)?;
+        Ok(datagram.len())
+    }
+
+    fn poll_recv_from(&self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<(usize, SocketAddr)>> {
+        let mut inbound = self.inbound.lock().unwrap_or_else(|e| e.into_inner());
+        match std::task::ready!(inbound.poll_recv(cx)) {
+            Some((datagram, from)) => {
+                // Like UDP, the part that doesn't fit is lost
+                let len = datagram.len().min(buf.len());
+                buf[..len].copy_from_slice(&datagram[..len]);
+                Poll::Ready(Ok((len, from)))
+            }
+            // Every `Inbound` is gone, nothing can arrive anymore
+            None => Poll::Pending,
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local)
+    }
+}
@@ -0,0 +1,307 @@
+use crate::capture::{Capture, CaptureConfig};
+use crate::error::WireShadeError;
+use crate::exit::ExitListener;
+use crate::http_proxy::{self, HttpProxyConfig};
+use crate::packet::{self, PacketFilter, PacketHooks};
+use crate::peer::PeerSpec;
+use crate::ping::{PingConfig, PingResult};
+use crate::proxy::{self, Access, ProxyAuth, ProxyServer, Target};
+use crate::reconnect::ReconnectPolicy;
+use crate::simulator::NetworkConditions;
+use crate::socks5;
+use crate::stream::{TunnelListener, TunnelStream, ACCEPT_BACKLOG, STREAM_EVENTS};
+use crate::supervisor::TaskState;
+use crate::transport::Transport;
+use crate::{ConnectionContext, EndpointChange, NetworkCommand, StateChange};
+#[cfg(feature = "napi")]
+use crate::{CloseCallback, DataCallback};
+use bytes::Bytes;
+use smoltcp::wire::{IpAddress, Ipv4Address, Ipv6Address};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, oneshot};
+
+/// Everything needed to bring up a tunnel, like an interface section plus its peers.
+pub struct TunnelConfig {
+    pub private_key: [u8; 32],
+    /// Our address inside the tunnel.
+    pub source_ip: Ipv4Address,
+    /// Our IPv6 address inside the tunnel, if it has one; needed to connect to IPv6 hosts.
+    pub source_ip6: Option<Ipv6Address>,
+    /// Outer UDP port (`ListenPort`); an ephemeral one if `None`.
+    pub listen_port: Option<u16>,
+    pub peers: Vec<PeerSpec>,
+    /// Look up hostname endpoints this often, besides after repeated handshake failures.
+    pub resolve_interval: Option<Duration>,
+    pub reconnect: ReconnectPolicy,
+    /// DNS servers inside the tunnel (`DNS`), used by `resolve` and the proxies.
+    pub dns_servers: Vec<IpAddress>,
+    /// Carries the WireGuard datagrams instead of a UDP socket on `listen_port`.
+    pub transport: Option<Box<dyn Transport>>,
+    /// Puts the decrypted packets through a simulated network on their way to and from
+    /// the TCP stack. Its timing follows the tunnel loop, so to about 10 ms.
+    pub inner_conditions: Option<NetworkConditions>,
+}
+
+/// Handle on a running tunnel. Clones share the tunnel; it shuts down when the last one is dropped.
+#[derive(Clone)]
+pub struct Tunnel {
+    pub(crate) cmd_tx: mpsc::Sender<NetworkCommand>,
+    pub(crate) state: Arc<TaskState>,
+    capture: Arc<Capture>,
+}
+
+impl Tunnel {
+    /// Binds the outer UDP socket (unless `transport` is set) and starts the tunnel on the
+    /// current Tokio runtime. Bad keys or a port in use fail here; later failures go to
+    /// `on_fatal_error`.
+    pub fn start(config: TunnelConfig) -> Result<Self, WireShadeError> {
+        crate::spawn(config)
+    }
+
+    pub(crate) fn new(cmd_tx: mpsc::Sender<NetworkCommand>, state: Arc<TaskState>, capture: Arc<Capture>) -> Self {
+        Self { cmd_tx, state, capture }
+    }
+
+    /// Opens a TCP connection through the tunnel and waits until it is established.
+    pub async fn connect(&self, dest_ip: Ipv4Address, dest_port: u16) -> Result<TunnelStream, WireShadeError> {
+        self.connect_ip(dest_ip.into(), dest_port).await
+    }
+
+    /// Like `connect`, for IPv4 or IPv6 destinations. IPv6 needs `source_ip6`.
+    pub async fn connect_ip(&self, dest_ip: IpAddress, dest_port: u16) -> Result<TunnelStream, WireShadeError> {
+        let (events_tx, events) = mpsc::channel(STREAM_EVENTS);
+        let context = ConnectionContext::Stream { events: events_tx, eof_sent: false };
+        let id = self.open(dest_ip, dest_port, context).await?;
+        TunnelStream::new(id, events, self.clone()).connected().await
+    }
+
+    /// Listens on `port` at the tunnel address, and at `source_ip6` if there is one.
+    pub async fn listen(&self, port: u16) -> Result<TunnelListener, WireShadeError> {
+        let (accepted_tx, accepted) = mpsc::channel(ACCEPT_BACKLOG);
+        let (resp, rx) = oneshot::channel();
+        self.command(NetworkCommand::Listen {
+            port,
+            accepted: accepted_tx,
+            resp,
+        }).await?;
+        let local = rx.await.map_err(|_| self.state.closed_error())??;
+        Ok(TunnelListener::new(local, accepted, self.clone()))
+    }
+
+    /// Accepts TCP connections from peers to any address and port in `access` (exit-node
+    /// mode). Its `local_addr` has port 0; each connection's destination comes with `accept`.
+    pub async fn listen_any(&self, access: Access) -> Result<TunnelListener, WireShadeError> {
+        let (accepted_tx, accepted) = mpsc::channel(ACCEPT_BACKLOG);
+        let (resp, rx) = oneshot::channel();
+        self.command(NetworkCommand::StartExit {
+            listener: ExitListener { access, accepted: accepted_tx },
+            resp,
+        }).await?;
+        let local = rx.await.map_err(|_| self.state.closed_error())??;
+        Ok(TunnelListener::new(local, accepted, self.clone()))
+    }
+
+    /// Looks up an IPv4 address with the DNS servers inside the tunnel.
+    pub async fn resolve(&self, name: &str) -> Result<Ipv4Address, WireShadeError> {
+        let (resp, rx) = oneshot::channel();
+        self.command(NetworkCommand::Resolve { name: name.to_string(), resp }).await?;
+        rx.await.map_err(|_| self.state.closed_error())?
+    }
+
+    /// Sends ICMP echo requests to `dest_ip` and waits until each is answered or timed out.
+    pub async fn ping(&self, dest_ip: Ipv4Address, config: PingConfig) -> Result<PingResult, WireShadeError> {
+        if config.count == 0 || config.count > u32::from(u16::MAX) {
+            return Err(WireShadeError::InvalidArgument("ping count".to_string()));
+        }
+        // 20 bytes IPv4 and 8 bytes ICMP header must fit the 1420 byte MTU
+        if config.size > 1392 {
+            return Err(WireShadeError::InvalidArgument("ping size".to_string()));
+        }
+        let (resp, rx) = oneshot::channel();
+        self.command(NetworkCommand::Ping { dest_ip, config, resp }).await?;
+        rx.await.map_err(|_| self.state.closed_error())?
+    }
+
+    /// Injects a raw IPv4 or IPv6 packet. It is routed to a peer by its destination like
+    /// packets from the TCP stack, and sent as is.
+    pub async fn send_packet(&self, packet: Vec<u8>) -> Result<(), WireShadeError> {
+        if packet::destination(&packet).is_none() {
+            return Err(WireShadeError::InvalidArgument("IP packet".to_string()));
+        }
+        self.command(NetworkCommand::SendPacket { packet }).await
+    }
+
+    /// Calls `callback` with every decrypted packet from the tunnel that matches `filter`.
+    /// Returns an id for `off_packet`. The callback runs on the tunnel loop, so keep it short.
+    /// This and the other hook registrations don't wait: they fail with `Busy` while the
+    /// loop's command queue is full.
+    pub fn on_packet<F>(&self, filter: PacketFilter, callback: F) -> Result<u32, WireShadeError>
+    where
+        F: Fn(&[u8]) + Send + 'static,
+    {
+        let id = PacketHooks::next_id();
+        self.try_command(NetworkCommand::OnPacket { id, filter, callback: Box::new(callback) })?;
+        Ok(id)
+    }
+
+    pub fn off_packet(&self, id: u32) -> Result<(), WireShadeError> {
+        self.try_command(NetworkCommand::OffPacket { id })
+    }
+
+    /// Calls `callback` whenever a peer connects, is detected dead, is retried or is given up on.
+    pub fn on_state_change<F>(&self, callback: F) -> Result<(), WireShadeError>
+    where
+        F: Fn(StateChange) + Send + 'static,
+    {
+        self.try_command(NetworkCommand::OnStateChange { callback: Box::new(callback) })
+    }
+
+    /// Calls `callback` whenever a peer's endpoint moves.
+    pub fn on_endpoint_change<F>(&self, callback: F) -> Result<(), WireShadeError>
+    where
+        F: Fn(EndpointChange) + Send + 'static,
+    {
+        self.try_command(NetworkCommand::OnEndpointChange { callback: Box::new(callback) })
+    }
+
+    /// Calls `callback` once with the root cause if the tunnel dies (an error or a bug in the
+    /// loop). Every call after that fails with `TunnelFailed`.
+    pub fn on_fatal_error<F>(&self, callback: F)
+    where
+        F: Fn(WireShadeError) + Send + 'static,
+    {
+        self.state.set_fatal_callback(Box::new(callback));
+    }
+
+    /// Replaces the outer UDP socket and all WireGuard sessions, e.g. after a network change.
+    /// The TCP/IP stack is kept, so open connections resume once the new handshake is done.
+    /// Returns the new local UDP address.
+    pub async fn rebind(&self, listen_port: Option<u16>) -> Result<SocketAddr, WireShadeError> {
+        let (resp, rx) = oneshot::channel();
+        self.command(NetworkCommand::Rebind { listen_port, resp }).await?;
+        rx.await.map_err(|_| self.state.closed_error())?
+    }
+
+    /// Writes packets to a pcapng file at `path` until `stop_capture`. Starting again
+    /// switches to the new file.
+    pub fn start_capture(&self, path: &str, config: CaptureConfig) -> Result<(), WireShadeError> {
+        self.capture.start(path, config)?;
+        log::info!("Capturing to {}", path);
+        Ok(())
+    }
+
+    /// Stops the running capture and flushes it to disk.
+    pub fn stop_capture(&self) -> Result<(), WireShadeError> {
+        Ok(self.capture.stop()?)
+    }
+
+    /// Runs a SOCKS5 proxy on the host address `bind` that opens its connections through
+    /// the tunnel. Supports CONNECT to IPv4 addresses and to names, resolved with `dns_servers`.
+    pub async fn start_socks5(&self, bind: &str, auth: Option<ProxyAuth>) -> Result<ProxyServer, WireShadeError> {
+        let tunnel = self.clone();
+        let auth = Arc::new(auth);
+        proxy::start(bind, "SOCKS5 proxy", move |client, stats| {
+            socks5::handle(client, tunnel.clone(), auth.clone(), stats)
+        }).await
+    }
+
+    /// Runs an HTTP proxy on the host address `bind` that opens its connections through the
+    /// tunnel: `CONNECT host:port` for HTTPS and other TCP, and plain `http://` requests.
+    pub async fn start_http_proxy(&self, bind: &str, config: HttpProxyConfig) -> Result<ProxyServer, WireShadeError> {
+        let tunnel = self.clone();
+        let config = Arc::new(config);
+        proxy::start(bind, "HTTP proxy", move |client, stats| {
+            http_proxy::handle(client, tunnel.clone(), config.clone(), stats)
+        }).await
+    }
+
+    /// Listens on the host address `bind` and connects every client to
+    /// `remote_ip:remote_port` through the tunnel.
+    pub async fn forward_local(&self, bind: &str, remote_ip: Ipv4Address, remote_port: u16) -> Result<ProxyServer, WireShadeError> {
+        let tunnel = self.clone();
+        proxy::start(bind, "Local forward", move |client, stats| {
+            proxy::forward(client, tunnel.clone(), remote_ip, remote_port, stats)
+        }).await
+    }
+
+    /// Listens on `port` at the tunnel address, and at `source_ip6` if there is one, and
+    /// connects every client to `target` on the host. Beyond `max_connections` open at once,
+    /// new clients are reset.
+    pub async fn forward_remote(&self, port: u16, target: Target, max_connections: Option<u32>) -> Result<ProxyServer, WireShadeError> {
+        proxy::start_remote(self.clone(), port, target, max_connections).await
+    }
+
+    /// Acts as an exit node: TCP connections peers make through us to destinations in
+    /// `access` are made from this machine instead. `access.ips` must not be empty, so an
+    /// exit node is never an open relay by accident.
+    pub async fn start_exit_node(&self, access: Access, max_connections: Option<u32>) -> Result<ProxyServer, WireShadeError> {
+        if access.ips.is_empty() {
+            return Err(WireShadeError::InvalidArgument("allowedIps: an exit node needs at least one destination".to_string()));
+        }
+        proxy::start_exit(self.clone(), access, max_connections).await
+    }
+
+    /// Resolves once `data` is in the TCP send buffer of the connection.
+    pub(crate) async fn write_data(&self, connection_id: u32, data: Bytes) -> Result<(), WireShadeError> {
+        let (done, rx) = oneshot::channel();
+        self.command(NetworkCommand::SendData { connection_id, data, done: Some(done) }).await?;
+        rx.await.map_err(|_| WireShadeError::ConnectionReset)
+    }
+
+    async fn open(&self, dest_ip: IpAddress, dest_port: u16, context: ConnectionContext) -> Result<u32, WireShadeError> {
+        let (resp, rx) = oneshot::channel();
+        self.command(NetworkCommand::Connect { dest_ip, dest_port, context, resp }).await?;
+        rx.await.map_err(|_| self.state.closed_error())?
+    }
+
+    pub(crate) async fn command(&self, cmd: NetworkCommand) -> Result<(), WireShadeError> {
+        self.cmd_tx.send(cmd).await.map_err(|_| self.state.closed_error())
+    }
+
+    /// For calls that can't wait: a full queue is `Busy`, not a closed tunnel.
+    fn try_command(&self, cmd: NetworkCommand) -> Result<(), WireShadeError> {
+        self.cmd_tx.try_send(cmd).map_err(|e| match e {
+            TrySendError::Full(_) => WireShadeError::Busy,
+            TrySendError::Closed(_) => self.state.closed_error(),
+        })
+    }
+
+    /// For `Drop`, which can't wait either but mustn't lose the command: with the queue
+    /// full, a task waits for room.
+    pub(crate) fn command_on_drop(&self, cmd: NetworkCommand) {
+        if let Err(TrySendError::Full(cmd)) = self.cmd_tx.try_send(cmd) {
+            if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+                let cmd_tx = self.cmd_tx.clone();
+                runtime.spawn(async move {
+                    let _ = cmd_tx.send(cmd).await;
+                });
+            }
+        }
+    }
+}
+
+/// Connections driven by callbacks and ids, the way the JS client uses them.
+#[cfg(feature = "napi")]
+impl Tunnel {
+    /// Opens a connection whose data and close go to callbacks. Returns its id without
+    /// waiting for the handshake.
+    pub(crate) async fn connect_with(&self, dest_ip: Ipv4Address, dest_port: u16, on_data: DataCallback, on_close: CloseCallback) -> Result<u32, WireShadeError> {
+        self.open(dest_ip.into(), dest_port, ConnectionContext::Callbacks { on_data, on_close }).await
+    }
+
+    /// Queues `data` on a connection without waiting for it to be sent.
+    pub(crate) async fn send_data(&self, connection_id: u32, data: Bytes) -> Result<(), WireShadeError> {
+        self.command(NetworkCommand::SendData { connection_id, data, done: None }).await
+    }
+
+    pub(crate) async fn close_connection(&self, connection_id: u32) -> Result<(), WireShadeError> {
+        self.command(NetworkCommand::Close { connection_id }).await
+    }
+
+    pub(crate) async fn abort_connection(&self, connection_id: u32) -> Result<(), WireShadeError> {
+        self.command(NetworkCommand::Abort { connection_id }).await
+    }
+}
@@ -0,0 +1,150 @@
+//! Two tunnels wired to each other: a "server" at 10.0.0.1 that waits for its peer, and
+//! a "client" at 10.0.0.2 that connects to it.
+
+#![allow(dead_code)]
+
+use boringtun::x25519::{PublicKey, StaticSecret};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::mpsc;
+use wireshade::{PeerSpec, ReconnectPolicy, Transport, Tunnel, TunnelConfig, UdpTransport, WgTcpListener};
+
+pub const SERVER_KEY: [u8; 32] = [1; 32];
+pub const CLIENT_KEY: [u8; 32] = [2; 32];
+
+pub fn public(key: [u8; 32]) -> [u8; 32] {
+    PublicKey::from(&StaticSecret::from(key)).to_bytes()
+}
+
+pub fn config(key: [u8; 32], ip: &str, peer: PeerSpec) -> TunnelConfig {
+    TunnelConfig {
+        private_key: key,
+        source_ip: ip.parse().unwrap(),
+        source_ip6: None,
+        listen_port: None,
+        peers: vec![peer],
+        resolve_interval: None,
+        reconnect: ReconnectPolicy::default(),
+        dns_servers: Vec::new(),
+        transport: None,
+        inner_conditions: None,
+    }
+}
+
+pub fn peer(key: [u8; 32], endpoint: Option<SocketAddr>, allowed_ip: &str) -> PeerSpec {
+    PeerSpec {
+        public_key: public(key),
+        preshared_key: None,
+        endpoint,
+        endpoint_host: None,
+        allowed_ips: vec![allowed_ip.parse().unwrap()],
+        persistent_keepalive: None,
+    }
+}
+
+pub fn server_config() -> TunnelConfig {
+    config(SERVER_KEY, "10.0.0.1", peer(CLIENT_KEY, None, "10.0.0.2/32"))
+}
+
+pub fn client_config(server: SocketAddr) -> TunnelConfig {
+    config(CLIENT_KEY, "10.0.0.2", peer(SERVER_KEY, Some(server), "10.0.0.0/24"))
+}
+
+/// Server and client talking over UDP on localhost.
+pub fn udp_pair() -> (Tunnel, Tunnel) {
+    udp_pair_with(|config| config)
+}
+
+/// Like `udp_pair`, with the client's config changed by `client`.
+pub fn udp_pair_with(client: impl FnOnce(TunnelConfig) -> TunnelConfig) -> (Tunnel, Tunnel) {
+    let transport = UdpTransport::bind(0).unwrap();
+    let port = transport.local_addr().unwrap().port();
+    let server = Tunnel::start(TunnelConfig { transport: Some(Box::new(transport)), ..server_config() }).unwrap();
+    let client = Tunnel::start(client(client_config(SocketAddr::from(([127, 0, 0, 1], port))))).unwrap();
+    (server, client)
+}
+
+/// Server and client on the given transports; the server's is reachable at `server_addr`.
+pub fn pair_over(server_transport: impl Transport, client_transport: impl Transport, server_addr: SocketAddr) -> (Tunnel, Tunnel) {
+    let server = Tunnel::start(TunnelConfig { transport: Some(Box::new(server_transport)), ..server_config() }).unwrap();
+    let client = Tunnel::start(TunnelConfig { transport: Some(Box::new(client_transport)), ..client_config(server_addr) }).unwrap();
+    (server, client)
+}
+
+/// Echoes every connection to `port` on `tunnel` until the end of its data.
+pub async fn echo_server(tunnel: &Tunnel, port: u16) {
+    let mut listener = WgTcpListener::bind(tunnel, port).await.unwrap();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = tokio::io::split(stream);
+                tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+                writer.shutdown().await.unwrap();
+            });
+        }
+    });
+}
+
+/// Reads and throws away everything sent to `port` on `tunnel`, answering each `chunk`
+/// bytes with one byte so the sender knows they arrived.
+pub async fn sink_server(tunnel: &Tunnel, port: u16, chunk: usize) {
+    let mut listener = WgTcpListener::bind(tunnel, port).await.unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut buf = vec![0u8; chunk];
+                while stream.read_exact(&mut buf).await.is_ok() {
+                    if stream.write_all(&[1]).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+}
+
+/// Accepts every connection to `port` on `tunnel` and drops it.
+pub async fn drop_server(tunnel: &Tunnel, port: u16) {
+    let mut listener = tunnel.listen(port).await.unwrap();
+    tokio::spawn(async move { while listener.accept().await.is_ok() {} });
+}
+
+/// Sends `data` through a connection to an echo server and returns what came back.
+pub async fn echo(tunnel: &Tunnel, port: u16, data: Vec<u8>) -> Vec<u8> {
+    let stream = wireshade::WgTcpStream::connect(tunnel, format!("10.0.0.1:{}", port).parse().unwrap()).await.unwrap();
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let send = tokio::spawn(async move {
+        writer.write_all(&data).await.unwrap();
+        writer.shutdown().await.unwrap();
+    });
+    let mut received = Vec::new();
+    reader.read_to_end(&mut received).await.unwrap();
+    send.await.unwrap();
+    received
+}
+
+/// `len` bytes that differ from one position to the next.
+pub fn payload(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+}
+
+/// Peer states as they change, e.g. "connected".
+pub fn states(tunnel: &Tunnel) -> mpsc::UnboundedReceiver<String> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    tunnel.on_state_change(move |change| { let _ = tx.send(change.state); }).unwrap();
+    rx
+}
+
+/// Waits until `states` reports `state`.
+pub async fn wait_for(states: &mut mpsc::UnboundedReceiver<String>, state: &str) {
+    within(async {
+        while states.recv().await.expect("tunnel stopped") != state {}
+    }).await
+}
+
+/// Fails the test instead of hanging when `future` takes too long.
+pub async fn within<F: Future>(future: F) -> F::Output {
+    tokio::time::timeout(Duration::from_secs(20), future).await.expect("timed out")
+}
@@ -0,0 +1,110 @@
+mod common;
+
+use common::*;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use wireshade::{CallbackTransport, NetworkConditions, PingConfig, SimulatedTransport, Transport, TunnelConfig, WgTcpStream};
+
+/// The client's side of a `CallbackTransport::pair` put through `conditions`.
+fn simulated_pair(conditions: NetworkConditions) -> (wireshade::Tunnel, wireshade::Tunnel) {
+    let (a, b) = ("192.0.2.1:51820".parse().unwrap(), "192.0.2.2:51820".parse().unwrap());
+    let (server_transport, client_transport) = CallbackTransport::pair(a, b);
+    pair_over(server_transport, SimulatedTransport::new(client_transport, conditions), a)
+}
+
+/// Which of 200 datagrams sent through `conditions` make it, in the order they arrive.
+async fn delivered(conditions: NetworkConditions) -> Vec<u8> {
+    let arrived = Arc::new(Mutex::new(Vec::new()));
+    let (carrier, _inbound) = CallbackTransport::new("192.0.2.1:51820".parse().unwrap(), {
+        let arrived = arrived.clone();
+        move |datagram: &[u8], _| {
+            arrived.lock().unwrap().push(datagram[0]);
+            Ok(())
+        }
+    });
+    let simulated = SimulatedTransport::new(carrier, conditions);
+    let to: SocketAddr = "192.0.2.2:51820".parse().unwrap();
+    for i in 0..200u8 {
+        simulated.try_send_to(&[i], to).unwrap();
+    }
+    // Held datagrams leave while the transport is polled for incoming ones
+    let _ = tokio::time::timeout(Duration::from_millis(100), std::future::poll_fn(|cx| simulated.poll_recv_from(cx, &mut [0; 16]))).await;
+    let arrived = arrived.lock().unwrap().clone();
+    arrived
+}
+
+#[tokio::test]
+async fn same_seed_same_network() {
+    let conditions = |seed| NetworkConditions { loss: 0.3, duplicate: 0.1, seed, ..NetworkConditions::default() };
+    let first = delivered(conditions(7)).await;
+    assert!(first.len() > 100 && first.len() < 180, "{} of 200 arrived", first.len());
+    assert_eq!(delivered(conditions(7)).await, first);
+    assert_ne!(delivered(conditions(8)).await, first);
+}
+
+#[tokio::test]
+async fn reorders_held_back_datagrams() {
+    let arrived = delivered(NetworkConditions { reorder: 0.2, ..NetworkConditions::default() }).await;
+    assert_eq!(arrived.len(), 200);
+    assert!(arrived.windows(2).any(|pair| pair[0] > pair[1]));
+}
+
+#[tokio::test]
+async fn delay_adds_to_the_round_trip() {
+    let (_server, client) = simulated_pair(NetworkConditions { delay: Duration::from_millis(50), ..NetworkConditions::default() });
+    let config = PingConfig { count: 2, interval: Duration::from_millis(100), ..PingConfig::default() };
+    let result = within(client.ping("10.0.0.1".parse().unwrap(), config)).await.unwrap();
+    assert_eq!(result.received, 2);
+    // 50 ms there and 50 ms back
+    assert!(result.min.unwrap() >= 100.0, "{:?}", result.rtts);
+}
+
+#[tokio::test]
+async fn bandwidth_caps_throughput() {
+    let (server, client) = simulated_pair(NetworkConditions { bandwidth: Some(256 << 10), ..NetworkConditions::default() });
+    echo_server(&server, 7).await;
+    let data = payload(128 << 10);
+    let started = Instant::now();
+    assert!(within(echo(&client, 7, data.clone())).await == data);
+    // Half a second each way at the cap, minus what TCP has in flight when it starts
+    assert!(started.elapsed() >= Duration::from_millis(400), "took {:?}", started.elapsed());
+}
+
+#[tokio::test]
+async fn tcp_gets_through_a_bad_network() {
+    let conditions = NetworkConditions {
+        loss: 0.02,
+        delay: Duration::from_millis(10),
+        jitter: Duration::from_millis(5),
+        duplicate: 0.02,
+        reorder: 0.05,
+        seed: 1,
+        ..NetworkConditions::default()
+    };
+    let (server, client) = simulated_pair(conditions);
+    echo_server(&server, 7).await;
+    let data = payload(64 << 10);
+    let stream = within(WgTcpStream::connect(&client, "10.0.0.1:7".parse().unwrap())).await.unwrap();
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let sent = data.clone();
+    tokio::spawn(async move { writer.write_all(&sent).await.unwrap() });
+    // Stays open until the echo is back: smoltcp stops retransmitting after the peer's FIN
+    let mut echoed = vec![0; data.len()];
+    within(reader.read_exact(&mut echoed)).await.unwrap();
+    assert!(echoed == data);
+}
+
+#[tokio::test]
+async fn impairs_inner_packets() {
+    let (server, client) = udp_pair_with(|config| TunnelConfig {
+        inner_conditions: Some(NetworkConditions { delay: Duration::from_millis(50), ..NetworkConditions::default() }),
+        ..config
+    });
+    let config = PingConfig { count: 2, interval: Duration::from_millis(100), ..PingConfig::default() };
+    let result = within(client.ping("10.0.0.1".parse().unwrap(), config)).await.unwrap();
+    assert_eq!(result.received, 2);
+    assert!(result.min.unwrap() >= 100.0, "{:?}", result.rtts);
+    drop(server);
+}
@@ -0,0 +1,379 @@
+mod common;
+
+use boringtun::noise::{Tunn, TunnResult};
+use boringtun::x25519::{PublicKey, StaticSecret};
+use common::*;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UdpSocket;
+use wireshade::wire::{Icmpv4Message, Icmpv4Packet, Icmpv4Repr, IpProtocol, Ipv4Address, Ipv4Packet, Ipv4Repr};
+use wireshade::{Access, CallbackTransport, HttpProxyConfig, PacketFilter, ProxyAuth, PingConfig, ReconnectPolicy, Transport, Tunnel, TunnelConfig, UdpTransport, WgTcpListener, WgTcpStream, WireShadeError};
+
+/// A bare boringtun peer on UDP that answers pings, to check our side of the handshake
+/// against another implementation.
+async fn boringtun_responder() -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    let mut tunn = Tunn::new(StaticSecret::from(SERVER_KEY), PublicKey::from(public(CLIENT_KEY)), None, None, 0, None).unwrap();
+    tokio::spawn(async move {
+        let (mut datagram, mut out) = (vec![0u8; 65535], vec![0u8; 65535]);
+        loop {
+            let (len, from) = socket.recv_from(&mut datagram).await.unwrap();
+            let mut reply = None;
+            match tunn.decapsulate(Some(from.ip()), &datagram[..len], &mut out) {
+                TunnResult::WriteToNetwork(b) => {
+                    socket.send_to(b, from).await.unwrap();
+                    while let TunnResult::WriteToNetwork(b) = tunn.decapsulate(None, &[], &mut out) {
+                        socket.send_to(b, from).await.unwrap();
+                    }
+                }
+                TunnResult::WriteToTunnelV4(packet, _) => reply = echo_reply(packet),
+                _ => {}
+            }
+            if let Some(reply) = reply {
+                if let TunnResult::WriteToNetwork(b) = tunn.encapsulate(&reply, &mut out) {
+                    socket.send_to(b, from).await.unwrap();
+                }
+            }
+        }
+    });
+    addr
+}
+
+/// The reply to an ICMP echo request, if `packet` is one.
+fn echo_reply(packet: &[u8]) -> Option<Vec<u8>> {
+    let mut reply = packet.to_vec();
+    let mut ip = Ipv4Packet::new_checked(&mut reply[..]).ok()?;
+    let (src, dst) = (ip.src_addr(), ip.dst_addr());
+    ip.set_src_addr(dst);
+    ip.set_dst_addr(src);
+    ip.fill_checksum();
+    let mut icmp = Icmpv4Packet::new_checked(ip.payload_mut()).ok()?;
+    if icmp.msg_type() != Icmpv4Message::EchoRequest {
+        return None;
+    }
+    icmp.set_msg_type(Icmpv4Message::EchoReply);
+    icmp.fill_checksum();
+    Some(reply)
+}
+
+/// An ICMP echo request from `src` to `dst`.
+fn echo_request(src: Ipv4Address, dst: Ipv4Address) -> Vec<u8> {
+    let icmp = Icmpv4Repr::EchoRequest { ident: 1, seq_no: 1, data: b"ping" };
+    let ip = Ipv4Repr { src_addr: src, dst_addr: dst, next_header: IpProtocol::Icmp, payload_len: icmp.buffer_len(), hop_limit: 64 };
+    let mut packet = vec![0; ip.buffer_len() + icmp.buffer_len()];
+    let mut ipv4 = Ipv4Packet::new_unchecked(&mut packet[..]);
+    ip.emit(&mut ipv4, &Default::default());
+    icmp.emit(&mut Icmpv4Packet::new_unchecked(ipv4.payload_mut()), &Default::default());
+    packet
+}
+
+#[tokio::test]
+async fn handshakes_with_boringtun() {
+    let responder = boringtun_responder().await;
+    let client = Tunnel::start(client_config(responder)).unwrap();
+    let config = PingConfig { count: 3, interval: Duration::from_millis(100), ..PingConfig::default() };
+    let result = within(client.ping("10.0.0.1".parse().unwrap(), config)).await.unwrap();
+    assert_eq!(result.received, 3);
+}
+
+#[tokio::test]
+async fn reports_the_connected_peer() {
+    let (server, client) = udp_pair();
+    let mut client_states = states(&client);
+    wait_for(&mut client_states, "connected").await;
+    drop(server);
+}
+
+#[tokio::test]
+async fn drops_sources_outside_the_senders_allowed_ips() {
+    let transport = UdpTransport::bind(0).unwrap();
+    let port = transport.local_addr().unwrap().port();
+    let mut config = server_config();
+    config.peers.push(peer([3; 32], None, "10.0.0.3/32"));
+    let server = Tunnel::start(TunnelConfig { transport: Some(Box::new(transport)), ..config }).unwrap();
+    let client = Tunnel::start(client_config(SocketAddr::from(([127, 0, 0, 1], port)))).unwrap();
+    let (seen_tx, mut seen) = tokio::sync::mpsc::unbounded_channel();
+    let filter = PacketFilter { protocol: Some(1), port: None, intercept: true };
+    server.on_packet(filter, move |packet| { let _ = seen_tx.send(Ipv4Packet::new_unchecked(packet).src_addr()); }).unwrap();
+    wait_for(&mut states(&client), "connected").await;
+
+    // The first peer posing as the second, then as itself
+    let server_ip = Ipv4Address::new(10, 0, 0, 1);
+    client.send_packet(echo_request(Ipv4Address::new(10, 0, 0, 3), server_ip)).await.unwrap();
+    client.send_packet(echo_request(Ipv4Address::new(10, 0, 0, 2), server_ip)).await.unwrap();
+    assert_eq!(within(seen.recv()).await, Some(Ipv4Address::new(10, 0, 0, 2)));
+}
+
+/// `udp_pair` with IPv6 addresses inside the tunnel as well: fd00::1 and fd00::2.
+fn dual_stack_pair() -> (Tunnel, Tunnel) {
+    let transport = UdpTransport::bind(0).unwrap();
+    let port = transport.local_addr().unwrap().port();
+    let mut server = TunnelConfig { source_ip6: Some("fd00::1".parse().unwrap()), transport: Some(Box::new(transport)), ..server_config() };
+    server.peers[0].allowed_ips.push("fd00::2/128".parse().unwrap());
+    let mut client = TunnelConfig { source_ip6: Some("fd00::2".parse().unwrap()), ..client_config(SocketAddr::from(([127, 0, 0, 1], port))) };
+    client.peers[0].allowed_ips.push("fd00::/64".parse().unwrap());
+    (Tunnel::start(server).unwrap(), Tunnel::start(client).unwrap())
+}
+
+#[tokio::test]
+async fn connects_over_ipv6() {
+    let (server, client) = dual_stack_pair();
+    echo_server(&server, 7).await;
+    let stream = within(client.connect_ip("fd00::1".parse().unwrap(), 7)).await.unwrap();
+    let (mut reader, writer) = stream.into_split();
+    within(writer.write(b"over v6".to_vec())).await.unwrap();
+    assert_eq!(&within(reader.read()).await.unwrap().unwrap()[..], b"over v6");
+    // Still reachable over IPv4 on the same port
+    assert_eq!(within(echo(&client, 7, b"over v4".to_vec())).await, b"over v4");
+}
+
+#[tokio::test]
+async fn ipv6_needs_an_address_in_the_tunnel() {
+    let (_server, client) = udp_pair();
+    let result = client.connect_ip("fd00::1".parse().unwrap(), 7).await;
+    assert!(matches!(result, Err(e) if e.code() == "EADDRNOTAVAIL"));
+}
+
+#[tokio::test]
+async fn socks5_connects_to_ipv6_and_ipv4() {
+    let (server, client) = dual_stack_pair();
+    echo_server(&server, 7).await;
+    let proxy = client.start_socks5("127.0.0.1:0", None).await.unwrap();
+    let targets: [&[u8]; 2] = [&[4, 0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1], &[1, 10, 0, 0, 1]];
+    for target in targets {
+        let mut socks = tokio::net::TcpStream::connect(proxy.local_addr()).await.unwrap();
+        socks.write_all(&[5, 1, 0]).await.unwrap();
+        let mut method = [0; 2];
+        socks.read_exact(&mut method).await.unwrap();
+        assert_eq!(method, [5, 0]);
+        socks.write_all(&[&[5, 1, 0][..], target, &7u16.to_be_bytes()].concat()).await.unwrap();
+        let mut reply = [0; 10];
+        within(socks.read_exact(&mut reply)).await.unwrap();
+        assert_eq!(reply[1], 0, "reply to {:?}", target);
+        socks.write_all(b"hi").await.unwrap();
+        let mut echoed = [0; 2];
+        within(socks.read_exact(&mut echoed)).await.unwrap();
+        assert_eq!(&echoed, b"hi");
+    }
+}
+
+#[tokio::test]
+async fn http_proxy_answers_one_plain_request_per_connection() {
+    let (server, client) = udp_pair();
+    // Hands back everything it got once the proxy has sent the request
+    let mut listener = WgTcpListener::bind(&server, 80).await.unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = vec![0; 4096];
+        let n = stream.read(&mut request).await.unwrap();
+        let response = format!("HTTP/1.1 200 OK\r\nConnection: keep-alive\r\nContent-Length: {}\r\n\r\n", n);
+        stream.write_all(response.as_bytes()).await.unwrap();
+        stream.write_all(&request[..n]).await.unwrap();
+        stream.shutdown().await.unwrap();
+    });
+    let auth = ProxyAuth { username: "me".into(), password: "secret".into() };
+    let config = HttpProxyConfig { auth: Some(auth), access: Access { ips: Vec::new(), ports: Vec::new() } };
+    let proxy = client.start_http_proxy("127.0.0.1:0", config).await.unwrap();
+
+    let mut http = tokio::net::TcpStream::connect(proxy.local_addr()).await.unwrap();
+    let request = "GET http://10.0.0.1/{} HTTP/1.1\r\nHost: 10.0.0.1\r\nProxy-Authorization: Basic bWU6c2VjcmV0\r\n\r\n";
+    // Two requests on one connection, the second must not reach the server
+    http.write_all(format!("{}{}", request.replace("{}", "a"), request.replace("{}", "b")).as_bytes()).await.unwrap();
+    let mut response = String::new();
+    within(http.read_to_string(&mut response)).await.unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    assert!(head.contains("Connection: close") && !head.contains("keep-alive"), "{}", head);
+    assert_eq!(body, "GET /a HTTP/1.1\r\nHost: 10.0.0.1\r\nConnection: close\r\n\r\n");
+}
+
+#[tokio::test]
+async fn names_need_a_dns_server() {
+    let (_server, client) = udp_pair();
+    let result = client.resolve("example.com").await;
+    assert!(matches!(result, Err(WireShadeError::NoDnsServer)));
+
+    let proxy = client.start_socks5("127.0.0.1:0", None).await.unwrap();
+    let mut socks = tokio::net::TcpStream::connect(proxy.local_addr()).await.unwrap();
+    socks.write_all(&[5, 1, 0, 5, 1, 0, 3, 11]).await.unwrap();
+    socks.write_all(b"example.com\0\x50").await.unwrap();
+    let mut replies = [0; 12];
+    within(socks.read_exact(&mut replies)).await.unwrap();
+    // General failure rather than "host unreachable"
+    assert_eq!(replies[..4], [5, 0, 5, 1]);
+}
+
+#[tokio::test]
+async fn echoes_large_payloads() {
+    let (server, client) = udp_pair();
+    echo_server(&server, 7).await;
+    let data = payload(4 << 20);
+    assert!(within(echo(&client, 7, data.clone())).await == data);
+}
+
+#[tokio::test]
+async fn runs_over_an_in_process_transport() {
+    let (a, b) = ("192.0.2.1:51820".parse().unwrap(), "192.0.2.2:51820".parse().unwrap());
+    let (server_transport, client_transport) = CallbackTransport::pair(a, b);
+    let (server, client) = pair_over(server_transport, client_transport, a);
+    echo_server(&server, 7).await;
+    let data = payload(1 << 20);
+    assert!(within(echo(&client, 7, data.clone())).await == data);
+}
+
+#[tokio::test]
+async fn accepts_concurrent_connections() {
+    let (server, client) = udp_pair();
+    echo_server(&server, 7).await;
+    let echoes = (0..8).map(|i| {
+        let client = client.clone();
+        tokio::spawn(async move {
+            let data: Vec<u8> = payload(100_000 + i * 1000).into_iter().map(|b| b ^ i as u8).collect();
+            echo(&client, 7, data.clone()).await == data
+        })
+    });
+    for echoed in within(futures_join(echoes)).await {
+        assert!(echoed);
+    }
+}
+
+async fn futures_join<T>(handles: impl Iterator<Item = tokio::task::JoinHandle<T>>) -> Vec<T> {
+    let mut results = Vec::new();
+    for handle in handles.collect::<Vec<_>>() {
+        results.push(handle.await.unwrap());
+    }
+    results
+}
+
+#[tokio::test]
+async fn half_close_keeps_the_other_direction_open() {
+    let (server, client) = udp_pair();
+    let mut listener = WgTcpListener::bind(&server, 80).await.unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        stream.read_to_end(&mut request).await.unwrap();
+        // The client is done sending, but still hears the answer
+        stream.write_all(format!("got {}", request.len()).as_bytes()).await.unwrap();
+        stream.shutdown().await.unwrap();
+    });
+
+    let mut stream = within(WgTcpStream::connect(&client, "10.0.0.1:80".parse().unwrap())).await.unwrap();
+    stream.write_all(&payload(300_000)).await.unwrap();
+    stream.shutdown().await.unwrap();
+    let mut response = String::new();
+    within(stream.read_to_string(&mut response)).await.unwrap();
+    assert_eq!(response, "got 300000");
+}
+
+#[tokio::test]
+async fn abort_resets_the_connection() {
+    let (server, client) = udp_pair();
+    let mut listener = server.listen(80).await.unwrap();
+    tokio::spawn(async move {
+        let (stream, _, _) = listener.accept().await.unwrap();
+        stream.abort();
+    });
+
+    let stream = within(client.connect("10.0.0.1".parse().unwrap(), 80)).await.unwrap();
+    let (mut reader, _writer) = stream.into_split();
+    assert!(matches!(within(reader.read()).await, Err(WireShadeError::ConnectionReset)));
+}
+
+#[tokio::test]
+async fn refuses_closed_ports_and_unknown_hosts() {
+    let (server, client) = udp_pair();
+    let refused = within(client.connect("10.0.0.1".parse().unwrap(), 81)).await;
+    assert!(matches!(refused, Err(WireShadeError::ConnectionRefused)));
+    let unroutable = client.connect("192.0.2.1".parse().unwrap(), 80).await;
+    assert!(matches!(unroutable, Err(WireShadeError::NoRoute(_))));
+    drop(server);
+}
+
+#[tokio::test]
+async fn dropping_a_listener_frees_its_port() {
+    let (server, client) = udp_pair();
+    let listener = server.listen(80).await.unwrap();
+    assert!(matches!(server.listen(80).await, Err(WireShadeError::AddressInUse(80))));
+    drop(listener);
+
+    let refused = within(client.connect("10.0.0.1".parse().unwrap(), 80)).await;
+    assert!(matches!(refused, Err(WireShadeError::ConnectionRefused)));
+    echo_server(&server, 80).await;
+    assert_eq!(within(echo(&client, 80, b"again".to_vec())).await, b"again");
+}
+
+#[tokio::test]
+async fn connections_survive_a_rebind() {
+    let (server, client) = udp_pair();
+    echo_server(&server, 7).await;
+    let (endpoints_tx, mut endpoints) = tokio::sync::mpsc::unbounded_channel();
+    server.on_endpoint_change(move |change| { let _ = endpoints_tx.send(change.endpoint); }).unwrap();
+
+    let stream = within(WgTcpStream::connect(&client, "10.0.0.1:7".parse().unwrap())).await.unwrap();
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let mut buf = [0u8; 5];
+    writer.write_all(b"first").await.unwrap();
+    within(reader.read_exact(&mut buf)).await.unwrap();
+
+    let rebound = within(client.rebind(None)).await.unwrap();
+    writer.write_all(b"again").await.unwrap();
+    within(reader.read_exact(&mut buf)).await.unwrap();
+    assert_eq!(&buf, b"again");
+    // The server followed the client to its new port
+    within(async {
+        while !endpoints.recv().await.unwrap().ends_with(&format!(":{}", rebound.port())) {}
+    }).await;
+}
+
+#[tokio::test]
+async fn reconnects_after_the_server_restarts() {
+    let transport = UdpTransport::bind(0).unwrap();
+    let server_addr = SocketAddr::from(([127, 0, 0, 1], transport.local_addr().unwrap().port()));
+    let server = Tunnel::start(TunnelConfig { transport: Some(Box::new(transport)), ..server_config() }).unwrap();
+    let reconnect = ReconnectPolicy { dead_peer_timeout: Duration::from_millis(300), delay: Duration::from_millis(100), ..ReconnectPolicy::default() };
+    let client = Tunnel::start(TunnelConfig { reconnect, ..client_config(server_addr) }).unwrap();
+    wait_for(&mut states(&client), "connected").await;
+
+    // The new server knows nothing of the old session, the client has to notice
+    drop(server);
+    let transport = within(async {
+        loop {
+            match UdpTransport::bind(server_addr.port()) {
+                Ok(transport) => break transport,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        }
+    }).await;
+    let server = Tunnel::start(TunnelConfig { transport: Some(Box::new(transport)), ..server_config() }).unwrap();
+    echo_server(&server, 7).await;
+    assert_eq!(within(echo(&client, 7, b"back".to_vec())).await, b"back");
+}
+
+#[tokio::test]
+async fn gives_up_on_a_silent_peer() {
+    // Takes datagrams and never answers
+    let silent = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let reconnect = ReconnectPolicy {
+        dead_peer_timeout: Duration::from_millis(300),
+        delay: Duration::from_millis(100),
+        max_delay: Duration::from_millis(100),
+        jitter: 0.0,
+        max_attempts: 1,
+        ..ReconnectPolicy::default()
+    };
+    let client = Tunnel::start(TunnelConfig { reconnect, ..client_config(silent.local_addr().unwrap()) }).unwrap();
+    let mut client_states = states(&client);
+
+    // Without a session the traffic waits for the handshake, so it's the unanswered
+    // handshakes (one every 5s) that get the peer declared dead
+    let connecting = tokio::spawn({
+        let client = client.clone();
+        async move { client.connect("10.0.0.1".parse().unwrap(), 80).await.map(|_| ()) }
+    });
+    wait_for(&mut client_states, "failed").await;
+    let late = client.connect("10.0.0.1".parse().unwrap(), 80).await;
+    assert!(matches!(late, Err(WireShadeError::HandshakeTimeout)));
+    connecting.abort();
+}